@@ -19,36 +19,99 @@
                         :new_str ,(buffer-substring-no-properties pos end)))))
       (process-send-string collab-subprocess (concat json "\n")))))
 
+(defun collab-apply-diff (json)
+  (let ((pos (+ (cdr (assoc 'pos json)) 1))
+        (old-len (cdr (assoc 'old_len json)))
+        (new-str (cdr (assoc 'new_str json))))
+    (setq-local collab-performing-edit t)
+    (delete-region pos (+ pos old-len))
+    (let ((p (point)))
+      (goto-char pos)
+      (insert new-str)
+      (goto-char (if (> p pos) (+ p (length new-str)) p)))
+    (setq-local collab-performing-edit nil)))
+
+(defun collab-handle-event (event json)
+  (cond
+   ((string= event "Error")
+    (message "collab: %s" (cdr (assoc 'message json)))
+    (collab-mode -1))
+   ((string= event "Shutdown")
+    (message "collab: session was shut down")
+    (collab-mode -1))
+   ((string= event "PeerJoined")
+    (message "collab: peer joined: %s" (cdr (assoc 'addr json))))
+   ((string= event "PeerLeft")
+    (message "collab: peer left: %s" (cdr (assoc 'addr json))))
+   ((string= event "FileDeleted")
+    (message "collab: %s was deleted" (cdr (assoc 'path json))))
+   ((string= event "FileMoved")
+    (let ((from (cdr (assoc 'from json)))
+          (to (cdr (assoc 'to json))))
+      (when (string-suffix-p from (buffer-file-name))
+        (set-visited-file-name
+         (concat (string-remove-suffix from (buffer-file-name)) to) t)
+        (message "collab: file moved to %s" to))))
+   ((string= event "Suggestion")
+    (let ((id (cdr (assoc 'id json))))
+      (setq-local collab-suggestions (cons (cons id json) collab-suggestions))
+      (message "collab: %s suggests %S at %d"
+               (cdr (assoc 'author json)) (cdr (assoc 'new_str json))
+               (+ (cdr (assoc 'pos json)) 1))))
+   ((string= event "SuggestionResolved")
+    (setq-local collab-suggestions
+                (assoc-delete-all (cdr (assoc 'id json)) collab-suggestions)))
+   ((string= event "Summon")
+    (let ((file (cdr (assoc 'file json)))
+          (line (cdr (assoc 'line json))))
+      (when (y-or-n-p (format "collab: %s summons you to %s:%d. Go there? "
+                              (cdr (assoc 'from json)) (cdr (assoc 'path json)) line))
+        (find-file file)
+        (goto-char (point-min))
+        (forward-line (- line 1)))))))
+
 (defun collab-process-filter (proc string)
-  (if (string-prefix-p "Error" string)
-      (progn (message (string-trim string)) (collab-mode -1))
-    (let ((json (json-read-from-string string)))
-      (let ((pos (+ (cdr (assoc 'pos json)) 1))
-            (old-len (cdr (assoc 'old_len json)))
-            (new-str (cdr (assoc 'new_str json))))
-        (setq-local collab-performing-edit t)
-        (delete-region pos (+ pos old-len))
-        (let ((p (point)))
-          (goto-char pos)
-          (insert new-str)
-          (goto-char (if (> p pos) (+ p (length new-str)) p)))
-        (setq-local collab-performing-edit nil)))))
+  (with-current-buffer (process-get proc 'collab-buffer)
+    (dolist (line (split-string string "\n" t))
+      (let ((json (json-read-from-string line)))
+        (let ((event (cdr (assoc 'event json))))
+          (if event
+              (collab-handle-event event json)
+            (collab-apply-diff json)))))))
+
+(defun collab-resolve-suggestion (verb)
+  (let ((id (completing-read (format "Suggestion to %s: " verb)
+                             (mapcar #'car collab-suggestions) nil t)))
+    (process-send-string collab-subprocess
+                         (concat (json-encode `((,verb . ,id))) "\n"))))
+
+(defun collab-accept-suggestion ()
+  (interactive)
+  (collab-resolve-suggestion "accept"))
+
+(defun collab-reject-suggestion ()
+  (interactive)
+  (collab-resolve-suggestion "reject"))
 
 (defun collab-process-sentinel (proc event)
   ())
 
 (defun collab-make-subprocess ()
   (let
-      ((path (buffer-file-name)))
+      ((path (buffer-file-name))
+       (charset (coding-system-get buffer-file-coding-system :mime-charset)))
     (unless (eq path nil)
       (setq-local
        collab-subprocess
        (make-process
         :name "emacs-collab-attach"
-        :command (list collab-command-name "attach" "-m" "json" "-f" path "-d" "Emacs")
+        :command (append (list collab-command-name "attach" "-m" "json" "-f" path "-d" "Emacs")
+                         (when charset (list "-e" (symbol-name charset))))
+        :coding (when charset (coding-system-base buffer-file-coding-system))
         :filter 'collab-process-filter
         :sentinel 'collab-process-sentinel
-        :noquery t)))))
+        :noquery t))
+      (process-put collab-subprocess 'collab-buffer (current-buffer)))))
 
 (defun collab-info ()
   (interactive)
@@ -64,12 +127,15 @@
   "Toggle collab mode."
   :init-value nil
   :lighter " collab"
-  :keymap `((,(kbd "C-c i") . collab-info))
+  :keymap `((,(kbd "C-c i") . collab-info)
+            (,(kbd "C-c a") . collab-accept-suggestion)
+            (,(kbd "C-c r") . collab-reject-suggestion))
   :group 'collab
   (if collab-mode
       (progn
         (setq-local x 0)
         (setq-local collab-performing-edit nil)
+        (setq-local collab-suggestions nil)
         (collab-make-subprocess)
         (add-hook 'post-command-hook #'collab-on-point nil t)
         (add-hook 'after-change-functions #'collab-on-change nil t))
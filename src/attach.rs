@@ -1,21 +1,75 @@
 use crate::common::*;
-use crate::ipc;
+use crate::{encoding, ipc};
 use context_attribute::context;
+use encoding_rs::{Encoding, UTF_8};
 use std::{
-    io::{self, BufRead},
-    path::Path,
-    str, thread,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process, str, thread,
 };
 
+/// A diff as sent by the editor. The sequence number is optional; diffs
+/// without one are numbered consecutively after the last one seen.
+#[derive(serde::Deserialize, Debug)]
+struct EditorDiff {
+    #[serde(flatten)]
+    diff: BufferDiff,
+    seq: Option<u64>,
+}
+
+/// A line of input from the editor: either a diff, or a command to accept
+/// or reject a pending suggestion. In CSV mode commands are written as
+/// "accept,ID" and "reject,ID".
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum EditorInput {
+    Accept { accept: String },
+    Reject { reject: String },
+    Diff(EditorDiff),
+}
+
+/// The CSV form of an EditorDiff: pos, old_len, new_str and optionally seq
+#[derive(serde::Deserialize, Debug)]
+struct CsvEditorDiff {
+    pos: u32,
+    old_len: u32,
+    new_str: String,
+    seq: Option<u64>,
+}
+
 #[context("unable to parse csv: {}", csv)]
-fn parse_csv(csv: &str) -> Result<BufferDiff> {
+fn parse_csv(csv: &str) -> Result<EditorInput> {
     let mut reader = csv::ReaderBuilder::new()
         .has_headers(false)
         .double_quote(false)
         .escape(Some(b'\\'))
+        .flexible(true)
         .from_reader(csv.as_bytes());
-    let diff = reader.deserialize().next().unwrap()?;
-    return Ok(diff);
+    let record = reader.records().next().unwrap()?;
+    return Ok(match (record.get(0), record.get(1)) {
+        (Some("accept"), Some(id)) => EditorInput::Accept {
+            accept: id.to_string(),
+        },
+        (Some("reject"), Some(id)) => EditorInput::Reject {
+            reject: id.to_string(),
+        },
+        _ => {
+            let CsvEditorDiff {
+                pos,
+                old_len,
+                new_str,
+                seq,
+            } = record.deserialize(None)?;
+            EditorInput::Diff(EditorDiff {
+                diff: BufferDiff {
+                    pos,
+                    old_len,
+                    new_str,
+                },
+                seq,
+            })
+        }
+    });
 }
 
 #[context("unable to unparse csv: {:?}", diff)]
@@ -32,50 +86,306 @@ fn unparse_csv(diff: &BufferDiff) -> Result<String> {
     return Ok(String::from(csv));
 }
 
+/// Status events reported to the editor alongside buffer diffs. In JSON
+/// mode these are objects tagged with an "event" field; in CSV mode the
+/// event name is the first column, followed by the fields in order.
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(tag = "event")]
+enum AttachEvent {
+    Ack {
+        seq: u64,
+    },
+    Synced {
+        seq: u64,
+    },
+    Suggestion {
+        id: String,
+        author: String,
+        pos: u32,
+        old_len: u32,
+        new_str: String,
+    },
+    SuggestionResolved {
+        id: String,
+        accepted: bool,
+    },
+    Error {
+        message: String,
+    },
+    Shutdown,
+    PeerJoined {
+        addr: String,
+    },
+    PeerLeft {
+        addr: String,
+    },
+    FileDeleted {
+        path: RelativePathBuf,
+    },
+    FileMoved {
+        from: RelativePathBuf,
+        to: RelativePathBuf,
+    },
+    Summon {
+        path: RelativePathBuf,
+        file: PathBuf,
+        line: u32,
+        from: String,
+    },
+}
+
+impl AttachEvent {
+    fn from_response(response: &IpcClientResponse, root: &Path) -> Option<Self> {
+        use IpcClientResponse::*;
+        return match response {
+            Ack(seq) => Some(AttachEvent::Ack { seq: *seq }),
+            Synced(seq) => Some(AttachEvent::Synced { seq: *seq }),
+            Suggestion(suggestion) => Some(AttachEvent::Suggestion {
+                id: suggestion.id.clone(),
+                author: suggestion.author.clone(),
+                pos: suggestion.diff.pos,
+                old_len: suggestion.diff.old_len,
+                new_str: suggestion.diff.new_str.clone(),
+            }),
+            SuggestionResolved { id, accepted } => Some(AttachEvent::SuggestionResolved {
+                id: id.clone(),
+                accepted: *accepted,
+            }),
+            Error(message) => Some(AttachEvent::Error {
+                message: message.clone(),
+            }),
+            Shutdown => Some(AttachEvent::Shutdown),
+            PeerJoined(info) => Some(AttachEvent::PeerJoined {
+                addr: info.advertised_addr.to_string(),
+            }),
+            PeerLeft(info) => Some(AttachEvent::PeerLeft {
+                addr: info.advertised_addr.to_string(),
+            }),
+            FileDeleted(path) => Some(AttachEvent::FileDeleted { path: path.clone() }),
+            FileMoved(from, to) => Some(AttachEvent::FileMoved {
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            Summon { path, line, from } => Some(AttachEvent::Summon {
+                path: path.clone(),
+                file: path_join(root, path),
+                line: *line,
+                from: from.to_string(),
+            }),
+            _ => None,
+        };
+    }
+
+    fn csv_fields(&self) -> Vec<String> {
+        use AttachEvent::*;
+        return match self {
+            Ack { seq } => vec!["Ack".to_string(), seq.to_string()],
+            Synced { seq } => vec!["Synced".to_string(), seq.to_string()],
+            Suggestion {
+                id,
+                author,
+                pos,
+                old_len,
+                new_str,
+            } => vec![
+                "Suggestion".to_string(),
+                id.clone(),
+                author.clone(),
+                pos.to_string(),
+                old_len.to_string(),
+                new_str.clone(),
+            ],
+            SuggestionResolved { id, accepted } => vec![
+                "SuggestionResolved".to_string(),
+                id.clone(),
+                accepted.to_string(),
+            ],
+            Error { message } => vec!["Error".to_string(), message.clone()],
+            Shutdown => vec!["Shutdown".to_string()],
+            PeerJoined { addr } => vec!["PeerJoined".to_string(), addr.clone()],
+            PeerLeft { addr } => vec!["PeerLeft".to_string(), addr.clone()],
+            FileDeleted { path } => vec!["FileDeleted".to_string(), path.to_string()],
+            FileMoved { from, to } => {
+                vec!["FileMoved".to_string(), from.to_string(), to.to_string()]
+            }
+            Summon {
+                path,
+                file,
+                line,
+                from,
+            } => vec![
+                "Summon".to_string(),
+                path.to_string(),
+                file.display().to_string(),
+                line.to_string(),
+                from.clone(),
+            ],
+        };
+    }
+}
+
+#[context("unable to unparse csv event: {:?}", event)]
+fn unparse_csv_event(event: &AttachEvent) -> Result<String> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .double_quote(false)
+        .escape(b'\\')
+        .from_writer(Vec::new());
+    writer.write_record(event.csv_fields())?;
+    writer.flush()?;
+    let vec = writer.into_inner()?;
+    let csv = str::from_utf8(&vec[..])?;
+    return Ok(String::from(csv));
+}
+
+/// Writes a line to the editor in the encoding of the attached file.
+#[context("unable to print line")]
+fn print_line(text: &str, encoding: &'static Encoding) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(&encoding::encode(encoding, text)[..])?;
+    stdout.write_all(&encoding::encode(encoding, "\n")[..])?;
+    stdout.flush()?;
+    return Ok(());
+}
+
+#[context("unable to emit event: {:?}", event)]
+fn emit_event(event: &AttachEvent, mode: AttachMode, encoding: &'static Encoding) -> Result<()> {
+    let text = match mode {
+        AttachMode::Json => serde_json::to_string(event)?,
+        AttachMode::Csv => unparse_csv_event(event)?,
+    };
+    return print_line(&text, encoding);
+}
+
+/// Reports an error to the editor as an event, so that it can react
+/// without having to scrape stderr.
+fn emit_error(err: &Error, mode: AttachMode, encoding: &'static Encoding) {
+    let event = AttachEvent::Error {
+        message: format!("{:#}", err),
+    };
+    match emit_event(&event, mode, encoding) {
+        Ok(()) => (),
+        Err(err) => eprintln!("Error reporting error: {}", err),
+    }
+}
+
+pub fn attach(
+    root: &Path,
+    file: &Path,
+    desc: String,
+    mode: AttachMode,
+    label: Option<String>,
+    acks: AckLevel,
+    role: AttachRole,
+) -> Result<()> {
+    let file_encoding = match &label {
+        Some(label) => encoding::lookup(label),
+        None => encoding::detect(file),
+    };
+    let res = match &file_encoding {
+        Ok(file_encoding) => attach_inner(root, file, desc, mode, file_encoding, acks, role),
+        Err(err) => Err(CollabError::Error(format!("{:#}", err)).into()),
+    };
+    return match res {
+        Ok(()) => Ok(()),
+        // the editor already has the error as an event, so don't report it
+        // again on the way out
+        Err(err) => {
+            emit_error(&err, mode, file_encoding.as_ref().unwrap_or(&UTF_8));
+            process::exit(1);
+        }
+    };
+}
+
 #[context(
-    "unable to attach, root: {:?}, file: {:?}, mode: {:?}",
+    "unable to attach, root: {:?}, file: {:?}, mode: {:?}, encoding: {}",
     root,
     file,
-    mode
+    mode,
+    file_encoding.name()
 )]
-pub fn attach(root: &Path, file: &Path, desc: String, mode: AttachMode) -> Result<()> {
+fn attach_inner(
+    root: &Path,
+    file: &Path,
+    desc: String,
+    mode: AttachMode,
+    file_encoding: &'static Encoding,
+    acks: AckLevel,
+    role: AttachRole,
+) -> Result<()> {
     let (sender, receiver) = ipc::client(&root)?;
 
     let path = strip_prefix(file, root)?;
 
-    thread::spawn(move || -> Result<()> {
-        loop {
-            match receiver.recv()? {
-                IpcClientResponse::BufferDiff(diff) => {
-                    let text = match mode {
-                        AttachMode::Json => serde_json::to_string(&diff)?,
-                        AttachMode::Csv => unparse_csv(&diff)?,
-                    };
-                    println!("{}", text);
-                }
-                IpcClientResponse::LocalDisconnect | IpcClientResponse::RemoteDisconnect => {
-                    return Ok(())
+    let root = PathBuf::from(root);
+    thread::spawn(move || {
+        let res = (|| -> Result<()> {
+            loop {
+                let response = receiver.recv()?;
+                match response {
+                    IpcClientResponse::BufferDiff(diff) => {
+                        let text = match mode {
+                            AttachMode::Json => serde_json::to_string(&diff)?,
+                            AttachMode::Csv => unparse_csv(&diff)?,
+                        };
+                        print_line(&text, file_encoding)?;
+                    }
+                    IpcClientResponse::LocalDisconnect | IpcClientResponse::RemoteDisconnect => {
+                        return Ok(())
+                    }
+                    IpcClientResponse::Info(_) => (),
+                    _ => match AttachEvent::from_response(&response, &root) {
+                        Some(event) => emit_event(&event, mode, file_encoding)?,
+                        None => (),
+                    },
                 }
-                IpcClientResponse::Info(_) => (),
             }
+        })();
+        match res {
+            Ok(()) => (),
+            Err(err) => emit_error(&err, mode, file_encoding),
         }
     });
 
-    sender.send(IpcClientMsg::AttachRequest { path, desc })?;
+    sender.send(IpcClientMsg::AttachRequest {
+        path,
+        desc,
+        encoding: file_encoding.name().to_string(),
+        acks,
+        role,
+    })?;
+
+    let mut last_seq = 0;
 
     loop {
-        for line in io::stdin().lock().lines() {
+        for line in encoding::LineReader::new(io::stdin().lock(), file_encoding) {
             let line = line?;
             if line == "q" {
                 // quit
                 return Ok(());
             }
-            let data = match mode {
+            let input = match mode {
                 AttachMode::Json => serde_json::from_str(&line[..])
                     .with_context(|| format!("unable to parse json: {}", &line[..]))?,
                 AttachMode::Csv => parse_csv(&line[..])?,
             };
-            sender.send(IpcClientMsg::BufferDiff(data))?;
+            match input {
+                EditorInput::Diff(data) => {
+                    let seq = data.seq.unwrap_or(last_seq + 1);
+                    last_seq = seq;
+                    sender.send(IpcClientMsg::BufferDiff {
+                        seq,
+                        diff: data.diff,
+                    })?;
+                }
+                EditorInput::Accept { accept } => {
+                    sender.send(IpcClientMsg::AcceptSuggestion(accept))?
+                }
+                EditorInput::Reject { reject } => {
+                    sender.send(IpcClientMsg::RejectSuggestion(reject))?
+                }
+            }
         }
     }
 }
@@ -0,0 +1,272 @@
+use crate::common::*;
+use crate::tls::PeerStream;
+use crate::wire::Codec;
+use crate::{keys, tcp};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::net;
+
+// Everyone in a session shares a secret. Before a connection is used for
+// anything else, each side sends a random challenge and the other proves
+// it knows the secret by answering with an HMAC of that challenge. The
+// secret itself never goes over the wire. The answer also covers which
+// side of the connection is answering, so that a peer can't just reflect
+// a challenge back to get it answered.
+//
+// Along with the HMAC, each side sends its public key, advertised address
+// and node id, and signs the challenge, address and node id with its key.
+// Both sides then check the other's key against known_peers (see keys.rs).
+// Finally each side says whether it accepts the other, e.g. a side may have
+// banned the other, so that the side that connected doesn't keep trying to
+// reconnect to a peer that will never accept it.
+//
+// With TLS, the HMAC and signature also cover a value exported from the TLS
+// session (see tls.rs), which differs on the two sides of a connection that
+// someone in the middle has terminated. Passing the exchange along between
+// two honest peers then gets them nowhere. Without TLS there is nothing to
+// bind to: someone who can intercept the connection can relay the exchange
+// and take over the connection once it is authenticated, so sessions that
+// cross untrusted networks should use TLS.
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+enum AuthMsg {
+    Challenge(String),
+    Response {
+        mac: String,
+        key: String,
+        addr: net::SocketAddr,
+        node_id: NodeId,
+        signature: String,
+    },
+    Accepted,
+    Refused(String),
+}
+
+/// Who is on the other end of an authenticated connection
+#[derive(Debug)]
+pub struct PeerIdentity {
+    /// Public key, as printed by `collab info`
+    pub key: String,
+    /// Where the peer is: the address we dialed, or if we didn't dial one,
+    /// the one the peer says it can be reached at
+    pub addr: net::SocketAddr,
+    pub node_id: NodeId,
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut data[..]);
+    return data;
+}
+
+/// Generates a secret for a new session
+pub fn generate_secret() -> String {
+    return to_hex(&random_bytes(16)[..]);
+}
+
+/// Token proving to a relay that a member knows the secret of the session
+/// it is joining, without giving the secret away
+pub fn session_token(secret: &str, session: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(b"relay session ");
+    mac.update(session.as_bytes());
+    return to_hex(&mac.finalize().into_bytes()[..]);
+}
+
+fn role(initiator: bool) -> &'static [u8] {
+    return if initiator { b"initiator" } else { b"acceptor" };
+}
+
+/// `binding` is the value exported from the TLS session, or empty without
+/// TLS
+fn mac(secret: &str, initiator: bool, challenge: &[u8], binding: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(role(initiator));
+    mac.update(challenge);
+    mac.update(binding);
+    return mac;
+}
+
+/// The message signed by a peer's key: its role, the challenge it was
+/// given, the TLS session binding and the address and node id it claims
+fn signed_message(
+    initiator: bool,
+    challenge: &[u8],
+    binding: &[u8],
+    addr: &net::SocketAddr,
+    node_id: &str,
+) -> Vec<u8> {
+    let mut message = role(initiator).to_vec();
+    message.extend_from_slice(challenge);
+    message.extend_from_slice(binding);
+    message.extend_from_slice(addr.to_string().as_bytes());
+    message.push(0);
+    message.extend_from_slice(node_id.as_bytes());
+    return message;
+}
+
+#[context("unable to receive auth message")]
+fn recv(stream: &mut PeerStream) -> Result<AuthMsg> {
+    return match Codec::Json.read_frame(&mut stream.reader)? {
+        Some(data) => Ok(Codec::Json.decode(&data[..])?),
+        None => {
+            Err(CollabError::Error("Connection closed during authentication".to_string()).into())
+        }
+    };
+}
+
+fn auth_error<T>(message: &str) -> Result<T> {
+    return Err(CollabError::Error(message.to_string()).into());
+}
+
+/// Proves to the peer that we know the session secret and hold our key,
+/// and checks that the peer does too. Fails if it doesn't, or if its key
+/// has been untrusted. `initiator` is whether we opened the connection. If
+/// we did so by dialing an address, `dialed` is that address, which is
+/// used rather than anything the peer claims.
+#[context("unable to authenticate peer")]
+pub fn authenticate(
+    stream: &mut PeerStream,
+    state: &SharedState,
+    initiator: bool,
+    dialed: Option<&net::SocketAddr>,
+) -> Result<PeerIdentity> {
+    let binding = stream.binding.clone().unwrap_or_default();
+    let challenge = random_bytes(32);
+    let challenge_msg = AuthMsg::Challenge(to_hex(&challenge[..]));
+    Codec::Json.write_frame(&mut stream.writer, &Codec::Json.encode(&challenge_msg)?)?;
+
+    let peer_challenge = match recv(stream)? {
+        AuthMsg::Challenge(challenge) => from_hex(&challenge),
+        _ => return auth_error("Expected challenge"),
+    };
+    let peer_challenge = match peer_challenge {
+        Some(challenge) => challenge,
+        None => return auth_error("Malformed challenge"),
+    };
+    let response = AuthMsg::Response {
+        mac: to_hex(
+            &mac(&state.secret, initiator, &peer_challenge[..], &binding[..])
+                .finalize()
+                .into_bytes()[..],
+        ),
+        key: keys::format_key(&state.keypair.public()),
+        addr: state.advertised_addr,
+        node_id: state.node_id.clone(),
+        signature: to_hex(
+            &state.keypair.sign(&signed_message(
+                initiator,
+                &peer_challenge[..],
+                &binding[..],
+                &state.advertised_addr,
+                &state.node_id,
+            ))[..],
+        ),
+    };
+    Codec::Json.write_frame(&mut stream.writer, &Codec::Json.encode(&response)?)?;
+
+    // tell the peer whether we accept it, so that it knows not to retry if
+    // we don't
+    let verified = verify(
+        state,
+        initiator,
+        &challenge[..],
+        &binding[..],
+        recv(stream)?,
+        dialed,
+    );
+    let verdict = match &verified {
+        Ok(_) => AuthMsg::Accepted,
+        Err(err) => AuthMsg::Refused(format!("{:#}", err)),
+    };
+    Codec::Json.write_frame(&mut stream.writer, &Codec::Json.encode(&verdict)?)?;
+    let identity = verified?;
+    return match recv(stream)? {
+        AuthMsg::Accepted => Ok(identity),
+        AuthMsg::Refused(reason) => {
+            Err(CollabError::Error(format!("Peer refused the connection: {}", reason)).into())
+        }
+        _ => auth_error("Expected the peer to accept or refuse the connection"),
+    };
+}
+
+/// Checks the peer's response to our challenge, returning who it is
+fn verify(
+    state: &SharedState,
+    initiator: bool,
+    challenge: &[u8],
+    binding: &[u8],
+    response: AuthMsg,
+    dialed: Option<&net::SocketAddr>,
+) -> Result<PeerIdentity> {
+    let (peer_mac, peer_key, peer_addr, peer_node_id, peer_signature) = match response {
+        AuthMsg::Response {
+            mac,
+            key,
+            addr,
+            node_id,
+            signature,
+        } => (from_hex(&mac), key, addr, node_id, from_hex(&signature)),
+        _ => return auth_error("Expected response"),
+    };
+
+    let knows_secret = match peer_mac {
+        Some(peer_mac) => mac(&state.secret, !initiator, challenge, binding)
+            .verify_slice(&peer_mac[..])
+            .is_ok(),
+        None => false,
+    };
+    if !knows_secret {
+        return auth_error("Peer does not know the session secret");
+    }
+
+    let public = keys::parse_key(&peer_key)?;
+    let signed = match peer_signature {
+        Some(signature) => keys::verify(
+            &public,
+            &signed_message(!initiator, challenge, binding, &peer_addr, &peer_node_id),
+            &signature[..],
+        ),
+        None => false,
+    };
+    if !signed {
+        return auth_error("Peer does not hold the key it presented");
+    }
+    if peer_node_id == state.node_id {
+        return auth_error("Connected to ourselves");
+    }
+
+    let peer_addr = *dialed.unwrap_or(&peer_addr);
+    if tcp::banned(state, Some(&peer_node_id), &peer_addr) {
+        return auth_error("Banned");
+    }
+    match keys::check_peer(&public, &peer_addr)? {
+        keys::Trust::Known => (),
+        keys::Trust::New => println!("Trusting new peer {} with key {}", peer_addr, peer_key),
+        keys::Trust::Changed(known) => {
+            println!("Trusting new peer {} with key {}", peer_addr, peer_key);
+            eprintln!(
+                "WARNING: peer at {} presented key {}, but {} was first seen there. Someone may \
+                 be impersonating it. If the key isn't expected to have changed, run \
+                 `collab untrust {}`.",
+                peer_addr, peer_key, known, peer_key
+            );
+        }
+        keys::Trust::Untrusted => {
+            return Err(CollabError::Error(format!(
+                "Peer at {} has untrusted key {}; run `collab trust {}` to trust it",
+                peer_addr, peer_key, peer_key
+            ))
+            .into());
+        }
+    }
+
+    return Ok(PeerIdentity {
+        key: peer_key,
+        addr: peer_addr,
+        node_id: peer_node_id,
+    });
+}
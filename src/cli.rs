@@ -1,17 +1,57 @@
 use crate::common::*;
-use std::{env, fs, net, path::PathBuf};
+use crate::queue;
+use std::{env, fs, net, path::PathBuf, time};
 
+// Only one command is parsed per run, so the size of starting one doesn't
+// matter
+#[allow(clippy::large_enum_variant)]
 pub enum CliCommand {
-    Start {
-        connect: Option<net::SocketAddr>,
+    Start(StartConfig),
+    Relay {
+        listen: net::SocketAddr,
     },
     Stop,
     Info,
     List,
+    Bridge,
+    Discover,
+    Summon {
+        peer: Option<NodeId>,
+        file: PathBuf,
+        line: u32,
+    },
+    Trust {
+        key: String,
+    },
+    Untrust {
+        key: String,
+    },
+    Connect {
+        addr: String,
+        fingerprint: Option<String>,
+    },
+    Disconnect {
+        peer: PeerRef,
+    },
+    Ban {
+        peer: PeerRef,
+    },
+    Unban {
+        peer: PeerRef,
+    },
+    /// Change the rate limits, in bytes per second (0 for none), leaving
+    /// those that are None as they are
+    Limit {
+        upload: Option<u64>,
+        download: Option<u64>,
+    },
     Attach {
         file: PathBuf,
         desc: String,
         mode: AttachMode,
+        encoding: Option<String>,
+        acks: AckLevel,
+        role: AttachRole,
     },
 }
 
@@ -20,10 +60,61 @@ pub struct Cli {
     pub command: CliCommand,
 }
 
+fn validate_socket_addr(str: String) -> std::result::Result<(), String> {
+    return match str.parse::<net::SocketAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("invalid address".to_string()),
+    };
+}
+
+fn validate_host_addr(str: String) -> std::result::Result<(), String> {
+    return match net::ToSocketAddrs::to_socket_addrs(&str) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    };
+}
+
+fn validate_peer_ref(str: String) -> std::result::Result<(), String> {
+    return match PeerRef::parse(&str) {
+        Some(_) => Ok(()),
+        None => Err("expected a node id, address or IP address".to_string()),
+    };
+}
+
+/// Parses a number of bytes, optionally followed by K, M or G for
+/// multiples of 1024
+fn parse_bytes(str: &str) -> Option<u64> {
+    let (digits, multiplier) = match str.chars().last() {
+        Some('K') | Some('k') => (&str[..str.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&str[..str.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&str[..str.len() - 1], 1 << 30),
+        _ => (str, 1),
+    };
+    return match digits.parse::<u64>() {
+        Ok(count) => count.checked_mul(multiplier),
+        Err(_) => None,
+    };
+}
+
+fn validate_bytes(str: String) -> std::result::Result<(), String> {
+    return match parse_bytes(&str) {
+        Some(_) => Ok(()),
+        None => Err("expected a number of bytes, e.g. 512K or 2M".to_string()),
+    };
+}
+
 #[context("unable to parse cli")]
 pub fn parse_cli() -> Result<Cli> {
     use clap::{App, Arg, SubCommand};
 
+    let peer_arg = Arg::with_name("peer")
+        .value_name("PEER")
+        .required(true)
+        .help(
+            "Node id or advertised address of the peer, or an IP address for every peer on a host",
+        )
+        .validator(validate_peer_ref);
+
     let matches = App::new("collab")
         .about("Google Docs for code")
         .arg(
@@ -41,18 +132,276 @@ pub fn parse_cli() -> Result<Cli> {
                     Arg::with_name("connect")
                         .short("c")
                         .long("connect")
+                        .value_name("HOST:PORT")
+                        .help("Instance to connect to, by host name or address")
+                        .takes_value(true)
+                        .requires("secret")
+                        .validator(validate_host_addr),
+                )
+                .arg(
+                    Arg::with_name("connect-cmd")
+                        .long("connect-cmd")
+                        .value_name("COMMAND")
+                        .help("Command to connect to an instance through, talking to it over the command's stdin and stdout, e.g. \"ssh devbox collab -r project bridge\"")
+                        .takes_value(true)
+                        .requires("secret")
+                        .conflicts_with("connect"),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDRESS:PORT")
+                        .help("Address to listen for peers on, e.g. 0.0.0.0:4000 or [::]:4000; port 0 picks a free port")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:0")
+                        .validator(validate_socket_addr),
+                )
+                .arg(
+                    Arg::with_name("advertise")
+                        .long("advertise")
                         .value_name("ADDRESS:PORT")
-                        .help("Instance to connect to")
+                        .help("Address peers should use to reach this instance, if not the listen address")
+                        .takes_value(true)
+                        .validator(validate_socket_addr),
+                )
+                .arg(
+                    Arg::with_name("tls")
+                        .long("tls")
+                        .help("Encrypt connections to peers with TLS"),
+                )
+                .arg(
+                    Arg::with_name("fingerprint")
+                        .long("fingerprint")
+                        .value_name("FINGERPRINT")
+                        .help("TLS certificate fingerprint of the instance to connect to, as printed when it starts")
+                        .takes_value(true)
+                        .requires("tls"),
+                )
+                .arg(
+                    Arg::with_name("secret")
+                        .short("s")
+                        .long("secret")
+                        .value_name("SECRET")
+                        .help("Secret that peers must know to join the session; when connecting, the secret of the session being joined. Generated and printed if not given")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-compression")
+                        .long("no-compression")
+                        .help("Don't compress messages sent to peers"),
+                )
+                .arg(
+                    Arg::with_name("heartbeat-timeout")
+                        .long("heartbeat-timeout")
+                        .value_name("SECONDS")
+                        .help("How long a peer can go without answering before it is shown as unreachable")
+                        .takes_value(true)
+                        .default_value("30")
+                        .validator(|str| match str.parse::<u64>() {
+                            Ok(secs) if secs > 0 => Ok(()),
+                            _ => Err("must be a positive number of seconds".to_string()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("queue-limit")
+                        .long("queue-limit")
+                        .value_name("MESSAGES")
+                        .help("Most messages to hold for a peer or attached client that can't keep up")
                         .takes_value(true)
-                        .validator(|str| match net::ToSocketAddrs::to_socket_addrs(&str) {
-                            Ok(_) => Ok(()),
-                            Err(_) => Err("invalid address".to_string()),
+                        .default_value("1024")
+                        .validator(|str| match str.parse::<usize>() {
+                            Ok(limit) if limit > 0 => Ok(()),
+                            _ => Err("must be a positive number of messages".to_string()),
                         }),
+                )
+                .arg(
+                    Arg::with_name("overflow")
+                        .long("overflow")
+                        .value_name("POLICY")
+                        .help("What to do once a peer's queue is full: wait for it to empty, drop writes that newer ones replace (waiting if that isn't enough), or disconnect and resync later. Attached clients are always disconnected")
+                        .takes_value(true)
+                        .possible_values(&["block", "collapse", "disconnect"])
+                        .default_value("collapse"),
+                )
+                .arg(
+                    Arg::with_name("relay")
+                        .long("relay")
+                        .value_name("ADDRESS:PORT")
+                        .help("Relay to join the session through, for peers that can't connect to each other directly")
+                        .takes_value(true)
+                        .requires("session")
+                        .validator(validate_socket_addr),
+                )
+                .arg(
+                    Arg::with_name("session")
+                        .long("session")
+                        .value_name("NAME")
+                        .help("Name of the session to join through the relay")
+                        .takes_value(true)
+                        .requires("relay"),
+                )
+                .arg(
+                    Arg::with_name("announce")
+                        .long("announce")
+                        .value_name("NAME")
+                        .help("Announce the session on the local network under this name, so that others can find it with `collab discover` and join it with --join")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("join")
+                        .long("join")
+                        .value_name("NAME")
+                        .help("Join the session announced on the local network under this name, and keep announcing it. With --tls, --fingerprint must be given too, as announcements can be forged")
+                        .takes_value(true)
+                        .requires("secret")
+                        .conflicts_with_all(&["connect", "connect-cmd", "announce"]),
+                )
+                .arg(
+                    Arg::with_name("upload-limit")
+                        .long("upload-limit")
+                        .value_name("BYTES")
+                        .help("Most bytes per second to send to peers, e.g. 512K or 2M. Only files are held back; edits go out straight away")
+                        .takes_value(true)
+                        .validator(validate_bytes),
+                )
+                .arg(
+                    Arg::with_name("download-limit")
+                        .long("download-limit")
+                        .value_name("BYTES")
+                        .help("Most bytes per second to receive from peers, e.g. 512K or 2M")
+                        .takes_value(true)
+                        .validator(validate_bytes),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("relay")
+                .about("Run a relay that daemons can join sessions through")
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDRESS:PORT")
+                        .help("Address to listen for daemons on")
+                        .takes_value(true)
+                        .default_value("0.0.0.0:4000")
+                        .validator(validate_socket_addr),
                 ),
         )
         .subcommand(SubCommand::with_name("stop").about("Stop the current session"))
         .subcommand(SubCommand::with_name("info").about("Print info for current session"))
         .subcommand(SubCommand::with_name("list").about("List all active sessions"))
+        .subcommand(
+            SubCommand::with_name("bridge")
+                .about("Connect stdin and stdout to the running daemon, for a daemon elsewhere to connect through with --connect-cmd"),
+        )
+        .subcommand(
+            SubCommand::with_name("discover")
+                .about("List sessions announced on the local network"),
+        )
+        .subcommand(
+            SubCommand::with_name("summon")
+                .about("Ask the editors of a peer to open a file at a line")
+                .arg(
+                    Arg::with_name("peer")
+                        .value_name("PEER")
+                        .required(true)
+                        .help("Node id of the peer to summon, or \"all\"")
+                        .validator(|str| match str.as_str() {
+                            "all" => Ok(()),
+                            _ => match uuid::Uuid::parse_str(&str) {
+                                Ok(_) => Ok(()),
+                                Err(_) => Err("expected a node id".to_string()),
+                            },
+                        }),
+                )
+                .arg(
+                    Arg::with_name("location")
+                        .value_name("FILE:LINE")
+                        .required(true)
+                        .help("File and line to open")
+                        .validator(|str| match str.rsplit_once(':') {
+                            Some((_, line)) => match line.parse::<u32>() {
+                                Ok(_) => Ok(()),
+                                Err(_) => Err("invalid line number".to_string()),
+                            },
+                            None => Err("expected FILE:LINE".to_string()),
+                        }),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trust")
+                .about("Trust a peer's public key, including one untrusted before")
+                .arg(
+                    Arg::with_name("key")
+                        .value_name("KEY")
+                        .required(true)
+                        .help("Public key of the peer, as printed by collab info"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("untrust")
+                .about("Refuse peers with a public key from now on")
+                .arg(
+                    Arg::with_name("key")
+                        .value_name("KEY")
+                        .required(true)
+                        .help("Public key of the peer, as printed by collab info"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("connect")
+                .about("Connect the running daemon to another peer")
+                .arg(
+                    Arg::with_name("address")
+                        .value_name("HOST:PORT")
+                        .required(true)
+                        .help("Host name or address of the peer")
+                        .validator(validate_host_addr),
+                )
+                .arg(
+                    Arg::with_name("fingerprint")
+                        .long("fingerprint")
+                        .value_name("FINGERPRINT")
+                        .help("TLS certificate fingerprint of the peer, if the session uses TLS")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disconnect")
+                .about("Drop the connection to a peer; peers that connected to this one may reconnect unless banned")
+                .arg(peer_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("ban")
+                .about("Disconnect a peer and refuse connections from it for the rest of the session")
+                .arg(peer_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("unban")
+                .about("Allow a banned peer to connect again")
+                .arg(peer_arg),
+        )
+        .subcommand(
+            SubCommand::with_name("limit")
+                .about("Change how fast the running daemon sends and receives files, or show the limits")
+                .arg(
+                    Arg::with_name("upload")
+                        .long("upload")
+                        .value_name("BYTES")
+                        .help("Most bytes per second to send to peers, e.g. 512K or 2M; 0 for no limit")
+                        .takes_value(true)
+                        .validator(validate_bytes),
+                )
+                .arg(
+                    Arg::with_name("download")
+                        .long("download")
+                        .value_name("BYTES")
+                        .help("Most bytes per second to receive from peers, e.g. 512K or 2M; 0 for no limit")
+                        .takes_value(true)
+                        .validator(validate_bytes),
+                ),
+        )
         .subcommand(SubCommand::with_name("attach")
                     .about("Used by editors to attach to files for publishing and receiving real-time changes")
                     .arg(
@@ -81,6 +430,37 @@ pub fn parse_cli() -> Result<Cli> {
                             .takes_value(true)
                             .possible_values(&["json", "csv"])
                             .default_value("json"),
+                    )
+                    .arg(
+                        Arg::with_name("encoding")
+                            .short("e")
+                            .long("encoding")
+                            .value_name("ENCODING")
+                            .help("Text encoding of the file, e.g. latin1, shift_jis or utf-16le; detected from the file if omitted")
+                            .takes_value(true)
+                            .validator(|str| match encoding_rs::Encoding::for_label(str.as_bytes()) {
+                                Some(_) => Ok(()),
+                                None => Err("unknown encoding".to_string()),
+                            }),
+                    )
+                    .arg(
+                        Arg::with_name("ack")
+                            .short("a")
+                            .long("ack")
+                            .value_name("LEVEL")
+                            .help("Acknowledge sent diffs once the daemon accepts them, and optionally once all peers have them")
+                            .takes_value(true)
+                            .possible_values(&["none", "daemon", "peers"])
+                            .default_value("none"),
+                    )
+                    .arg(
+                        Arg::with_name("role")
+                            .long("role")
+                            .value_name("ROLE")
+                            .help("Whether edits are applied directly or proposed as suggestions")
+                            .takes_value(true)
+                            .possible_values(&["edit", "suggest"])
+                            .default_value("edit"),
                     ),
         )
         .get_matches();
@@ -102,23 +482,108 @@ pub fn parse_cli() -> Result<Cli> {
 
     let command = match matches.subcommand() {
         ("start", Some(matches)) => {
-            let connect: Option<net::SocketAddr> = match matches.value_of("connect") {
-                Some(str) => {
-                    if fs::read_dir(&root)?.next().is_some() {
-                        return Err(CollabError::Error(
-                            "Root directory must be non-empty when connecting to an existing collab"
-                                .to_string(),
-                        ).into());
-                    }
-                    Some(str.parse()?)
-                }
+            let joining = matches.is_present("connect")
+                || matches.is_present("connect-cmd")
+                || matches.is_present("join");
+            if joining && fs::read_dir(&root)?.next().is_some() {
+                return Err(CollabError::Error(
+                    "Root directory must be non-empty when connecting to an existing collab"
+                        .to_string(),
+                )
+                .into());
+            }
+            let connect = matches.value_of("connect").map(String::from);
+            let listen = matches.value_of("listen").unwrap().parse()?;
+            let advertise = match matches.value_of("advertise") {
+                Some(str) => Some(str.parse()?),
                 None => None,
             };
-            CliCommand::Start { connect }
+            CliCommand::Start(StartConfig {
+                connect,
+                connect_cmd: matches.value_of("connect-cmd").map(String::from),
+                listen,
+                advertise,
+                tls: matches.is_present("tls"),
+                fingerprint: matches.value_of("fingerprint").map(String::from),
+                secret: matches.value_of("secret").map(String::from),
+                compression: !matches.is_present("no-compression"),
+                heartbeat_timeout: time::Duration::from_secs(
+                    matches.value_of("heartbeat-timeout").unwrap().parse()?,
+                ),
+                relay: match matches.value_of("relay") {
+                    Some(str) => Some(str.parse()?),
+                    None => None,
+                },
+                session: matches.value_of("session").map(String::from),
+                announce: matches.value_of("announce").map(String::from),
+                join: matches.value_of("join").map(String::from),
+                queue: queue::Policy {
+                    limit: matches.value_of("queue-limit").unwrap().parse()?,
+                    overflow: match matches.value_of("overflow") {
+                        Some("block") => queue::Overflow::Block,
+                        Some("collapse") => queue::Overflow::Collapse,
+                        Some("disconnect") => queue::Overflow::Disconnect,
+                        _ => panic!("got invalid overflow policy"),
+                    },
+                },
+                // a limit of 0 is no limit
+                upload_limit: matches
+                    .value_of("upload-limit")
+                    .and_then(parse_bytes)
+                    .filter(|limit| *limit > 0),
+                download_limit: matches
+                    .value_of("download-limit")
+                    .and_then(parse_bytes)
+                    .filter(|limit| *limit > 0),
+            })
         }
+        ("relay", Some(matches)) => CliCommand::Relay {
+            listen: matches.value_of("listen").unwrap().parse()?,
+        },
         ("stop", _) => CliCommand::Stop,
         ("info", _) | (_, None) => CliCommand::Info,
         ("list", _) => CliCommand::List,
+        ("bridge", _) => CliCommand::Bridge,
+        ("discover", _) => CliCommand::Discover,
+        ("summon", Some(matches)) => {
+            let peer = match matches.value_of("peer").unwrap() {
+                "all" => None,
+                str => Some(uuid::Uuid::parse_str(str)?.to_string()),
+            };
+            let (file, line) = matches
+                .value_of("location")
+                .unwrap()
+                .rsplit_once(':')
+                .unwrap();
+            CliCommand::Summon {
+                peer,
+                file: PathBuf::from(file).canonicalize()?,
+                line: line.parse()?,
+            }
+        }
+        ("trust", Some(matches)) => CliCommand::Trust {
+            key: matches.value_of("key").unwrap().to_string(),
+        },
+        ("untrust", Some(matches)) => CliCommand::Untrust {
+            key: matches.value_of("key").unwrap().to_string(),
+        },
+        ("connect", Some(matches)) => CliCommand::Connect {
+            addr: matches.value_of("address").unwrap().to_string(),
+            fingerprint: matches.value_of("fingerprint").map(String::from),
+        },
+        ("disconnect", Some(matches)) => CliCommand::Disconnect {
+            peer: PeerRef::parse(matches.value_of("peer").unwrap()).unwrap(),
+        },
+        ("ban", Some(matches)) => CliCommand::Ban {
+            peer: PeerRef::parse(matches.value_of("peer").unwrap()).unwrap(),
+        },
+        ("unban", Some(matches)) => CliCommand::Unban {
+            peer: PeerRef::parse(matches.value_of("peer").unwrap()).unwrap(),
+        },
+        ("limit", Some(matches)) => CliCommand::Limit {
+            upload: matches.value_of("upload").and_then(parse_bytes),
+            download: matches.value_of("download").and_then(parse_bytes),
+        },
         ("attach", Some(matches)) => {
             let file = PathBuf::from(matches.value_of("file").unwrap()).canonicalize()?;
             let desc = String::from(matches.value_of("description").unwrap());
@@ -127,7 +592,26 @@ pub fn parse_cli() -> Result<Cli> {
                 Some("csv") => AttachMode::Csv,
                 _ => panic!("got invalid mode"),
             };
-            CliCommand::Attach { file, desc, mode }
+            let encoding = matches.value_of("encoding").map(String::from);
+            let acks = match matches.value_of("ack") {
+                Some("none") => AckLevel::None,
+                Some("daemon") => AckLevel::Daemon,
+                Some("peers") => AckLevel::Peers,
+                _ => panic!("got invalid ack level"),
+            };
+            let role = match matches.value_of("role") {
+                Some("edit") => AttachRole::Edit,
+                Some("suggest") => AttachRole::Suggest,
+                _ => panic!("got invalid role"),
+            };
+            CliCommand::Attach {
+                file,
+                desc,
+                mode,
+                encoding,
+                acks,
+                role,
+            }
         }
         (subcommand, Some(_)) => panic!("unrecognized command: {}", subcommand),
     };
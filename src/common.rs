@@ -1,11 +1,11 @@
 use std::{
     collections::{hash_map::DefaultHasher, HashMap, HashSet},
-    fs, hash,
+    fmt, fs, hash,
     hash::{Hash, Hasher},
     net,
     path::{Path, PathBuf},
-    sync::mpsc,
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicBool, Arc, Mutex},
+    time,
 };
 
 pub use relative_path::{RelativePath, RelativePathBuf};
@@ -14,6 +14,8 @@ pub use anyhow::{Context, Error, Result};
 pub use context_attribute::context;
 
 use crate::collabignore;
+use crate::suggest::{Suggestion, Suggestions};
+use crate::{delta, keys, mesh, protocol, queue, ratelimit, resync, tls, transfer};
 
 #[derive(thiserror::Error, Debug)]
 pub enum CollabError {
@@ -22,7 +24,11 @@ pub enum CollabError {
 }
 
 pub type Reg = HashMap<RelativePathBuf, FsReg>;
-pub type Peers = HashMap<net::SocketAddr, Peer>;
+/// Identifies a daemon for as long as it runs, unlike its address, which
+/// can be shared with other daemons (e.g. behind the same NAT) or reached
+/// in more than one way
+pub type NodeId = String;
+pub type Peers = HashMap<NodeId, Peer>;
 
 pub fn strip_prefix(path: &Path, prefix: &Path) -> Result<RelativePathBuf> {
     return Ok(RelativePathBuf::from_path(path.strip_prefix(prefix)?)?);
@@ -35,12 +41,121 @@ pub fn path_join(prefix: &Path, path: &RelativePath) -> PathBuf {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct PeerInfo {
     pub advertised_addr: net::SocketAddr,
+    /// Host name and port that we dialed the peer by, resolved again each
+    /// time we connect. Only kept locally, since other peers go by the
+    /// advertised address.
+    #[serde(skip)]
+    pub host: Option<String>,
+    /// Command that we connected to the peer through (see stdio.rs), run
+    /// again each time we connect. Only kept locally, like the host name.
+    #[serde(skip)]
+    pub command: Option<String>,
+    /// Node id of the peer, once known
+    pub node_id: Option<NodeId>,
+    /// Fingerprint of the peer's TLS certificate, if TLS is in use
+    pub fingerprint: Option<String>,
+    /// Public key of the peer, once known
+    pub key: Option<String>,
+}
+
+impl PeerInfo {
+    /// What we connect to the peer by: the command, host name or address
+    pub fn target(&self) -> String {
+        return match (&self.command, &self.host) {
+            (Some(command), _) => format!("`{}`", command),
+            (None, Some(host)) => host.clone(),
+            (None, None) => self.advertised_addr.to_string(),
+        };
+    }
 }
 
 #[derive(Debug)]
 pub struct Peer {
-    pub sender: mpsc::Sender<RemoteMsg>,
+    pub sender: queue::Sender<RemoteMsg>,
+    pub info: PeerInfo,
+    /// Whether the advertised address is known. Until a peer that connected
+    /// to us sends its startup message, all we have is the address of its
+    /// end of the connection, which others can't connect to.
+    pub advertised: bool,
+    pub link: Link,
+    /// Optional protocol features that both sides support
+    pub capabilities: protocol::Capabilities,
+    /// Whether the peer has stopped answering heartbeats
+    pub unreachable: bool,
+}
+
+/// How we are connected to a peer
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Link {
+    /// We connected to the peer
+    Dialed,
+    /// The peer connected to us
+    Accepted,
+    /// Through a relay (see relay.rs)
+    Relayed,
+}
+
+/// Picks out a peer, e.g. to disconnect or ban it
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
+pub enum PeerRef {
+    Node(NodeId),
+    /// The address the peer advertises
+    Addr(net::SocketAddr),
+    /// Any peer on a host
+    Ip(net::IpAddr),
+}
+
+impl PeerRef {
+    /// Parses an address, an IP address or a node id
+    pub fn parse(str: &str) -> Option<Self> {
+        return match (
+            str.parse::<net::SocketAddr>(),
+            str.parse::<net::IpAddr>(),
+            uuid::Uuid::parse_str(str),
+        ) {
+            (Ok(addr), _, _) => Some(PeerRef::Addr(addr)),
+            (_, Ok(ip), _) => Some(PeerRef::Ip(ip)),
+            (_, _, Ok(uuid)) => Some(PeerRef::Node(uuid.to_string())),
+            _ => None,
+        };
+    }
+
+    /// Whether this picks out the peer with the given node id (if known)
+    /// and address
+    pub fn matches(&self, node_id: Option<&NodeId>, addr: &net::SocketAddr) -> bool {
+        return match self {
+            PeerRef::Node(id) => node_id == Some(id),
+            PeerRef::Addr(peer_addr) => peer_addr == addr,
+            PeerRef::Ip(ip) => ip == &addr.ip(),
+        };
+    }
+}
+
+impl fmt::Display for PeerRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            PeerRef::Node(id) => write!(f, "{}", id),
+            PeerRef::Addr(addr) => write!(f, "{}", addr),
+            PeerRef::Ip(ip) => write!(f, "{}", ip),
+        };
+    }
+}
+
+/// What `collab info` shows about a peer
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct PeerStatus {
     pub info: PeerInfo,
+    pub unreachable: bool,
+    /// Messages waiting to be sent to the peer
+    pub queued: usize,
+}
+
+/// What `collab info` shows about an attached client
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ClientStatus {
+    pub info: AttachedIpcClientInfo,
+    /// Messages waiting to be sent to the client
+    pub queued: usize,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
@@ -48,12 +163,34 @@ pub struct AttachedIpcClientInfo {
     pub path: RelativePathBuf,
     pub addr: net::SocketAddr,
     pub desc: String,
+    pub encoding: String,
+    pub role: AttachRole,
+}
+
+/// Whether an attached editor's diffs are applied directly or held as
+/// suggestions for someone with editing rights to accept
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Copy, Clone, Debug)]
+pub enum AttachRole {
+    Edit,
+    Suggest,
+}
+
+/// How much confirmation an attached editor wants for the diffs it sends
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum AckLevel {
+    /// No acknowledgements
+    None,
+    /// Acknowledge diffs once the daemon has accepted them
+    Daemon,
+    /// Additionally report when every connected peer has received them
+    Peers,
 }
 
 #[derive(Clone, Debug)]
 pub struct AttachedIpcClient {
-    pub sender: mpsc::Sender<IpcClientResponse>,
+    pub sender: queue::Sender<IpcClientResponse>,
     pub info: AttachedIpcClientInfo,
+    pub acks: AckLevel,
 }
 
 impl PartialEq for AttachedIpcClient {
@@ -72,9 +209,19 @@ impl hash::Hash for AttachedIpcClient {
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct IpcClientInfo {
+    pub node_id: NodeId,
     pub addr: net::SocketAddr,
-    pub peers: Vec<PeerInfo>,
-    pub attached_clients: Vec<AttachedIpcClientInfo>,
+    /// Address we listen for peers on, which may differ from the advertised
+    /// one
+    pub listen_addr: net::SocketAddr,
+    pub key: String,
+    pub fingerprint: Option<String>,
+    pub peers: Vec<PeerStatus>,
+    pub attached_clients: Vec<ClientStatus>,
+    pub bans: Vec<PeerRef>,
+    /// Bytes per second, if limited
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -84,6 +231,14 @@ pub struct BufferDiff {
     pub new_str: String,
 }
 
+impl BufferDiff {
+    /// The position just past the replaced text, or None if the range
+    /// doesn't fit in a buffer
+    pub fn end(&self) -> Option<u32> {
+        return self.pos.checked_add(self.old_len);
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct FilePerm {
     pub readonly: bool,
@@ -131,7 +286,7 @@ impl FilePerm {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Clone, Debug)]
 pub enum FsReg {
     File(u64, Option<FilePerm>),
     Dir,
@@ -144,6 +299,66 @@ pub enum FsDiff {
     Del(RelativePathBuf),
     Move(RelativePathBuf, RelativePathBuf),
     Chmod(RelativePathBuf, FilePerm),
+    /// Changes to a file, relative to a version the receiver should have
+    Delta(RelativePathBuf, delta::Delta),
+    /// A file too large to send in one message, with its size and hash, for
+    /// the receiver to fetch in chunks (see transfer.rs)
+    Large(RelativePathBuf, u64, u64),
+}
+
+/// Part of a file, which is only printed as its length. Encoded the same
+/// way as a Vec<u8>, but copied in one go rather than a byte at a time.
+#[derive(Clone)]
+pub struct Bytes(pub Vec<u8>);
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "<{} bytes>", self.0.len());
+    }
+}
+
+impl serde::Serialize for Bytes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.serialize_bytes(&self.0[..]);
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Bytes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Bytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                return write!(f, "bytes");
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<Bytes, E> {
+                return Ok(Bytes(bytes.to_vec()));
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<Bytes, E> {
+                return Ok(Bytes(bytes));
+            }
+
+            // JSON has no bytes, only arrays of numbers
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Bytes, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                loop {
+                    match seq.next_element()? {
+                        Some(byte) => bytes.push(byte),
+                        None => return Ok(Bytes(bytes)),
+                    }
+                }
+            }
+        }
+
+        return deserializer.deserialize_byte_buf(Visitor);
+    }
 }
 
 // TODO: this type structure is a mess. clean it up, please??
@@ -151,9 +366,47 @@ pub enum FsDiff {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum RemoteMsg {
     FsDiff(FsDiff),
-    BufferDiff(RelativePathBuf, BufferDiff),
-    AddPeer(net::SocketAddr),
+    BufferDiff(RelativePathBuf, BufferDiff, Option<u64>),
+    BufferAck(u64),
+    Suggestion(Suggestion),
+    SuggestionResolved {
+        id: String,
+        accepted: bool,
+    },
+    Summon {
+        path: RelativePathBuf,
+        line: u32,
+        from: NodeId,
+    },
+    AddPeer(PeerInfo),
     Startup(net::SocketAddr),
+    /// Several messages sent together so that they compress well
+    Batch(Vec<RemoteMsg>),
+    /// Ask for the whole of a file, when a delta couldn't be applied
+    RequestFile(RelativePathBuf),
+    /// Sent first when reconnecting to a peer after losing the connection,
+    /// with our advertised address and register
+    Resync(net::SocketAddr, Reg),
+    /// Reply to a resync, with the register of the peer reconnected to
+    ResyncReply(Reg),
+    /// A message for everyone, which peers pass along (see mesh.rs)
+    Mesh(mesh::MsgId, Box<RemoteMsg>),
+    /// Sent periodically to check that the peer is still there
+    Ping,
+    Pong,
+    /// Ask for part of a large file, which must still have the given hash
+    FetchChunk {
+        path: RelativePathBuf,
+        hash: u64,
+        offset: u64,
+    },
+    /// Reply to a fetch, empty if the file has changed since
+    Chunk {
+        path: RelativePathBuf,
+        hash: u64,
+        offset: u64,
+        data: Bytes,
+    },
     LocalDisconnect,
 }
 
@@ -161,8 +414,42 @@ pub enum RemoteMsg {
 pub enum IpcClientMsg {
     ShutdownRequest,
     InfoRequest,
-    AttachRequest { path: RelativePathBuf, desc: String },
-    BufferDiff(BufferDiff),
+    AttachRequest {
+        path: RelativePathBuf,
+        desc: String,
+        encoding: String,
+        acks: AckLevel,
+        role: AttachRole,
+    },
+    BufferDiff {
+        seq: u64,
+        diff: BufferDiff,
+    },
+    AcceptSuggestion(String),
+    RejectSuggestion(String),
+    /// Ask the editors attached to the peer with a node id (or all peers
+    /// if None) to open a path at a line
+    SummonRequest {
+        peer: Option<NodeId>,
+        path: RelativePathBuf,
+        line: u32,
+    },
+    /// Connect to another peer, as `collab start --connect` does
+    ConnectRequest {
+        addr: String,
+        fingerprint: Option<String>,
+    },
+    DisconnectRequest(PeerRef),
+    /// Disconnect matching peers and refuse connections from them until
+    /// unbanned
+    BanRequest(PeerRef),
+    UnbanRequest(PeerRef),
+    /// Change the limits on traffic to and from peers, in bytes per second.
+    /// None leaves a limit as it is and 0 lifts it.
+    RateLimitRequest {
+        upload: Option<u64>,
+        download: Option<u64>,
+    },
     LocalDisconnect,
 }
 
@@ -170,6 +457,25 @@ pub enum IpcClientMsg {
 pub enum IpcClientResponse {
     Info(IpcClientInfo),
     BufferDiff(BufferDiff),
+    Ack(u64),
+    Synced(u64),
+    Suggestion(Suggestion),
+    SuggestionResolved {
+        id: String,
+        accepted: bool,
+    },
+    Summon {
+        path: RelativePathBuf,
+        line: u32,
+        from: NodeId,
+    },
+    Done,
+    Error(String),
+    Shutdown,
+    PeerJoined(PeerInfo),
+    PeerLeft(PeerInfo),
+    FileDeleted(RelativePathBuf),
+    FileMoved(RelativePathBuf, RelativePathBuf),
     LocalDisconnect,
     RemoteDisconnect,
 }
@@ -183,8 +489,8 @@ pub enum MsgBody {
 #[derive(Debug)]
 pub enum MsgSource {
     Inotify,
-    Peer(net::SocketAddr),
-    IpcClient(mpsc::Sender<IpcClientResponse>, net::SocketAddr),
+    Peer(NodeId),
+    IpcClient(queue::Sender<IpcClientResponse>, net::SocketAddr),
 }
 
 #[derive(Debug)]
@@ -199,6 +505,9 @@ pub struct AttachedClients {
     by_addr: HashMap<net::SocketAddr, AttachedIpcClient>,
 }
 
+// Clients hash by their info only, so the queue inside them changing doesn't
+// matter
+#[allow(clippy::mutable_key_type)]
 impl AttachedClients {
     pub fn new() -> Self {
         return AttachedClients {
@@ -231,7 +540,6 @@ impl AttachedClients {
             }
             None => panic!("inconsistent attached clients data structure"),
         }
-        self.by_path.remove(&client.info.path);
         self.by_addr.remove(&client.info.addr);
     }
 
@@ -249,6 +557,119 @@ impl AttachedClients {
     pub fn all(&self) -> impl Iterator<Item = &AttachedIpcClient> {
         return self.by_addr.values();
     }
+
+    /// Returns all clients attached to the given path or to anything inside it
+    pub fn get_under(&self, path: &RelativePath) -> Vec<AttachedIpcClient> {
+        return self
+            .all()
+            .filter(|client| client.info.path.starts_with(path))
+            .map(AttachedIpcClient::clone)
+            .collect();
+    }
+
+    /// Re-points clients attached to anything under `from` so that they
+    /// are attached to the corresponding path under `to`
+    pub fn move_path(&mut self, from: &RelativePath, to: &RelativePath) {
+        for client in self.get_under(from) {
+            match self.by_path.get_mut(&client.info.path) {
+                Some(set) => {
+                    set.remove(&client);
+                    if set.is_empty() {
+                        self.by_path.remove(&client.info.path);
+                    }
+                }
+                None => panic!("inconsistent attached clients data structure"),
+            }
+            let suffix = client.info.path.strip_prefix(from).unwrap();
+            let mut info = client.info.clone();
+            info.path = if suffix.as_str().is_empty() {
+                to.to_relative_path_buf()
+            } else {
+                to.join(suffix)
+            };
+            self.add(AttachedIpcClient {
+                sender: client.sender,
+                info,
+                acks: client.acks,
+            });
+        }
+    }
+
+    /// Sends a response to every attached client. Clients that have gone
+    /// away are ignored; they are cleaned up when their disconnect arrives.
+    pub fn broadcast(&self, response: IpcClientResponse) {
+        for client in self.all() {
+            client.sender.send(response.clone());
+        }
+    }
+}
+
+/// A buffer diff forwarded to peers on behalf of an attached client that
+/// wants to know once all of them have received it
+#[derive(Debug)]
+pub struct PendingAck {
+    pub client: net::SocketAddr,
+    pub seq: u64,
+    pub waiting: HashSet<NodeId>,
+}
+
+#[derive(Debug)]
+pub struct PendingAcks {
+    next_id: u64,
+    pending: HashMap<u64, PendingAck>,
+}
+
+impl PendingAcks {
+    pub fn new() -> Self {
+        return PendingAcks {
+            next_id: 0,
+            pending: HashMap::new(),
+        };
+    }
+
+    /// Starts waiting on the given peers, returning the id to send along
+    /// with the diff
+    pub fn add(&mut self, client: net::SocketAddr, seq: u64, waiting: HashSet<NodeId>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            PendingAck {
+                client,
+                seq,
+                waiting,
+            },
+        );
+        return id;
+    }
+
+    /// Records that a peer has received a diff. Returns the pending
+    /// acknowledgement if that was the last peer being waited on.
+    pub fn ack(&mut self, id: u64, peer: &NodeId) -> Option<PendingAck> {
+        let done = match self.pending.get_mut(&id) {
+            Some(pending) => {
+                pending.waiting.remove(peer);
+                pending.waiting.is_empty()
+            }
+            None => false,
+        };
+        return if done { self.pending.remove(&id) } else { None };
+    }
+
+    /// Stops waiting on a peer that has gone away, returning any
+    /// acknowledgements that are now complete
+    pub fn remove_peer(&mut self, peer: &NodeId) -> Vec<PendingAck> {
+        let ids: Vec<u64> = self.pending.keys().cloned().collect();
+        return ids
+            .into_iter()
+            .filter_map(|id| self.ack(id, peer))
+            .collect();
+    }
+
+    /// Drops everything pending for a client that has gone away
+    pub fn remove_client(&mut self, client: &net::SocketAddr) {
+        self.pending.retain(|_, pending| &pending.client != client);
+    }
 }
 
 #[derive(Clone)]
@@ -256,7 +677,83 @@ pub struct SharedState {
     pub register: Arc<Mutex<Reg>>,
     pub peers: Arc<Mutex<Peers>>,
     pub attached_clients: Arc<Mutex<AttachedClients>>,
+    pub pending_acks: Arc<Mutex<PendingAcks>>,
+    /// Peers being reconnected to, each with a flag that tells the thread
+    /// reconnecting to stop, e.g. once the peer has connected by itself
+    pub reconnecting: Arc<Mutex<HashMap<NodeId, Arc<AtomicBool>>>>,
+    /// Where things stood with each peer when it disconnected, to
+    /// resync against if it comes back
+    pub snapshots: Arc<Mutex<HashMap<NodeId, resync::Snapshot>>>,
+    /// Ids of messages passed around between peers
+    pub mesh: Arc<Mutex<mesh::Mesh>>,
+    /// Previous contents of files, to send deltas against
+    pub bases: Arc<Mutex<delta::Bases>>,
+    /// Large files being fetched from peers
+    pub transfers: Arc<Mutex<transfer::Transfers>>,
+    pub suggestions: Arc<Mutex<Suggestions>>,
+    /// Peers that aren't allowed to connect
+    pub bans: Arc<Mutex<HashSet<PeerRef>>>,
     pub ignore: Arc<Mutex<collabignore::Ignore>>,
+    /// Certificate used for peer connections, if TLS is enabled
+    pub tls: Option<Arc<tls::Identity>>,
+    /// Secret that peers must know to join the session
+    pub secret: Arc<String>,
+    /// Keypair identifying this user to peers
+    pub keypair: Arc<keys::Keypair>,
+    pub node_id: NodeId,
+    /// Address that peers should use to reach this instance
+    pub advertised_addr: net::SocketAddr,
+    /// Whether to offer compression to peers
+    pub compression: bool,
+    /// How long a peer can go without answering before it is considered
+    /// unreachable
+    pub heartbeat_timeout: time::Duration,
+    /// How many messages to hold for each peer or client, and what to do
+    /// once there are more
+    pub queue: queue::Policy,
+    /// Limits on traffic to and from peers
+    pub limits: ratelimit::Limits,
+}
+
+/// Options for starting a daemon
+#[derive(Clone, Debug)]
+pub struct StartConfig {
+    /// Existing instance to connect to, as a host name or address with a
+    /// port
+    pub connect: Option<String>,
+    /// Command to connect to an existing instance through, speaking to it
+    /// over the command's stdin and stdout (see stdio.rs)
+    pub connect_cmd: Option<String>,
+    /// Address to bind the peer listener to
+    pub listen: net::SocketAddr,
+    /// Address that other peers should use to reach this one, if different
+    /// from the bound address (e.g. when listening on 0.0.0.0 or behind NAT)
+    pub advertise: Option<net::SocketAddr>,
+    /// Whether to encrypt peer connections with TLS
+    pub tls: bool,
+    /// Expected certificate fingerprint of the instance to connect to
+    pub fingerprint: Option<String>,
+    /// Session secret; generated if not given
+    pub secret: Option<String>,
+    /// Whether to offer compression to peers
+    pub compression: bool,
+    /// How long a peer can go without answering before it is considered
+    /// unreachable
+    pub heartbeat_timeout: time::Duration,
+    /// Relay to join the session through
+    pub relay: Option<net::SocketAddr>,
+    /// Name of the session to join through the relay
+    pub session: Option<String>,
+    /// Name to announce the session under on the local network
+    pub announce: Option<String>,
+    /// Name of a session announced on the local network to join
+    pub join: Option<String>,
+    /// How many messages to hold for each peer or client, and what to do
+    /// once there are more
+    pub queue: queue::Policy,
+    /// Most bytes per second to send to and receive from peers, if limited
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -265,6 +762,23 @@ pub enum AttachMode {
     Csv,
 }
 
+pub fn to_hex(data: &[u8]) -> String {
+    return data.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+pub fn from_hex(str: &str) -> Option<Vec<u8>> {
+    if !str.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..str.len())
+        .step_by(2)
+        .map(|i| {
+            str.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect();
+}
+
 pub fn hash_file(data: &Arc<Vec<u8>>) -> u64 {
     let mut hasher = DefaultHasher::new();
     data.hash(&mut hasher);
@@ -0,0 +1,271 @@
+use crate::common::*;
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+// When a large file changes, peers that already have the previous version
+// are sent only what changed, rsync-style: the new contents are described
+// as ranges copied from the previous version (the base) and the bytes in
+// between that are new. The base is identified by the hash kept in the
+// register, which every peer in the session agrees on. A receiver that
+// doesn't have that version on disk asks for the whole file instead.
+//
+// Unlike rsync, the sender doesn't need to ask the receiver what it has,
+// since it keeps its own copy of the base. Only files within a size range
+// are kept: smaller ones aren't worth a delta, and larger ones would take
+// up too much memory.
+
+/// Files smaller than this are always sent whole
+const MIN_SIZE: usize = 4096;
+
+/// Files larger than this aren't kept as bases
+pub const MAX_BASE_SIZE: usize = 16 << 20;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Delta {
+    /// Hash of the version the delta applies to
+    pub base: u64,
+    /// Hash of the contents once the delta is applied
+    pub hash: u64,
+    pub ops: Vec<DeltaOp>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum DeltaOp {
+    /// Copy a range of the base
+    Copy { offset: u64, len: u64 },
+    /// Insert new bytes
+    Insert(Vec<u8>),
+}
+
+impl Delta {
+    /// Roughly how many bytes the delta takes up on the wire
+    pub fn size(&self) -> usize {
+        return self
+            .ops
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Copy { .. } => 16,
+                DeltaOp::Insert(data) => 8 + data.len(),
+            })
+            .sum();
+    }
+
+    /// Applies the delta to the base, checking that the result is what the
+    /// sender had
+    #[context("unable to apply delta")]
+    pub fn patch(&self, base: &[u8]) -> Result<Arc<Vec<u8>>> {
+        let mut data = Vec::new();
+        for op in &self.ops {
+            match op {
+                DeltaOp::Copy { offset, len } => match offset.checked_add(*len) {
+                    Some(end) if end <= base.len() as u64 => {
+                        data.extend_from_slice(&base[*offset as usize..end as usize])
+                    }
+                    _ => {
+                        return Err(
+                            CollabError::Error("Delta copies past end of base".to_string()).into(),
+                        )
+                    }
+                },
+                DeltaOp::Insert(bytes) => data.extend_from_slice(&bytes[..]),
+            }
+        }
+        let data = Arc::new(data);
+        if hash_file(&data) != self.hash {
+            return Err(CollabError::Error("Delta produced the wrong contents".to_string()).into());
+        }
+        return Ok(data);
+    }
+}
+
+/// Rebuilds the contents described by a delta from the base on disk,
+/// returning them as a write
+#[context("unable to resolve delta for {}", path)]
+pub fn resolve(root: &Path, path: &RelativePath, delta: &Delta) -> Result<FsDiff> {
+    let base = Arc::new(fs::read(path_join(root, path))?);
+    if hash_file(&base) != delta.base {
+        return Err(CollabError::Error("Base version is not on disk".to_string()).into());
+    }
+    let data = delta.patch(&base[..])?;
+    return Ok(FsDiff::Write(path.to_relative_path_buf(), data));
+}
+
+fn block_size(len: usize) -> usize {
+    return ((len as f64).sqrt() as usize).clamp(64, 1 << 16);
+}
+
+/// The weak checksum from rsync, which can be rolled along the data a byte
+/// at a time
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut rolling = Rolling { a: 0, b: 0, len };
+        for (i, byte) in block.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(*byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        return rolling;
+    }
+
+    fn digest(&self) -> u32 {
+        return (self.a & 0xffff) | (self.b << 16);
+    }
+
+    /// Moves the window one byte along
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+fn push_copy(ops: &mut Vec<DeltaOp>, offset: usize, len: usize) {
+    match ops.last_mut() {
+        Some(DeltaOp::Copy {
+            offset: prev_offset,
+            len: prev_len,
+        }) if *prev_offset + *prev_len == offset as u64 => *prev_len += len as u64,
+        _ => ops.push(DeltaOp::Copy {
+            offset: offset as u64,
+            len: len as u64,
+        }),
+    }
+}
+
+fn push_insert(ops: &mut Vec<DeltaOp>, data: &[u8]) {
+    if !data.is_empty() {
+        ops.push(DeltaOp::Insert(data.to_vec()));
+    }
+}
+
+/// Works out the operations that turn `base` into `data`
+pub fn compute(base: &[u8], data: &[u8]) -> Vec<DeltaOp> {
+    let block = block_size(base.len());
+    let mut ops = Vec::new();
+    if base.len() < block || data.len() < block {
+        push_insert(&mut ops, data);
+        return ops;
+    }
+
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for start in (0..=base.len() - block).step_by(block) {
+        index
+            .entry(Rolling::new(&base[start..start + block]).digest())
+            .or_default()
+            .push(start);
+    }
+
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = Rolling::new(&data[..block]);
+    loop {
+        let window = &data[pos..pos + block];
+        let found = match index.get(&rolling.digest()) {
+            Some(starts) => starts
+                .iter()
+                .find(|start| &base[**start..**start + block] == window),
+            None => None,
+        };
+        match found {
+            Some(start) => {
+                push_insert(&mut ops, &data[literal_start..pos]);
+                push_copy(&mut ops, *start, block);
+                pos += block;
+                literal_start = pos;
+                if pos + block > data.len() {
+                    break;
+                }
+                rolling = Rolling::new(&data[pos..pos + block]);
+            }
+            None => {
+                if pos + block >= data.len() {
+                    break;
+                }
+                rolling.roll(data[pos], data[pos + block]);
+                pos += 1;
+            }
+        }
+    }
+    push_insert(&mut ops, &data[literal_start..]);
+    return ops;
+}
+
+/// The last contents of each file of a suitable size, to compute deltas
+/// against
+#[derive(Debug)]
+pub struct Bases {
+    files: HashMap<RelativePathBuf, (u64, Arc<Vec<u8>>)>,
+}
+
+impl Bases {
+    pub fn new() -> Self {
+        return Bases {
+            files: HashMap::new(),
+        };
+    }
+
+    /// Returns a delta to send instead of writing `data` to `path`, if
+    /// peers have the version with the given hash and a delta is worth it
+    pub fn delta(&self, path: &RelativePath, base: u64, data: &Arc<Vec<u8>>) -> Option<FsDiff> {
+        if data.len() < MIN_SIZE {
+            return None;
+        }
+        let base_data = match self.files.get(path) {
+            Some((hash, base_data)) if *hash == base => base_data,
+            _ => return None,
+        };
+        let delta = Delta {
+            base,
+            hash: hash_file(data),
+            ops: compute(&base_data[..], &data[..]),
+        };
+        // not worth it if most of the file changed anyway
+        return if delta.size() < data.len() / 2 {
+            Some(FsDiff::Delta(path.to_relative_path_buf(), delta))
+        } else {
+            None
+        };
+    }
+
+    /// Keeps track of the latest contents of files
+    pub fn record(&mut self, diff: &FsDiff) {
+        match diff {
+            FsDiff::Write(path, data) if data.len() >= MIN_SIZE && data.len() <= MAX_BASE_SIZE => {
+                self.files
+                    .insert(path.clone(), (hash_file(data), data.clone()));
+            }
+            FsDiff::Write(path, _) | FsDiff::Delta(path, _) | FsDiff::Large(path, _, _) => {
+                self.files.remove(path);
+            }
+            FsDiff::Del(path) => self.files.retain(|file, _| !file.starts_with(path)),
+            FsDiff::Move(from, to) => {
+                let moved: Vec<RelativePathBuf> = self
+                    .files
+                    .keys()
+                    .filter(|file| file.starts_with(from))
+                    .cloned()
+                    .collect();
+                for file in moved {
+                    let base = self.files.remove(&file).unwrap();
+                    let suffix = file.strip_prefix(from).unwrap();
+                    let new_path = if suffix.as_str().is_empty() {
+                        to.to_relative_path_buf()
+                    } else {
+                        to.join(suffix)
+                    };
+                    self.files.insert(new_path, base);
+                }
+            }
+            FsDiff::NewDir(_) | FsDiff::Chmod(_, _) => (),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::common::*;
+use std::{collections::HashMap, ffi, io, net, thread, time};
+
+// Sessions can be found on the local network instead of copying addresses
+// around. A daemon started with --announce sends a small UDP packet to a
+// multicast group every few seconds, with the name of the session, where to
+// connect to it and how many peers it has. `collab discover` and
+// `collab start --join` listen to the group for a while and go by what they
+// hear. A daemon that joined a session by name keeps announcing it, so the
+// session can still be found once the daemon that started it is gone.
+//
+// Announcements only say where a session is: joining it still takes the
+// session secret, so nothing in them needs hiding. Nothing in them is
+// authenticated either, so the certificate fingerprint they carry is never
+// pinned; joining over TLS takes the fingerprint on the command line. Names aren't checked
+// for uniqueness, so it is up to users to keep them apart. Announcements
+// aren't routed beyond the local network.
+
+const GROUP: net::Ipv4Addr = net::Ipv4Addr::new(239, 255, 67, 76);
+const PORT: u16 = 47654;
+
+const ANNOUNCE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// How long to listen for announcements, long enough to hear every
+/// announcing daemon at least once
+const LISTEN_TIME: time::Duration = time::Duration::from_millis(2500);
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub name: String,
+    pub node_id: NodeId,
+    /// Host name of the machine the daemon runs on
+    pub host: String,
+    /// Advertised address of the daemon
+    pub addr: net::SocketAddr,
+    /// Fingerprint of the daemon's TLS certificate, if TLS is in use. Only
+    /// informational, as anyone can send an announcement
+    pub fingerprint: Option<String>,
+    /// How many peers the daemon is connected to
+    pub peers: usize,
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return None;
+    }
+    return match ffi::CStr::from_bytes_until_nul(&buf) {
+        Ok(name) => Some(name.to_string_lossy().into_owned()),
+        Err(_) => None,
+    };
+}
+
+/// Starts announcing the session under the given name
+#[context("unable to announce session {}", name)]
+pub fn announce(name: &str, state: &SharedState) -> Result<()> {
+    let socket = net::UdpSocket::bind((net::Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_multicast_ttl_v4(1)?;
+    if state.advertised_addr.ip().is_loopback() {
+        eprintln!(
+            "Announcing {} with a loopback address; only this machine will be able to join. Use --listen and --advertise to accept others",
+            state.advertised_addr
+        );
+    }
+    println!("Announcing session {} on the local network", name);
+
+    let host = hostname().unwrap_or_else(|| state.advertised_addr.ip().to_string());
+    let (name, state) = (name.to_string(), state.clone());
+    thread::spawn(move || loop {
+        let announcement = Announcement {
+            name: name.clone(),
+            node_id: state.node_id.clone(),
+            host: host.clone(),
+            addr: state.advertised_addr,
+            fingerprint: state.tls.as_ref().map(|tls| tls.fingerprint.clone()),
+            peers: state.peers.lock().unwrap().len(),
+        };
+        let data = serde_json::to_vec(&announcement).unwrap();
+        // the network may come and go, so keep trying
+        match socket.send_to(&data[..], (GROUP, PORT)) {
+            Ok(_) => (),
+            Err(err) => eprintln!("Unable to announce session: {}", err),
+        }
+        thread::sleep(ANNOUNCE_INTERVAL);
+    });
+    return Ok(());
+}
+
+/// Listens for announcements until `done` returns true for one of them or
+/// `LISTEN_TIME` has passed, returning the latest announcement from each
+/// daemon heard from
+#[context("unable to listen for sessions")]
+fn listen<F: Fn(&Announcement) -> bool>(done: F) -> Result<Vec<Announcement>> {
+    // others on this machine may be listening too
+    let socket = net2::UdpBuilder::new_v4()?
+        .reuse_address(true)?
+        .bind((net::Ipv4Addr::UNSPECIFIED, PORT))?;
+    socket.join_multicast_v4(&GROUP, &net::Ipv4Addr::UNSPECIFIED)?;
+
+    let mut heard = HashMap::new();
+    let deadline = time::Instant::now() + LISTEN_TIME;
+    let mut buf = [0u8; 4096];
+    loop {
+        let now = time::Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(err) => return Err(err.into()),
+        };
+        // anything else sent to the group isn't for us
+        let announcement: Announcement = match serde_json::from_slice(&buf[..len]) {
+            Ok(announcement) => announcement,
+            Err(_) => continue,
+        };
+        let done = done(&announcement);
+        heard.insert(announcement.node_id.clone(), announcement);
+        if done {
+            break;
+        }
+    }
+
+    let mut heard: Vec<Announcement> = heard.into_values().collect();
+    heard.sort_by(|a, b| (&a.name, &a.host, a.addr).cmp(&(&b.name, &b.host, b.addr)));
+    return Ok(heard);
+}
+
+/// Lists the sessions announced on the local network
+pub fn discover() -> Result<Vec<Announcement>> {
+    return listen(|_| false);
+}
+
+/// Finds a daemon announcing the session with the given name
+pub fn find(name: &str) -> Result<Announcement> {
+    let heard = listen(|announcement| announcement.name == name)?;
+    return match heard
+        .into_iter()
+        .find(|announcement| announcement.name == name)
+    {
+        Some(announcement) => Ok(announcement),
+        None => Err(CollabError::Error(format!(
+            "No session named {} found on the local network",
+            name
+        ))
+        .into()),
+    };
+}
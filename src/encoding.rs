@@ -0,0 +1,108 @@
+use crate::common::*;
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::{fs, io, path::Path};
+
+// The daemon only ever deals in Unicode. Editors that keep a file in some
+// other encoding talk to `collab attach` in that encoding, and attach
+// converts at the boundary. File contents on disk are synchronized as raw
+// bytes, so they are never re-encoded.
+
+/// Looks up an encoding by any of its WHATWG labels, e.g. "latin1".
+#[context("unable to find encoding: {}", label)]
+pub fn lookup(label: &str) -> Result<&'static Encoding> {
+    return match Encoding::for_label(label.as_bytes()) {
+        Some(encoding) => Ok(encoding),
+        None => Err(CollabError::Error(format!("Unknown encoding: {}", label)).into()),
+    };
+}
+
+/// Guesses the encoding of a file from its contents. A byte order mark
+/// wins; otherwise valid UTF-8 is assumed to be UTF-8, and anything else
+/// is treated as Latin-1 (windows-1252), which can decode any byte string.
+#[context("unable to detect encoding: {}", path.display())]
+pub fn detect(path: &Path) -> Result<&'static Encoding> {
+    let data = fs::read(path)?;
+    return Ok(match Encoding::for_bom(&data) {
+        Some((encoding, _)) => encoding,
+        None => match std::str::from_utf8(&data) {
+            Ok(_) => UTF_8,
+            Err(_) => WINDOWS_1252,
+        },
+    });
+}
+
+/// Encodes text in the given encoding. encoding_rs follows the WHATWG
+/// spec, which never encodes to UTF-16, so that is handled here.
+pub fn encode(encoding: &'static Encoding, text: &str) -> Vec<u8> {
+    if encoding == UTF_16LE {
+        return text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    } else if encoding == UTF_16BE {
+        return text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    } else {
+        let (data, _, _) = encoding.encode(text);
+        return data.into_owned();
+    }
+}
+
+/// Splits a byte stream in some encoding into lines of Unicode text.
+/// Decoding happens before splitting, so multi-byte encodings whose
+/// newlines are not a single '\n' byte (e.g. UTF-16) work too.
+pub struct LineReader<R: io::Read> {
+    reader: R,
+    decoder: Decoder,
+    buf: String,
+    eof: bool,
+}
+
+impl<R: io::Read> LineReader<R> {
+    pub fn new(reader: R, encoding: &'static Encoding) -> Self {
+        return Self {
+            reader,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            buf: String::new(),
+            eof: false,
+        };
+    }
+
+    #[context("unable to read encoded input")]
+    fn fill(&mut self) -> Result<()> {
+        let mut chunk = [0; 4096];
+        let size = self.reader.read(&mut chunk)?;
+        self.eof = size == 0;
+        match self.decoder.max_utf8_buffer_length(size) {
+            Some(len) => self.buf.reserve(len),
+            None => return Err(CollabError::Error("Input too large".to_string()).into()),
+        }
+        // with enough space reserved, the decoder always consumes all input
+        let _ = self
+            .decoder
+            .decode_to_string(&chunk[..size], &mut self.buf, self.eof);
+        return Ok(());
+    }
+}
+
+impl<R: io::Read> Iterator for LineReader<R> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        loop {
+            match self.buf.find('\n') {
+                Some(index) => {
+                    let line: String = self.buf.drain(..=index).collect();
+                    return Some(Ok(line.trim_end_matches(&['\n', '\r'][..]).to_string()));
+                }
+                None if self.eof => {
+                    return if self.buf.is_empty() {
+                        None
+                    } else {
+                        Some(Ok(self.buf.drain(..).collect()))
+                    };
+                }
+                None => match self.fill() {
+                    Ok(()) => (),
+                    Err(err) => return Some(Err(err)),
+                },
+            }
+        }
+    }
+}
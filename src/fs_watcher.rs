@@ -1,10 +1,10 @@
 use crate::collabignore;
 use crate::common::*;
+use crate::transfer;
 use std::{
     fs,
     path::{Path, PathBuf},
     sync::mpsc,
-    sync::Arc,
     thread, time,
 };
 
@@ -28,6 +28,9 @@ impl FsDiff {
             Chmod(path, perm) => {
                 perm.set(&path_join(root, path))?;
             }
+            Delta(path, delta) => crate::delta::resolve(root, path, delta)?.apply(root)?,
+            // already in place by the time it is applied (see transfer.rs)
+            Large(_, _, _) => (),
         };
 
         return Ok(());
@@ -47,6 +50,22 @@ impl FsDiff {
                 reg.insert(path.clone(), File(hash_file(data), perm));
                 Ok(())
             }
+            Delta(path, delta) => {
+                let perm = match reg.get(path) {
+                    Some(FsReg::File(_, Some(perm))) => Some(perm.clone()),
+                    _ => None,
+                };
+                reg.insert(path.clone(), File(delta.hash, perm));
+                Ok(())
+            }
+            Large(path, _, hash) => {
+                let perm = match reg.get(path) {
+                    Some(FsReg::File(_, Some(perm))) => Some(perm.clone()),
+                    _ => None,
+                };
+                reg.insert(path.clone(), File(*hash, perm));
+                Ok(())
+            }
             NewDir(path) => {
                 reg.insert(path.clone(), Dir);
                 Ok(())
@@ -88,6 +107,16 @@ impl FsDiff {
                 None => true,
                 _ => false,
             },
+            Delta(path, delta) => match reg.get(path) {
+                Some(File(prev_data, _)) => &delta.hash != prev_data,
+                None => true,
+                _ => false,
+            },
+            Large(path, _, hash) => match reg.get(path) {
+                Some(File(prev_data, _)) => hash != prev_data,
+                None => true,
+                _ => false,
+            },
             NewDir(path) => match reg.get(path) {
                 Some(Dir) => false,
                 _ => true,
@@ -121,16 +150,18 @@ pub fn load_fs(root: &Path, state: &SharedState) -> Result<Vec<FsDiff>> {
         if entry.metadata()?.is_dir() {
             list.push(FsDiff::NewDir(stripped_path));
         } else {
-            let data = fs::read(&path).unwrap_or(Vec::new());
-            match FilePerm::get(path) {
-                Ok(perms) => {
-                    list.push(FsDiff::Write(stripped_path.clone(), Arc::new(data)));
+            match (
+                transfer::read(path, stripped_path.clone()),
+                FilePerm::get(path),
+            ) {
+                (Ok(diff), Ok(perms)) => {
+                    list.push(diff);
                     list.push(FsDiff::Chmod(stripped_path, perms));
                     if collabignore::is_ignore_file(path) {
                         state.ignore.lock().unwrap().ignore_file_modified(path)?;
                     }
                 }
-                Err(_) => (), // file may have been deleted or moved
+                _ => (), // file may have been deleted or moved
             }
         }
     }
@@ -206,14 +237,16 @@ pub fn watch_fs(root: &Path, state: &SharedState, send: mpsc::Sender<Msg>) -> Re
                     load_fs_and_send_parallel(root, state, &send, false);
                 }
                 if !ignore.is_ignored(&path) {
-                    let data = fs::read(&path).unwrap_or(Vec::new());
                     let relative_path = strip_prefix(&path, &root)?;
-                    match FilePerm::get(&path) {
-                        Ok(perms) => {
-                            diffs.push(FsDiff::Write(relative_path.clone(), Arc::new(data)));
+                    match (
+                        transfer::read(&path, relative_path.clone()),
+                        FilePerm::get(&path),
+                    ) {
+                        (Ok(diff), Ok(perms)) => {
+                            diffs.push(diff);
                             diffs.push(FsDiff::Chmod(relative_path, perms));
                         }
-                        Err(_) => (), // file may have been deleted or moved
+                        _ => (), // file may have been deleted or moved
                     }
                 }
             }
@@ -256,14 +289,16 @@ pub fn watch_fs(root: &Path, state: &SharedState, send: mpsc::Sender<Msg>) -> Re
                     diffs.push(FsDiff::Del(strip_prefix(&from, &root)?));
                 } else if !ignored_to {
                     // looks file is being created
-                    let data = fs::read(&to).unwrap_or(Vec::new());
                     let stripped_path = strip_prefix(&to, &root)?;
-                    match FilePerm::get(&to) {
-                        Ok(perms) => {
-                            diffs.push(FsDiff::Write(stripped_path.clone(), Arc::new(data)));
+                    match (
+                        transfer::read(&to, stripped_path.clone()),
+                        FilePerm::get(&to),
+                    ) {
+                        (Ok(diff), Ok(perms)) => {
+                            diffs.push(diff);
                             diffs.push(FsDiff::Chmod(stripped_path, perms));
                         }
-                        Err(_) => (), // file may have been deleted or moved
+                        _ => (), // file may have been deleted or moved
                     }
                 }
             }
@@ -1,4 +1,6 @@
 use crate::common::*;
+use crate::wire::Codec;
+use crate::{protocol, queue};
 use std::{
     env, fs, io, net,
     path::{Path, PathBuf},
@@ -7,8 +9,6 @@ use std::{
     thread,
 };
 
-const TCP_DELIM: u8 = b'\0';
-
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 struct TmpData {
     pub port: u16,
@@ -138,35 +138,56 @@ fn remove_data(key: &TmpKey) -> Result<()> {
 }
 
 #[context("unable to start ipc daemon thread")]
-fn daemon_thread(stream: net::TcpStream, sender: mpsc::Sender<Msg>) -> Result<()> {
-    use io::{BufRead, Write};
-
+fn daemon_thread(
+    stream: net::TcpStream,
+    sender: mpsc::Sender<Msg>,
+    policy: queue::Policy,
+) -> Result<()> {
     let addr = stream.peer_addr()?;
 
     let mut reader = io::BufReader::new(stream.try_clone()?);
+    let codec = match protocol::hello(
+        &mut reader,
+        &mut &stream,
+        protocol::IPC_VERSION,
+        protocol::IPC_CAPABILITIES,
+    ) {
+        Ok(capabilities) => Codec::negotiate(&capabilities),
+        Err(err) => {
+            eprintln!("Rejected ipc client {}: {:#}", addr, err);
+            return Ok(());
+        }
+    };
+
+    let socket = stream.try_clone()?;
     let mut writer = io::BufWriter::new(stream);
 
-    let (response_sender, response_receiver) = mpsc::channel();
+    let (response_sender, response_receiver) = queue::bounded(policy);
+    // the reader notices and detaches the client
+    response_sender.on_overflow(move || {
+        eprintln!("Too much queued for ipc client {}, disconnecting", addr);
+        let _ = socket.shutdown(net::Shutdown::Both);
+    });
 
     thread::spawn(move || -> Result<()> {
         loop {
-            let msg = response_receiver.recv()?;
+            let msg = match response_receiver.recv() {
+                Ok(msg) => msg,
+                Err(queue::RecvError::Overflowed) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
             match msg {
                 IpcClientResponse::LocalDisconnect => return Ok(()),
                 _ => (),
             };
-            let data = serde_json::to_vec(&msg)?;
-            writer.write(&data[..])?;
-            writer.write(&[TCP_DELIM])?;
-            writer.flush()?;
+            codec.write_frame(&mut writer, &codec.encode(&msg)?[..])?;
         }
     });
 
     loop {
-        let mut data = Vec::new();
-        match reader.read_until(TCP_DELIM, &mut data) {
-            Ok(0) => {
-                response_sender.send(IpcClientResponse::LocalDisconnect)?;
+        match codec.read_frame(&mut reader) {
+            Ok(None) => {
+                response_sender.push(IpcClientResponse::LocalDisconnect)?;
                 sender
                     .send(Msg {
                         body: MsgBody::IpcClient(IpcClientMsg::LocalDisconnect),
@@ -180,8 +201,19 @@ fn daemon_thread(stream: net::TcpStream, sender: mpsc::Sender<Msg>) -> Result<()
                     })?;
                 return Ok(());
             }
-            Ok(size) => {
-                let msg = serde_json::from_slice(&data[..size - 1])?;
+            Ok(Some(data)) => {
+                // let a newer client know that it asked for something we
+                // don't understand, rather than dropping the connection
+                let msg = match codec.decode(&data[..]) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        response_sender.send(IpcClientResponse::Error(format!(
+                            "Unsupported request: {:#}",
+                            err
+                        )));
+                        continue;
+                    }
+                };
                 sender
                     .send(Msg {
                         body: MsgBody::IpcClient(msg),
@@ -195,16 +227,16 @@ fn daemon_thread(stream: net::TcpStream, sender: mpsc::Sender<Msg>) -> Result<()
                     })?;
             }
             Err(err) => {
-                eprintln!("ipc error: {}", err);
-                response_sender.send(IpcClientResponse::LocalDisconnect)?;
-                return Err(err.into());
+                eprintln!("ipc error: {:#}", err);
+                response_sender.push(IpcClientResponse::LocalDisconnect)?;
+                return Err(err);
             }
         }
     }
 }
 
 #[context("unable to start ipc daemon: {}", root.display())]
-pub fn daemon(root: &Path, sender: mpsc::Sender<Msg>) -> Result<()> {
+pub fn daemon(root: &Path, sender: mpsc::Sender<Msg>, policy: queue::Policy) -> Result<()> {
     use std::convert::TryFrom;
 
     let key = get_key(&root)?;
@@ -226,7 +258,7 @@ pub fn daemon(root: &Path, sender: mpsc::Sender<Msg>) -> Result<()> {
             match stream {
                 Ok(stream) => {
                     let sender = sender.clone();
-                    thread::spawn(move || daemon_thread(stream, sender));
+                    thread::spawn(move || daemon_thread(stream, sender, policy));
                 }
                 Err(err) => eprintln!("Failed ipc connection: {}", err),
             }
@@ -234,9 +266,22 @@ pub fn daemon(root: &Path, sender: mpsc::Sender<Msg>) -> Result<()> {
     }
 }
 
+/// Directory for other files belonging to the session in the given
+/// directory, e.g. its TLS certificate. Removed when the daemon stops.
+#[context("unable to get session dir: {}", root.display())]
+pub fn session_dir(root: &Path) -> Result<PathBuf> {
+    let mut path = get_key(root)?.path.into_os_string();
+    path.push(".d");
+    return Ok(PathBuf::from(path));
+}
+
 #[context("unable to clean up ipc daemon: {}", root.display())]
 pub fn daemon_cleanup(root: &Path) -> Result<()> {
     let key = get_key(&root)?;
+    let dir = session_dir(root)?;
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
     return remove_data(&key);
 }
 
@@ -247,7 +292,6 @@ pub fn client(
     mpsc::Sender<IpcClientMsg>,
     mpsc::Receiver<IpcClientResponse>,
 )> {
-    use io::{BufRead, Write};
     use net::*;
 
     let key = match find_key(root)? {
@@ -258,8 +302,14 @@ pub fn client(
     let data = load_data(&key)?;
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), data.port);
     let stream = TcpStream::connect(&addr)?;
-
     let mut reader = io::BufReader::new(stream.try_clone()?);
+    let codec = Codec::negotiate(&protocol::hello(
+        &mut reader,
+        &mut &stream,
+        protocol::IPC_VERSION,
+        protocol::IPC_CAPABILITIES,
+    )?);
+
     let mut writer = io::BufWriter::new(stream);
 
     let (request_sender, request_receiver) = mpsc::channel();
@@ -268,29 +318,26 @@ pub fn client(
     thread::spawn(move || -> Result<()> {
         loop {
             let request = request_receiver.recv()?;
-            let data = serde_json::to_vec(&request)?;
-            writer.write(&data[..])?;
-            writer.write(&[TCP_DELIM])?;
-            writer.flush()?;
+            codec.write_frame(&mut writer, &codec.encode(&request)?[..])?;
         }
     });
 
     thread::spawn(move || -> Result<()> {
         loop {
-            let mut data = Vec::new();
-            match reader.read_until(TCP_DELIM, &mut data) {
-                Ok(0) => {
+            match codec.read_frame(&mut reader) {
+                Ok(None) => {
                     response_sender.send(IpcClientResponse::RemoteDisconnect)?;
                     return Ok(());
                 }
-                Ok(size) => {
-                    let response = serde_json::from_slice(&data[..size - 1])?;
-                    response_sender.send(response)?;
-                }
+                Ok(Some(data)) => match codec.decode(&data[..]) {
+                    Ok(response) => response_sender.send(response)?,
+                    // sent by a newer daemon; nothing we can do with it
+                    Err(err) => eprintln!("Ignoring response that could not be decoded: {:#}", err),
+                },
                 Err(err) => {
-                    eprintln!("ipc error: {}", err);
+                    eprintln!("ipc error: {:#}", err);
                     response_sender.send(IpcClientResponse::RemoteDisconnect)?;
-                    return Err(err.into());
+                    return Err(err);
                 }
             }
         }
@@ -310,6 +357,19 @@ pub fn client_send_stop(root: &Path) -> Result<()> {
     };
 }
 
+/// Sends a request to connect, disconnect, ban, unban or summon a peer (or
+/// to change the rate limits), waiting for the daemon to carry it out
+#[context("unable to send peer request: {}", root.display())]
+pub fn client_peer_request(root: &Path, request: IpcClientMsg) -> Result<()> {
+    let (request_sender, response_receiver) = client(root)?;
+    request_sender.send(request)?;
+    return match response_receiver.recv()? {
+        IpcClientResponse::Done => Ok(()),
+        IpcClientResponse::Error(err) => Err(CollabError::Error(err).into()),
+        _ => Err(CollabError::Error("Daemon sent bad response".to_string()).into()),
+    };
+}
+
 #[context("unable to set client info: {}", root.display())]
 pub fn client_get_info(root: &Path) -> Result<IpcClientInfo> {
     let (request_sender, response_receiver) = client(root)?;
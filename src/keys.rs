@@ -0,0 +1,285 @@
+use crate::common::*;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    env, fs,
+    io::Write,
+    net,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Each user has a keypair that identifies their daemons to peers,
+// independent of whatever address they happen to connect from. Keys of
+// peers are remembered in a known_peers file next to it, whichever side
+// made the connection, and trusted the first time they are seen, much like
+// ssh's known_hosts. A key the user has untrusted is refused until they
+// trust it again.
+//
+// Addresses change with every reconnect or NAT, so they never decide which
+// key a peer should have. The address a key was first seen at is only kept
+// to warn when a new key turns up there, in case someone is impersonating
+// the peer that used to be there.
+
+/// Directory holding per-user state: $COLLAB_CONFIG_DIR if set, otherwise
+/// $XDG_CONFIG_HOME/collab or ~/.config/collab.
+#[context("unable to find config dir")]
+pub fn config_dir() -> Result<PathBuf> {
+    match env::var_os("COLLAB_CONFIG_DIR") {
+        Some(dir) => return Ok(PathBuf::from(dir)),
+        None => (),
+    }
+    let base = match (env::var_os("XDG_CONFIG_HOME"), env::var_os("HOME")) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(home)) => Path::new(&home).join(".config"),
+        (None, None) => {
+            return Err(
+                CollabError::Error("Neither XDG_CONFIG_HOME nor HOME is set".to_string()).into(),
+            )
+        }
+    };
+    return Ok(base.join("collab"));
+}
+
+/// Parses a public key as printed by `collab info`
+#[context("invalid public key: {}", str)]
+pub fn parse_key(str: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = match from_hex(str).map(|bytes| bytes.try_into()) {
+        Some(Ok(bytes)) => bytes,
+        _ => {
+            return Err(CollabError::Error("Expected 64 hexadecimal characters".to_string()).into())
+        }
+    };
+    return Ok(VerifyingKey::from_bytes(&bytes)?);
+}
+
+pub fn format_key(key: &VerifyingKey) -> String {
+    return to_hex(key.as_bytes());
+}
+
+/// This user's keypair
+pub struct Keypair {
+    signing: SigningKey,
+}
+
+impl Keypair {
+    /// Loads the keypair from the config dir, generating one the first time.
+    #[context("unable to load keypair")]
+    pub fn load() -> Result<Self> {
+        let dir = config_dir()?;
+        let path = dir.join("identity");
+        if path.exists() {
+            let bytes: [u8; 32] =
+                match from_hex(fs::read_to_string(&path)?.trim()).map(|bytes| bytes.try_into()) {
+                    Some(Ok(bytes)) => bytes,
+                    _ => {
+                        return Err(CollabError::Error(format!(
+                            "Malformed key file: {}",
+                            path.display()
+                        ))
+                        .into())
+                    }
+                };
+            return Ok(Self {
+                signing: SigningKey::from_bytes(&bytes),
+            });
+        }
+
+        let signing = SigningKey::generate(&mut rand::rngs::OsRng);
+        fs::create_dir_all(&dir)?;
+        // only we may ever read the key
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        if cfg!(target_family = "unix") {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&path)?
+            .write_all(to_hex(signing.as_bytes()).as_bytes())?;
+        println!("Generated new keypair: {}", path.display());
+        return Ok(Self { signing });
+    }
+
+    pub fn public(&self) -> VerifyingKey {
+        return self.signing.verifying_key();
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        return self.signing.sign(message).to_bytes().to_vec();
+    }
+}
+
+/// Checks a signature made by the given key
+pub fn verify(key: &VerifyingKey, message: &[u8], signature: &[u8]) -> bool {
+    return match Signature::from_slice(signature) {
+        Ok(signature) => key.verify(message, &signature).is_ok(),
+        Err(_) => false,
+    };
+}
+
+/// Serializes access to the known_peers file between connection threads
+static KNOWN_PEERS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Checks the key of a peer against the known_peers file. The file is
+/// reread every time so that changes made with `collab trust` and
+/// `collab untrust` take effect without restarting.
+pub fn check_peer(key: &VerifyingKey, addr: &net::SocketAddr) -> Result<Trust> {
+    let _lock = KNOWN_PEERS_LOCK.lock().unwrap();
+    return KnownPeers::load()?.check(key, addr);
+}
+
+pub enum Trust {
+    /// The key is trusted
+    Known,
+    /// The key hasn't been seen before; it has now been trusted
+    New,
+    /// Like `New`, but the address was first seen with the key given here
+    Changed(String),
+    /// The user has untrusted the key
+    Untrusted,
+}
+
+#[derive(Clone, Debug)]
+struct KnownPeer {
+    trusted: bool,
+    /// Where the key was first seen, if anywhere
+    addr: Option<net::SocketAddr>,
+}
+
+/// Public keys of peers, and whether to trust them. Stored one per line as
+/// "KEY trusted|untrusted [ADDRESS]".
+pub struct KnownPeers {
+    path: PathBuf,
+    keys: BTreeMap<String, KnownPeer>,
+}
+
+impl KnownPeers {
+    #[context("unable to load known peers")]
+    pub fn load() -> Result<Self> {
+        let path = config_dir()?.join("known_peers");
+        let mut keys = BTreeMap::new();
+        if path.exists() {
+            for line in fs::read_to_string(&path)?.lines() {
+                let parsed = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                    [key, trusted] => Some((key, trusted, None)),
+                    [key, trusted, addr] => match addr.parse() {
+                        Ok(addr) => Some((key, trusted, Some(addr))),
+                        Err(_) => None,
+                    },
+                    [] => continue,
+                    _ => None,
+                };
+                match parsed {
+                    Some((key, "trusted", addr)) => {
+                        keys.insert(
+                            key.to_string(),
+                            KnownPeer {
+                                trusted: true,
+                                addr,
+                            },
+                        );
+                    }
+                    Some((key, "untrusted", addr)) => {
+                        keys.insert(
+                            key.to_string(),
+                            KnownPeer {
+                                trusted: false,
+                                addr,
+                            },
+                        );
+                    }
+                    _ => eprintln!("Ignoring malformed known peer: {}", line),
+                }
+            }
+        }
+        return Ok(Self { path, keys });
+    }
+
+    #[context("unable to save known peers")]
+    fn save(&self) -> Result<()> {
+        match self.path.parent() {
+            Some(dir) => fs::create_dir_all(dir)?,
+            None => (),
+        }
+        let data: String = self
+            .keys
+            .iter()
+            .map(|(key, known)| {
+                let trusted = match known.trusted {
+                    true => "trusted",
+                    false => "untrusted",
+                };
+                match known.addr {
+                    Some(addr) => format!("{} {} {}\n", key, trusted, addr),
+                    None => format!("{} {}\n", key, trusted),
+                }
+            })
+            .collect();
+        fs::write(&self.path, data)?;
+        return Ok(());
+    }
+
+    /// Checks the key of a peer, trusting it if it hasn't been seen before.
+    /// The address is where the peer is, or says it is.
+    #[context("unable to check key of {}", addr)]
+    pub fn check(&mut self, key: &VerifyingKey, addr: &net::SocketAddr) -> Result<Trust> {
+        let key = format_key(key);
+        match self.keys.get(&key) {
+            Some(known) if known.trusted => return Ok(Trust::Known),
+            Some(_) => return Ok(Trust::Untrusted),
+            None => (),
+        }
+        let before = self
+            .keys
+            .iter()
+            .find(|(_, known)| known.addr.as_ref() == Some(addr))
+            .map(|(key, _)| key.clone());
+        self.keys.insert(
+            key,
+            KnownPeer {
+                trusted: true,
+                addr: Some(*addr),
+            },
+        );
+        self.save()?;
+        return Ok(match before {
+            Some(before) => Trust::Changed(before),
+            None => Trust::New,
+        });
+    }
+
+    /// Trusts a key, whether or not it was untrusted before
+    #[context("unable to trust {}", format_key(key))]
+    pub fn trust(&mut self, key: &VerifyingKey) -> Result<()> {
+        let addr = self.keys.get(&format_key(key)).and_then(|known| known.addr);
+        self.keys.insert(
+            format_key(key),
+            KnownPeer {
+                trusted: true,
+                addr,
+            },
+        );
+        return self.save();
+    }
+
+    /// Refuses a key from now on, returning whether it was trusted before
+    #[context("unable to untrust {}", format_key(key))]
+    pub fn untrust(&mut self, key: &VerifyingKey) -> Result<bool> {
+        let known = self.keys.get(&format_key(key)).cloned();
+        let addr = known.as_ref().and_then(|known| known.addr);
+        self.keys.insert(
+            format_key(key),
+            KnownPeer {
+                trusted: false,
+                addr,
+            },
+        );
+        self.save()?;
+        return Ok(match known {
+            Some(known) => known.trusted,
+            None => false,
+        });
+    }
+}
@@ -1,14 +1,30 @@
 mod attach;
+mod auth;
 mod cli;
 mod collabignore;
 mod common;
+mod delta;
+mod discovery;
+mod encoding;
 mod fs_watcher;
 mod ipc;
+mod keys;
+mod mesh;
+mod protocol;
+mod queue;
+mod ratelimit;
+mod relay;
+mod resync;
+mod stdio;
+mod suggest;
 mod tcp;
+mod tls;
+mod transfer;
+mod wire;
 
 use crate::common::*;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs, net,
     path::PathBuf,
     process,
@@ -17,54 +33,208 @@ use std::{
     thread,
 };
 
+/// Records the address that a peer which connected to us can be reached at,
+/// returning the sender for the peer
+fn set_advertised(
+    peers: &mut Peers,
+    source: &NodeId,
+    advertised_addr: net::SocketAddr,
+    state: &SharedState,
+) -> queue::Sender<RemoteMsg> {
+    // update advertised address
+    let peer = peers.get_mut(source).unwrap();
+    peer.info.advertised_addr = advertised_addr;
+    peer.advertised = true;
+    let (sender, info) = (peer.sender.clone(), peer.info.clone());
+
+    state
+        .attached_clients
+        .lock()
+        .unwrap()
+        .broadcast(IpcClientResponse::PeerJoined(info));
+
+    return sender;
+}
+
 #[context("unable to send startup, source: {}, advertised: {}, root: {}",
-          source_addr, advertised_addr, root.display())]
+          source, advertised_addr, root.display())]
 fn send_startup(
-    source_addr: net::SocketAddr,
+    source: &NodeId,
     advertised_addr: net::SocketAddr,
     root: &PathBuf,
     state: &SharedState,
 ) -> Result<()> {
+    let diffs = fs_watcher::load_fs(&root, state)?;
+    // the same order as everywhere else, to avoid deadlocks
+    let mut register = state.register.lock().unwrap();
     let mut peers = state.peers.lock().unwrap();
-
-    let sender = peers[&source_addr].sender.clone();
-
-    // update advertised address
-    peers.insert(
-        source_addr,
-        Peer {
-            sender: sender.clone(),
-            info: PeerInfo { advertised_addr },
-        },
-    );
+    let sender = set_advertised(&mut peers, source, advertised_addr, state);
+    let capabilities = peers.get(source).unwrap().capabilities.clone();
 
     // inform new peer of other peers
-    for peer in peers.values() {
+    for (node_id, peer) in peers.iter() {
         // don't tell the new connection about itself!
-        if peer.info.advertised_addr != advertised_addr {
-            sender.send(RemoteMsg::AddPeer(peer.info.advertised_addr))?;
+        if peer.advertised && node_id != source {
+            sender.send_blocking(RemoteMsg::AddPeer(peer.info.clone()));
         }
     }
 
-    let diffs = fs_watcher::load_fs(&root, state)?;
-    let mut register = state.register.lock().unwrap();
+    for suggestion in state.suggestions.lock().unwrap().all() {
+        sender.send_blocking(RemoteMsg::Suggestion(suggestion.clone()));
+    }
+
     for diff in diffs {
         diff.register(&mut register)?;
-        sender.send(RemoteMsg::FsDiff(diff))?;
+        let diff = transfer::whole(diff, root, &capabilities);
+        sender.send_blocking(RemoteMsg::FsDiff(diff));
     }
 
     return Ok(());
 }
 
-#[context("unable to start server, connect: {:?}", connect)]
-fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
-    let state = SharedState {
-        register: Arc::new(Mutex::new(HashMap::new())),
-        peers: Arc::new(Mutex::new(HashMap::new())),
-        attached_clients: Arc::new(Mutex::new(AttachedClients::new())),
-        ignore: Arc::new(Mutex::new(collabignore::Ignore::new(&root))),
+/// Tells attached editors when the file they are attached to is deleted or
+/// moved, re-pointing them in the latter case.
+fn notify_attached_clients(diff: &FsDiff, state: &SharedState) {
+    let mut clients = state.attached_clients.lock().unwrap();
+    match diff {
+        FsDiff::Del(path) => {
+            state.suggestions.lock().unwrap().remove_under(path);
+            for client in clients.get_under(path) {
+                client
+                    .sender
+                    .send(IpcClientResponse::FileDeleted(client.info.path.clone()));
+            }
+        }
+        FsDiff::Move(from, to) => {
+            state.suggestions.lock().unwrap().move_path(from, to);
+            let moved = clients.get_under(from);
+            clients.move_path(from, to);
+            for client in moved {
+                let new_path = clients.get_addr(&client.info.addr).unwrap().info.path;
+                client.sender.send(IpcClientResponse::FileMoved(
+                    client.info.path.clone(),
+                    new_path,
+                ));
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Sends a message meant for everyone to every peer except the one it came
+/// from, passing along the id it came with if it has one
+#[context("unable to broadcast message")]
+fn broadcast(
+    msg: RemoteMsg,
+    id: &Option<mesh::MsgId>,
+    source: Option<&NodeId>,
+    state: &SharedState,
+) -> Result<()> {
+    let id = state.mesh.lock().unwrap().forward_id(id);
+    for (node_id, peer) in state.peers.lock().unwrap().iter() {
+        if Some(node_id) != source {
+            peer.sender
+                .send(RemoteMsg::Mesh(id.clone(), Box::new(msg.clone())));
+        }
+    }
+    return Ok(());
+}
+
+/// Shows a new suggestion to local editors and peers
+#[context("unable to propose suggestion: {}", suggestion.id)]
+fn propose_suggestion(suggestion: &suggest::Suggestion, state: &SharedState) -> Result<()> {
+    for client in state
+        .attached_clients
+        .lock()
+        .unwrap()
+        .get_path(&suggestion.path)
+    {
+        client
+            .sender
+            .send(IpcClientResponse::Suggestion(suggestion.clone()));
+    }
+    return broadcast(
+        RemoteMsg::Suggestion(suggestion.clone()),
+        &None,
+        None,
+        state,
+    );
+}
+
+/// Accepts or rejects a pending suggestion on behalf of an attached editor.
+/// Accepted suggestions are applied like any other buffer diff, including
+/// to the editor that accepted them.
+#[context("unable to resolve suggestion: {}", id)]
+fn resolve_suggestion(
+    id: &str,
+    accepted: bool,
+    sender: &queue::Sender<IpcClientResponse>,
+    addr: &net::SocketAddr,
+    state: &SharedState,
+) -> Result<()> {
+    let client = state.attached_clients.lock().unwrap().get_addr(addr);
+    match client {
+        Some(client) if client.info.role == AttachRole::Edit => (),
+        _ => {
+            sender.send(IpcClientResponse::Error(
+                "Only editing clients can resolve suggestions".to_string(),
+            ));
+            return Ok(());
+        }
+    }
+
+    let suggestion = state.suggestions.lock().unwrap().remove(id);
+    let suggestion = match suggestion {
+        Some(suggestion) => suggestion,
+        None => {
+            sender.send(IpcClientResponse::Error(format!(
+                "No such suggestion: {}",
+                id
+            )));
+            return Ok(());
+        }
     };
 
+    suggest::notify_resolved(state, &suggestion, accepted);
+    let path = suggestion.path;
+    let diff = suggestion.diff;
+
+    // peers need to drop the suggestion before the diff arrives, otherwise
+    // it would be anchored against itself
+    let resolved = RemoteMsg::SuggestionResolved {
+        id: id.to_string(),
+        accepted,
+    };
+    broadcast(resolved, &None, None, state)?;
+
+    if accepted {
+        suggest::anchor(state, &path, &diff);
+        for client in state.attached_clients.lock().unwrap().get_path(&path) {
+            client
+                .sender
+                .send(IpcClientResponse::BufferDiff(diff.clone()));
+        }
+        broadcast(RemoteMsg::BufferDiff(path, diff, None), &None, None, state)?;
+    }
+
+    return Ok(());
+}
+
+/// Node ids of the peers that a reference picks out, along with how we
+/// are connected to each
+fn matching_peers(peer: &PeerRef, state: &SharedState) -> Vec<(NodeId, Link)> {
+    return state
+        .peers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(node_id, target)| peer.matches(Some(node_id), &target.info.advertised_addr))
+        .map(|(node_id, target)| (node_id.clone(), target.link))
+        .collect();
+}
+
+#[context("unable to start server, config: {:?}", config)]
+fn server(root: PathBuf, config: StartConfig) -> Result<()> {
     if ipc::has_active_session(&root)? {
         return Err(CollabError::Error(
             "A session is already started in this directory!".to_string(),
@@ -72,9 +242,84 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
         .into());
     }
 
+    let tls = if config.tls {
+        Some(Arc::new(tls::Identity::generate(&ipc::session_dir(
+            &root,
+        )?)?))
+    } else {
+        None
+    };
+
+    let secret = match &config.secret {
+        Some(secret) => secret.clone(),
+        None => {
+            let secret = auth::generate_secret();
+            println!("Session secret: {}", secret);
+            secret
+        }
+    };
+
+    let keypair = keys::Keypair::load()?;
+    println!("Public key: {}", keys::format_key(&keypair.public()));
+
+    let (listener, local_addr) = tcp::bind(&config)?;
+    let listen_addr = listener.local_addr()?;
+
+    let node_id = uuid::Uuid::new_v4().to_string();
+    println!("Node id: {}", node_id);
+
+    let mut config = config.clone();
+    match &config.join {
+        Some(name) => {
+            // anyone on the network can announce a session, so the
+            // certificate to expect has to come from the user
+            if config.tls && config.fingerprint.is_none() {
+                return Err(CollabError::Error(
+                    "Joining a session over TLS takes --fingerprint, as printed when the session started"
+                        .to_string(),
+                )
+                .into());
+            }
+            println!("Looking for session {} on the local network...", name);
+            let found = discovery::find(name)?;
+            println!("Found session {} on {}", name, found.host);
+            config.connect = Some(found.addr.to_string());
+        }
+        None => (),
+    }
+
+    let state = SharedState {
+        register: Arc::new(Mutex::new(HashMap::new())),
+        peers: Arc::new(Mutex::new(HashMap::new())),
+        attached_clients: Arc::new(Mutex::new(AttachedClients::new())),
+        suggestions: Arc::new(Mutex::new(suggest::Suggestions::new())),
+        bans: Arc::new(Mutex::new(HashSet::new())),
+        pending_acks: Arc::new(Mutex::new(PendingAcks::new())),
+        reconnecting: Arc::new(Mutex::new(HashMap::new())),
+        snapshots: Arc::new(Mutex::new(HashMap::new())),
+        mesh: Arc::new(Mutex::new(mesh::Mesh::new(node_id.clone()))),
+        bases: Arc::new(Mutex::new(delta::Bases::new())),
+        transfers: Arc::new(Mutex::new(transfer::Transfers::new(&root)?)),
+        ignore: Arc::new(Mutex::new(collabignore::Ignore::new(&root))),
+        tls,
+        secret: Arc::new(secret),
+        keypair: Arc::new(keypair),
+        node_id,
+        advertised_addr: local_addr,
+        compression: config.compression,
+        heartbeat_timeout: config.heartbeat_timeout,
+        queue: config.queue,
+        limits: ratelimit::Limits::new(config.upload_limit, config.download_limit),
+    };
+
     {
-        let root = root.clone();
+        let (root, state) = (root.clone(), state.clone());
         ctrlc::set_handler(move || {
+            state
+                .attached_clients
+                .lock()
+                .unwrap()
+                .broadcast(IpcClientResponse::Shutdown);
             match ipc::daemon_cleanup(&root) {
                 Ok(()) => (),
                 Err(err) => {
@@ -93,12 +338,29 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
     let (msg_sender, msg_receiver) = mpsc::channel();
 
     {
+        // an editor that stops reading is dropped rather than waited for,
+        // since responses go out while the attached clients are locked
+        let policy = queue::Policy {
+            limit: state.queue.limit,
+            overflow: queue::Overflow::Disconnect,
+        };
         let (root, msg_sender) = (root.clone(), msg_sender.clone());
-        thread::spawn(move || ipc::daemon(&root, msg_sender));
+        thread::spawn(move || ipc::daemon(&root, msg_sender, policy));
+    }
+
+    tcp::tcp_listener(&state, &msg_sender, listener, &config)?;
+
+    match config.announce.as_ref().or(config.join.as_ref()) {
+        Some(name) => discovery::announce(name, &state)?,
+        None => (),
     }
 
-    let addr = tcp::tcp_listener(&state, &msg_sender, connect)?;
-    println!("Listening for connections on {}", addr);
+    match (config.relay, &config.session) {
+        (Some(relay_addr), Some(session)) => {
+            relay::join(relay_addr, session, &state, &msg_sender, false)?
+        }
+        _ => (),
+    }
 
     let _fs_watcher = {
         let (root, msg_sender, state) = (root.clone(), msg_sender.clone(), state.clone());
@@ -109,37 +371,201 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
         match msg_receiver.recv() {
             Ok(msg) => {
                 println!("msg: {:?}", msg); // for testing
-                match (msg.body, msg.source) {
+                let (body, id) = match msg.body {
+                    MsgBody::Remote(RemoteMsg::Mesh(id, body)) => {
+                        // already handled when it arrived some other way
+                        if state.mesh.lock().unwrap().seen(&id) {
+                            // the sender is still waiting for an ack
+                            match (&*body, &msg.source) {
+                                (
+                                    RemoteMsg::BufferDiff(_, _, Some(ack_id)),
+                                    MsgSource::Peer(source),
+                                ) => match state.peers.lock().unwrap().get(source) {
+                                    Some(peer) => peer.sender.send(RemoteMsg::BufferAck(*ack_id)),
+                                    None => (),
+                                },
+                                _ => (),
+                            }
+                            continue;
+                        }
+                        (MsgBody::Remote(*body), Some(id))
+                    }
+                    body => (body, None),
+                };
+                match (body, msg.source) {
                     (MsgBody::Remote(RemoteMsg::FsDiff(diff)), msg_source) => {
+                        // turn deltas back into whole files, using our copy
+                        // of the version they are based on, but keep the
+                        // delta to pass along
+                        let (diff, delta) = match (diff, &msg_source) {
+                            (FsDiff::Delta(path, delta), MsgSource::Peer(source)) => {
+                                match delta::resolve(&root, &path, &delta) {
+                                    Ok(diff) => (diff, Some(FsDiff::Delta(path, delta))),
+                                    Err(err) => {
+                                        eprintln!(
+                                            "Requesting all of {} from {}: {:#}",
+                                            path, source, err
+                                        );
+                                        match state.peers.lock().unwrap().get(source) {
+                                            Some(peer) => {
+                                                peer.sender.send(RemoteMsg::RequestFile(path))
+                                            }
+                                            None => (),
+                                        }
+                                        continue;
+                                    }
+                                }
+                            }
+                            (diff, _) => (diff, None),
+                        };
+
+                        // large files are fetched before anything else
+                        // happens with them (see transfer.rs)
+                        match (&diff, &msg_source) {
+                            (FsDiff::Large(path, size, hash), MsgSource::Peer(source)) => {
+                                match transfer::fetch(path, *size, *hash, source, &state) {
+                                    Ok(false) => (),
+                                    Ok(true) => continue,
+                                    Err(err) => {
+                                        eprintln!("{:#}", err);
+                                        continue;
+                                    }
+                                }
+                            }
+                            (FsDiff::Chmod(path, perm), MsgSource::Peer(_)) => {
+                                if state.transfers.lock().unwrap().defer_chmod(path, perm) {
+                                    continue;
+                                }
+                            }
+                            (diff, _) => state.transfers.lock().unwrap().cancel(diff),
+                        }
+
                         let mut register = state.register.lock().unwrap();
+                        let mut bases = state.bases.lock().unwrap();
                         let changes_register = diff.changes_register(&mut register);
 
+                        // peers that can take a delta get one against the
+                        // version they have now
+                        let delta = match (&diff, &msg_source, delta) {
+                            (FsDiff::Write(path, data), MsgSource::Inotify, _)
+                                if changes_register =>
+                            {
+                                match register.get(path) {
+                                    Some(FsReg::File(base, _)) => bases.delta(path, *base, data),
+                                    _ => None,
+                                }
+                            }
+                            (_, _, delta) => delta,
+                        };
+
                         if changes_register {
                             diff.register(&mut register)?;
-
+                            notify_attached_clients(&diff, &state);
                             match msg_source {
                                 MsgSource::Peer(_) => diff.apply(&root)?,
-                                MsgSource::Inotify => {
-                                    for peer in state.peers.lock().unwrap().values() {
-                                        peer.sender.send(RemoteMsg::FsDiff(diff.clone()))?;
+                                _ => (),
+                            }
+                        }
+
+                        // pass along anything we haven't seen, even if we
+                        // already had the change from elsewhere
+                        if changes_register || id.is_some() {
+                            let source = match &msg_source {
+                                MsgSource::Peer(source) => Some(source),
+                                _ => None,
+                            };
+                            let id = state.mesh.lock().unwrap().forward_id(&id);
+                            for (node_id, peer) in state.peers.lock().unwrap().iter() {
+                                if Some(node_id) == source {
+                                    continue;
+                                }
+                                let diff = match &delta {
+                                    Some(delta) if peer.capabilities.contains(protocol::DELTA) => {
+                                        delta
                                     }
+                                    _ => &diff,
+                                };
+                                let diff = transfer::whole(diff.clone(), &root, &peer.capabilities);
+                                let msg = RemoteMsg::FsDiff(diff);
+                                peer.sender.send(RemoteMsg::Mesh(id.clone(), Box::new(msg)));
+                            }
+                        }
+                        bases.record(&diff);
+                    }
+                    (MsgBody::Remote(RemoteMsg::RequestFile(path)), MsgSource::Peer(source)) => {
+                        // the peer couldn't apply a delta, so send it all
+                        match transfer::read(&path_join(&root, &path), path.clone()) {
+                            Ok(diff) => match state.peers.lock().unwrap().get(&source) {
+                                Some(peer) => {
+                                    let diff = transfer::whole(diff, &root, &peer.capabilities);
+                                    peer.sender.send(RemoteMsg::FsDiff(diff))
                                 }
-                                MsgSource::IpcClient(_, _) => (),
+                                None => (),
+                            },
+                            Err(err) => {
+                                eprintln!("Unable to send {} to {}: {:#}", path, source, err)
                             }
                         }
                     }
                     (
-                        MsgBody::IpcClient(IpcClientMsg::AttachRequest { path, desc }),
+                        MsgBody::Remote(RemoteMsg::FetchChunk { path, hash, offset }),
+                        MsgSource::Peer(source),
+                    ) => match transfer::send_chunk(&root, &path, hash, offset, &source, &state) {
+                        Ok(()) => (),
+                        Err(err) => eprintln!("{:#}", err),
+                    },
+                    (
+                        MsgBody::Remote(RemoteMsg::Chunk {
+                            path,
+                            hash,
+                            offset,
+                            data,
+                        }),
+                        MsgSource::Peer(source),
+                    ) => {
+                        match transfer::receive(
+                            &path,
+                            hash,
+                            offset,
+                            data,
+                            &source,
+                            &state,
+                            &msg_sender,
+                        ) {
+                            Ok(()) => (),
+                            Err(err) => eprintln!("{:#}", err),
+                        }
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::AttachRequest {
+                            path,
+                            desc,
+                            encoding,
+                            acks,
+                            role,
+                        }),
                         MsgSource::IpcClient(sender, addr),
                     ) => {
+                        // catch the new client up on pending suggestions
+                        for suggestion in state.suggestions.lock().unwrap().get_path(&path) {
+                            sender.send(IpcClientResponse::Suggestion(suggestion));
+                        }
+
                         // add the new client
                         state
                             .attached_clients
                             .lock()
                             .unwrap()
                             .add(AttachedIpcClient {
-                                info: AttachedIpcClientInfo { path, desc, addr },
+                                info: AttachedIpcClientInfo {
+                                    path,
+                                    desc,
+                                    addr,
+                                    encoding,
+                                    role,
+                                },
                                 sender,
+                                acks,
                             });
                     }
                     (
@@ -152,48 +578,366 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
                             Some(client) => clients.remove(&client),
                             None => (),
                         };
+                        state.pending_acks.lock().unwrap().remove_client(&addr);
                     }
                     (
-                        MsgBody::IpcClient(IpcClientMsg::BufferDiff(diff)),
-                        MsgSource::IpcClient(_, addr),
+                        MsgBody::IpcClient(IpcClientMsg::BufferDiff { seq, diff }),
+                        MsgSource::IpcClient(sender, addr),
                     ) => {
+                        let client = state.attached_clients.lock().unwrap().get_addr(&addr);
+                        let client = match client {
+                            Some(client) => client,
+                            None => {
+                                sender.send(IpcClientResponse::Error(
+                                    "Client must attach before sending buffer diffs".to_string(),
+                                ));
+                                continue;
+                            }
+                        };
+                        if diff.end().is_none() {
+                            sender.send(IpcClientResponse::Error(format!(
+                                "Buffer diff replacing {} characters at {} runs past the end of the buffer",
+                                diff.old_len, diff.pos
+                            )));
+                            continue;
+                        }
+                        let (path, acks) = (client.info.path.clone(), client.acks);
+                        if acks != AckLevel::None {
+                            sender.send(IpcClientResponse::Ack(seq));
+                        }
+                        if client.info.role == AttachRole::Suggest {
+                            let suggestion = state.suggestions.lock().unwrap().create(
+                                &state.node_id,
+                                path,
+                                client.info.desc,
+                                diff,
+                            );
+                            propose_suggestion(&suggestion, &state)?;
+                            continue;
+                        }
+                        suggest::anchor(&state, &path, &diff);
                         let clients = state.attached_clients.lock().unwrap();
-                        let path = clients.get_addr(&addr).unwrap().info.path;
                         for client in clients.get_path(&path) {
                             if addr != client.info.addr {
                                 client
                                     .sender
-                                    .send(IpcClientResponse::BufferDiff(diff.clone()))?;
+                                    .send(IpcClientResponse::BufferDiff(diff.clone()));
                             }
                         }
-                        let peers = state.peers.lock().unwrap();
-                        for peer in peers.values() {
-                            peer.sender
-                                .send(RemoteMsg::BufferDiff(path.clone(), diff.clone()))?;
-                        }
+                        let ack_id = if acks == AckLevel::Peers {
+                            // peers that can't acknowledge aren't waited on
+                            let waiting: HashSet<NodeId> = state
+                                .peers
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .filter(|(_, peer)| peer.capabilities.contains(protocol::ACKS))
+                                .map(|(node_id, _)| node_id.clone())
+                                .collect();
+                            if waiting.is_empty() {
+                                sender.send(IpcClientResponse::Synced(seq));
+                                None
+                            } else {
+                                Some(state.pending_acks.lock().unwrap().add(addr, seq, waiting))
+                            }
+                        } else {
+                            None
+                        };
+                        broadcast(
+                            RemoteMsg::BufferDiff(path, diff, ack_id),
+                            &None,
+                            None,
+                            &state,
+                        )?;
                     }
-                    (MsgBody::Remote(RemoteMsg::BufferDiff(path, diff)), MsgSource::Peer(peer)) => {
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::AcceptSuggestion(id)),
+                        MsgSource::IpcClient(sender, addr),
+                    ) => resolve_suggestion(&id, true, &sender, &addr, &state)?,
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::RejectSuggestion(id)),
+                        MsgSource::IpcClient(sender, addr),
+                    ) => resolve_suggestion(&id, false, &sender, &addr, &state)?,
+                    (
+                        MsgBody::Remote(RemoteMsg::BufferDiff(path, diff, ack_id)),
+                        MsgSource::Peer(peer),
+                    ) => {
+                        if diff.end().is_none() {
+                            eprintln!(
+                                "Dropping buffer diff for {} from {} that runs past the end of the buffer",
+                                path, peer
+                            );
+                            continue;
+                        }
+                        suggest::anchor(&state, &path, &diff);
                         let clients = state.attached_clients.lock().unwrap();
                         for client in clients.get_path(&path) {
                             client
                                 .sender
-                                .send(IpcClientResponse::BufferDiff(diff.clone()))?;
+                                .send(IpcClientResponse::BufferDiff(diff.clone()));
+                        }
+                        match (ack_id, state.peers.lock().unwrap().get(&peer)) {
+                            (Some(id), Some(peer)) => peer.sender.send(RemoteMsg::BufferAck(id)),
+                            _ => (),
                         }
+                        // only direct peers are waited on, so passing it
+                        // along drops the ack id
+                        let msg = RemoteMsg::BufferDiff(path, diff, None);
+                        broadcast(msg, &id, Some(&peer), &state)?;
                     }
-                    (MsgBody::Remote(RemoteMsg::AddPeer(addr)), _) => {
-                        tcp::add_peer(&addr, &state, &msg_sender, None)?
+                    (
+                        MsgBody::Remote(RemoteMsg::Suggestion(suggestion)),
+                        MsgSource::Peer(source),
+                    ) => {
+                        state.suggestions.lock().unwrap().add(suggestion.clone());
+                        let clients = state.attached_clients.lock().unwrap();
+                        for client in clients.get_path(&suggestion.path) {
+                            client
+                                .sender
+                                .send(IpcClientResponse::Suggestion(suggestion.clone()));
+                        }
+                        broadcast(
+                            RemoteMsg::Suggestion(suggestion),
+                            &id,
+                            Some(&source),
+                            &state,
+                        )?;
+                    }
+                    (
+                        MsgBody::Remote(RemoteMsg::SuggestionResolved {
+                            id: suggestion_id,
+                            accepted,
+                        }),
+                        MsgSource::Peer(source),
+                    ) => {
+                        let suggestion = state.suggestions.lock().unwrap().remove(&suggestion_id);
+                        match suggestion {
+                            Some(suggestion) => {
+                                suggest::notify_resolved(&state, &suggestion, accepted)
+                            }
+                            None => (),
+                        }
+                        let msg = RemoteMsg::SuggestionResolved {
+                            id: suggestion_id,
+                            accepted,
+                        };
+                        broadcast(msg, &id, Some(&source), &state)?;
+                    }
+                    (MsgBody::Remote(RemoteMsg::BufferAck(id)), MsgSource::Peer(peer)) => {
+                        let done = state.pending_acks.lock().unwrap().ack(id, &peer);
+                        match done {
+                            Some(pending) => {
+                                let clients = state.attached_clients.lock().unwrap();
+                                match clients.get_addr(&pending.client) {
+                                    Some(client) => {
+                                        client.sender.send(IpcClientResponse::Synced(pending.seq))
+                                    }
+                                    None => (),
+                                }
+                            }
+                            None => (),
+                        }
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::SummonRequest { peer, path, line }),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        let summon = RemoteMsg::Summon {
+                            path,
+                            line,
+                            from: state.node_id.clone(),
+                        };
+                        let peer = match peer {
+                            Some(peer) => peer,
+                            None => {
+                                // summoning everyone includes peers that we
+                                // aren't connected to directly
+                                broadcast(summon, &None, None, &state)?;
+                                response_sender.send(IpcClientResponse::Done);
+                                continue;
+                            }
+                        };
+                        match state.peers.lock().unwrap().get(&peer) {
+                            Some(target) => {
+                                target.sender.send(summon);
+                                response_sender.send(IpcClientResponse::Done);
+                            }
+                            None => {
+                                response_sender.send(IpcClientResponse::Error(format!(
+                                    "No such peer: {}",
+                                    peer
+                                )));
+                            }
+                        }
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::ConnectRequest { addr, fingerprint }),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        let startup = RemoteMsg::Startup(state.advertised_addr);
+                        let (state, msg_sender) = (state.clone(), msg_sender.clone());
+                        // resolving the host name and connecting may take a while
+                        thread::spawn(move || {
+                            let connected = tcp::dial_info(&addr, fingerprint).and_then(|info| {
+                                tcp::add_peer(&info, &state, &msg_sender, Some(startup))
+                            });
+                            match connected {
+                                Ok(()) => response_sender.send(IpcClientResponse::Done),
+                                Err(err) => response_sender
+                                    .send(IpcClientResponse::Error(format!("{:#}", err))),
+                            }
+                        });
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::DisconnectRequest(peer)),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        let targets = matching_peers(&peer, &state);
+                        if targets.is_empty() {
+                            response_sender
+                                .send(IpcClientResponse::Error(format!("No such peer: {}", peer)));
+                            continue;
+                        }
+                        // the relay would keep passing its messages along
+                        if targets.iter().any(|(_, link)| *link == Link::Relayed) {
+                            response_sender.send(IpcClientResponse::Error(format!(
+                                "{} is reached through a relay; ban it to disconnect it",
+                                peer
+                            )));
+                            continue;
+                        }
+                        for (node_id, _) in targets {
+                            println!("Disconnecting peer: {}", node_id);
+                            tcp::disconnect_peer(&state, &node_id, None)?;
+                        }
+                        response_sender.send(IpcClientResponse::Done);
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::BanRequest(peer)),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        println!("Banning {}", peer);
+                        state.bans.lock().unwrap().insert(peer.clone());
+                        for (node_id, _) in matching_peers(&peer, &state) {
+                            tcp::disconnect_peer(&state, &node_id, None)?;
+                        }
+                        response_sender.send(IpcClientResponse::Done);
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::UnbanRequest(peer)),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        if state.bans.lock().unwrap().remove(&peer) {
+                            println!("Unbanned {}", peer);
+                            response_sender.send(IpcClientResponse::Done);
+                        } else {
+                            response_sender
+                                .send(IpcClientResponse::Error(format!("Not banned: {}", peer)));
+                        }
+                    }
+                    (
+                        MsgBody::IpcClient(IpcClientMsg::RateLimitRequest { upload, download }),
+                        MsgSource::IpcClient(response_sender, _),
+                    ) => {
+                        // a limit of 0 is no limit
+                        match upload {
+                            Some(limit) => state
+                                .limits
+                                .upload
+                                .set(Some(limit).filter(|limit| *limit > 0)),
+                            None => (),
+                        }
+                        match download {
+                            Some(limit) => state
+                                .limits
+                                .download
+                                .set(Some(limit).filter(|limit| *limit > 0)),
+                            None => (),
+                        }
+                        println!(
+                            "Upload limit: {}, download limit: {}",
+                            format_limit(state.limits.upload.rate()),
+                            format_limit(state.limits.download.rate())
+                        );
+                        response_sender.send(IpcClientResponse::Done);
+                    }
+                    (
+                        MsgBody::Remote(RemoteMsg::Summon { path, line, from }),
+                        MsgSource::Peer(source),
+                    ) => {
+                        state.attached_clients.lock().unwrap().broadcast(
+                            IpcClientResponse::Summon {
+                                path: path.clone(),
+                                line,
+                                from: from.clone(),
+                            },
+                        );
+                        // a summons for one peer in particular isn't passed on
+                        match id {
+                            Some(_) => {
+                                let msg = RemoteMsg::Summon { path, line, from };
+                                broadcast(msg, &id, Some(&source), &state)?;
+                            }
+                            None => (),
+                        }
+                    }
+                    (MsgBody::Remote(RemoteMsg::AddPeer(info)), _) => {
+                        // e.g. a peer we reach through a relay
+                        let connected = match &info.node_id {
+                            Some(node_id) => {
+                                node_id == &state.node_id
+                                    || state.peers.lock().unwrap().contains_key(node_id)
+                            }
+                            None => false,
+                        };
+                        if connected {
+                            continue;
+                        }
+                        // connecting takes a few round trips, so don't hold
+                        // up the messages from existing peers
+                        let (state, msg_sender) = (state.clone(), msg_sender.clone());
+                        thread::spawn(move || {
+                            match tcp::add_peer(&info, &state, &msg_sender, None) {
+                                Ok(()) => (),
+                                Err(err) => eprintln!("Failed connection: {:#}", err),
+                            }
+                        });
                     }
                     (
                         MsgBody::Remote(RemoteMsg::Startup(advertised_addr)),
-                        MsgSource::Peer(source_addr),
+                        MsgSource::Peer(source),
                     ) => {
-                        send_startup(source_addr, advertised_addr, &root, &state)?;
+                        send_startup(&source, advertised_addr, &root, &state)?;
+                    }
+                    (
+                        MsgBody::Remote(RemoteMsg::Resync(advertised_addr, theirs)),
+                        MsgSource::Peer(source),
+                    ) => {
+                        // a peer we lost the connection to is back
+                        let sender = {
+                            let mut peers = state.peers.lock().unwrap();
+                            if !peers.contains_key(&source) {
+                                continue;
+                            }
+                            set_advertised(&mut peers, &source, advertised_addr, &state)
+                        };
+                        let register = state.register.lock().unwrap().clone();
+                        sender.send(RemoteMsg::ResyncReply(register));
+                        resync::resync(&root, &state, &source, &theirs, false)?;
+                    }
+                    (MsgBody::Remote(RemoteMsg::ResyncReply(theirs)), MsgSource::Peer(source)) => {
+                        resync::resync(&root, &state, &source, &theirs, true)?;
                     }
                     (
                         MsgBody::IpcClient(IpcClientMsg::ShutdownRequest),
                         MsgSource::IpcClient(_, _),
                     ) => {
                         println!("Shutting down daemon...");
+                        state
+                            .attached_clients
+                            .lock()
+                            .unwrap()
+                            .broadcast(IpcClientResponse::Shutdown);
                         return ipc::daemon_cleanup(&root);
                     }
                     (
@@ -205,20 +949,37 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
                             .lock()
                             .unwrap()
                             .values()
-                            .map(|peer| peer.info.clone())
+                            .map(|peer| PeerStatus {
+                                info: peer.info.clone(),
+                                unreachable: peer.unreachable,
+                                queued: peer.sender.queued(),
+                            })
                             .collect();
                         let attached_clients = state
                             .attached_clients
                             .lock()
                             .unwrap()
                             .all()
-                            .map(|client| client.info.clone())
+                            .map(|client| ClientStatus {
+                                info: client.info.clone(),
+                                queued: client.sender.queued(),
+                            })
                             .collect();
                         response_sender.send(IpcClientResponse::Info(IpcClientInfo {
-                            addr,
+                            node_id: state.node_id.clone(),
+                            addr: local_addr,
+                            listen_addr,
+                            key: keys::format_key(&state.keypair.public()),
+                            fingerprint: state
+                                .tls
+                                .as_ref()
+                                .map(|identity| identity.fingerprint.clone()),
                             peers,
                             attached_clients,
-                        }))?;
+                            bans: state.bans.lock().unwrap().iter().cloned().collect(),
+                            upload_limit: state.limits.upload.rate(),
+                            download_limit: state.limits.download.rate(),
+                        }));
                     }
                     _ => (),
                 };
@@ -228,21 +989,68 @@ fn server(root: PathBuf, connect: Option<net::SocketAddr>) -> Result<()> {
     }
 }
 
+/// Describes a rate limit for the user
+fn format_limit(limit: Option<u64>) -> String {
+    return match limit {
+        Some(limit) => format!("{} bytes/s", limit),
+        None => "none".to_string(),
+    };
+}
+
 fn handle_command(root: PathBuf, command: cli::CliCommand) -> Result<()> {
     use cli::CliCommand::*;
     match command {
-        Start { connect } => server(root, connect)?,
+        Start(config) => server(root, config)?,
+        Relay { listen } => relay::relay(listen)?,
         Stop => ipc::client_send_stop(&root)?,
         Info => {
             let info = ipc::client_get_info(&root)?;
             println!("Address: {}", info.addr);
+            println!("Node id: {}", info.node_id);
+            println!("Key: {}", info.key);
+            if let Some(fingerprint) = info.fingerprint {
+                println!("Fingerprint: {}", fingerprint);
+            }
             println!("Peers ({} total):", info.peers.len());
-            for peer in info.peers {
-                println!("  {}", peer.advertised_addr);
+            for PeerStatus {
+                info: peer,
+                unreachable,
+                queued,
+            } in info.peers
+            {
+                let mut details: Vec<String> = vec![peer.node_id, peer.key, peer.fingerprint]
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                if unreachable {
+                    details.push("unreachable".to_string());
+                }
+                details.push(format!("{} queued", queued));
+                println!("  {} ({})", peer.advertised_addr, details.join(", "));
             }
+            if !info.bans.is_empty() {
+                let bans: Vec<String> = info.bans.iter().map(PeerRef::to_string).collect();
+                println!("Banned: {}", bans.join(", "));
+            }
+            println!(
+                "Upload limit: {}, download limit: {}",
+                format_limit(info.upload_limit),
+                format_limit(info.download_limit)
+            );
             println!("Attached clients ({} total):", info.attached_clients.len());
-            for client in info.attached_clients {
-                println!("  {}: {}", client.desc, client.path.as_str());
+            for ClientStatus {
+                info: client,
+                queued,
+            } in info.attached_clients
+            {
+                println!(
+                    "  {}: {} ({}, {:?}, {} queued)",
+                    client.desc,
+                    client.path.as_str(),
+                    client.encoding,
+                    client.role,
+                    queued
+                );
             }
         }
         List => {
@@ -252,7 +1060,76 @@ fn handle_command(root: PathBuf, command: cli::CliCommand) -> Result<()> {
                 println!("{}", session_path.display());
             }
         }
-        Attach { file, desc, mode } => attach::attach(&root, &file, desc, mode)?,
+        Bridge => stdio::bridge(&root)?,
+        Discover => {
+            let sessions = discovery::discover()?;
+            println!("Sessions on the local network ({} total):", sessions.len());
+            for session in sessions {
+                println!(
+                    "{} on {} at {} ({} peers)",
+                    session.name, session.host, session.addr, session.peers
+                );
+            }
+        }
+        Summon { peer, file, line } => {
+            let path = strip_prefix(&file, &root)?;
+            ipc::client_peer_request(&root, IpcClientMsg::SummonRequest { peer, path, line })?
+        }
+        Trust { key } => {
+            let key = keys::parse_key(&key)?;
+            keys::KnownPeers::load()?.trust(&key)?;
+            println!("Trusted key {}", keys::format_key(&key));
+        }
+        Untrust { key } => {
+            let key = keys::parse_key(&key)?;
+            if keys::KnownPeers::load()?.untrust(&key)? {
+                println!("Untrusted key {}", keys::format_key(&key));
+            } else {
+                println!(
+                    "Untrusted key {}, which wasn't trusted before",
+                    keys::format_key(&key)
+                );
+            }
+        }
+        Connect { addr, fingerprint } => {
+            let request = IpcClientMsg::ConnectRequest {
+                addr: addr.clone(),
+                fingerprint,
+            };
+            ipc::client_peer_request(&root, request)?;
+            println!("Connected to {}", addr);
+        }
+        Disconnect { peer } => {
+            ipc::client_peer_request(&root, IpcClientMsg::DisconnectRequest(peer.clone()))?;
+            println!("Disconnected {}", peer);
+        }
+        Ban { peer } => {
+            ipc::client_peer_request(&root, IpcClientMsg::BanRequest(peer.clone()))?;
+            println!("Banned {}", peer);
+        }
+        Unban { peer } => {
+            ipc::client_peer_request(&root, IpcClientMsg::UnbanRequest(peer.clone()))?;
+            println!("Unbanned {}", peer);
+        }
+        Limit { upload, download } => {
+            if upload.is_some() || download.is_some() {
+                ipc::client_peer_request(
+                    &root,
+                    IpcClientMsg::RateLimitRequest { upload, download },
+                )?;
+            }
+            let info = ipc::client_get_info(&root)?;
+            println!("Upload limit: {}", format_limit(info.upload_limit));
+            println!("Download limit: {}", format_limit(info.download_limit));
+        }
+        Attach {
+            file,
+            desc,
+            mode,
+            encoding,
+            acks,
+            role,
+        } => attach::attach(&root, &file, desc, mode, encoding, acks, role)?,
     };
 
     return Ok(());
@@ -0,0 +1,88 @@
+use crate::common::NodeId;
+use std::collections::{BTreeSet, HashMap};
+
+// Peers don't have to be connected to every other peer: a change is passed
+// along by each peer that receives it, so it reaches everyone as long as
+// the peers are connected somehow (e.g. in a chain A-B-C). Every message
+// that is passed along is wrapped with an id made up of the node that sent
+// it first and a sequence number, and each peer remembers the ids it has
+// seen, so that it handles each message once and stops passing it along
+// when it comes back around.
+//
+// Only messages meant for everyone get ids. Messages between neighbours
+// (startup, acks, resyncs, etc.) are never passed along. A message without
+// an id that changes something, e.g. from a resync, gets a new id from the
+// peer that received it and is passed along from there.
+
+/// How many ids to remember for each node. Older ids are assumed to have
+/// been seen.
+const MAX_SEEN: usize = 4096;
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
+pub struct MsgId {
+    /// The node that sent the message first
+    pub origin: NodeId,
+    pub seq: u64,
+}
+
+#[derive(Debug)]
+struct Seen {
+    /// Everything below this has been seen
+    floor: u64,
+    seqs: BTreeSet<u64>,
+}
+
+#[derive(Debug)]
+pub struct Mesh {
+    node_id: NodeId,
+    next_seq: u64,
+    seen: HashMap<NodeId, Seen>,
+}
+
+impl Mesh {
+    pub fn new(node_id: NodeId) -> Self {
+        return Mesh {
+            node_id,
+            next_seq: 0,
+            seen: HashMap::new(),
+        };
+    }
+
+    /// Returns a new id for a message sent from this node
+    pub fn next_id(&mut self) -> MsgId {
+        let id = MsgId {
+            origin: self.node_id.clone(),
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        self.seen(&id);
+        return id;
+    }
+
+    /// Returns the id a message should be passed along with, which is the
+    /// id it came with if it has one
+    pub fn forward_id(&mut self, id: &Option<MsgId>) -> MsgId {
+        return match id {
+            Some(id) => id.clone(),
+            None => self.next_id(),
+        };
+    }
+
+    /// Records that a message has been seen, returning true if it had been
+    /// seen already
+    pub fn seen(&mut self, id: &MsgId) -> bool {
+        let seen = self.seen.entry(id.origin.clone()).or_insert_with(|| Seen {
+            floor: 0,
+            seqs: BTreeSet::new(),
+        });
+        if id.seq < seen.floor || !seen.seqs.insert(id.seq) {
+            return true;
+        }
+        while seen.seqs.len() > MAX_SEEN {
+            let oldest = *seen.seqs.iter().next().unwrap();
+            seen.seqs.remove(&oldest);
+            seen.floor = oldest + 1;
+        }
+        return false;
+    }
+}
@@ -0,0 +1,160 @@
+use crate::common::*;
+use crate::wire::Codec;
+use std::{collections::HashSet, io};
+
+// Every connection, whether between peers or between a client and the
+// daemon, starts with both sides sending a hello carrying the version of
+// the protocol they speak and the optional features they support. The
+// version only changes when messages change in a way that the other side
+// couldn't make sense of, so different versions refuse to talk to each
+// other. Everything else is a capability: a side only sends messages that
+// need a capability if the other side has it.
+//
+// The hello itself must stay readable by every version, so fields may be
+// added to it but never removed or changed.
+
+/// Version of the protocol spoken between peers
+pub const PEER_VERSION: u32 = 3;
+
+/// Version of the protocol spoken between clients and the daemon
+pub const IPC_VERSION: u32 = 7;
+
+/// Peer capability: suggestions from editors attached in suggesting mode
+pub const SUGGESTIONS: &str = "suggestions";
+/// Peer capability: summoning editors to a location
+pub const SUMMON: &str = "summon";
+/// Peer capability: acknowledging buffer diffs
+pub const ACKS: &str = "acks";
+/// Length-prefixed binary frames instead of JSON (see wire.rs)
+pub const BINARY: &str = "binary";
+/// Compressed binary frames and batches of messages (see wire.rs)
+pub const COMPRESSION: &str = "compression";
+/// Sending changes to files as deltas (see delta.rs)
+pub const DELTA: &str = "delta";
+/// Passing messages along to other peers (see mesh.rs)
+pub const MESH: &str = "mesh";
+/// Pinging to detect peers that have gone away
+pub const HEARTBEAT: &str = "heartbeat";
+/// Fetching large files in chunks (see transfer.rs)
+pub const CHUNKS: &str = "chunks";
+/// Offered only by relays (see relay.rs)
+pub const RELAY: &str = "relay";
+
+pub const PEER_CAPABILITIES: &[&str] = &[
+    SUGGESTIONS,
+    SUMMON,
+    ACKS,
+    BINARY,
+    COMPRESSION,
+    DELTA,
+    MESH,
+    HEARTBEAT,
+    CHUNKS,
+];
+pub const IPC_CAPABILITIES: &[&str] = &[BINARY];
+pub const RELAY_CAPABILITIES: &[&str] = &[BINARY, RELAY];
+
+/// The capabilities to offer peers. Compression can be turned off, e.g.
+/// when the network is fast and the CPU time isn't worth it.
+pub fn peer_capabilities(compression: bool) -> Vec<&'static str> {
+    return PEER_CAPABILITIES
+        .iter()
+        .cloned()
+        .filter(|cap| compression || *cap != COMPRESSION)
+        .collect();
+}
+
+pub type Capabilities = HashSet<String>;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Hello {
+    version: u32,
+    capabilities: Vec<String>,
+}
+
+/// Sends our hello and reads the other side's, returning the capabilities
+/// that both sides have. Fails if the other side speaks a different version.
+#[context("unable to exchange hello")]
+pub fn hello(
+    reader: &mut dyn io::BufRead,
+    writer: &mut dyn io::Write,
+    version: u32,
+    capabilities: &[&str],
+) -> Result<Capabilities> {
+    let ours = Hello {
+        version,
+        capabilities: capabilities.iter().map(|cap| cap.to_string()).collect(),
+    };
+    Codec::Json.write_frame(writer, &Codec::Json.encode(&ours)?)?;
+
+    let theirs: Hello = match Codec::Json.read_frame(reader)? {
+        Some(data) => match Codec::Json.decode(&data[..]) {
+            Ok(hello) => hello,
+            Err(_) => {
+                return Err(CollabError::Error(
+                    "Expected a hello; the other side is probably running a version of collab \
+                     from before protocol versions were introduced"
+                        .to_string(),
+                )
+                .into())
+            }
+        },
+        None => {
+            return Err(CollabError::Error("Connection closed before hello".to_string()).into())
+        }
+    };
+
+    if theirs.version != version {
+        return Err(CollabError::Error(format!(
+            "Protocol version mismatch: this side speaks version {}, the other side speaks \
+             version {}; both need to run compatible versions of collab",
+            version, theirs.version
+        ))
+        .into());
+    }
+
+    return Ok(theirs
+        .capabilities
+        .into_iter()
+        .filter(|cap| capabilities.contains(&cap.as_str()))
+        .collect());
+}
+
+impl RemoteMsg {
+    /// The capability a peer needs to be sent this message, if any
+    pub fn capability(&self) -> Option<&'static str> {
+        return match self {
+            RemoteMsg::Suggestion(_) | RemoteMsg::SuggestionResolved { .. } => Some(SUGGESTIONS),
+            RemoteMsg::Summon { .. } => Some(SUMMON),
+            RemoteMsg::BufferAck(_) => Some(ACKS),
+            RemoteMsg::Batch(_) => Some(COMPRESSION),
+            RemoteMsg::FsDiff(FsDiff::Delta(_, _)) | RemoteMsg::RequestFile(_) => Some(DELTA),
+            RemoteMsg::Ping | RemoteMsg::Pong => Some(HEARTBEAT),
+            RemoteMsg::FsDiff(FsDiff::Large(_, _, _))
+            | RemoteMsg::FetchChunk { .. }
+            | RemoteMsg::Chunk { .. } => Some(CHUNKS),
+            _ => None,
+        };
+    }
+
+    /// Roughly how many bytes the message takes up, counting only file
+    /// contents
+    pub fn size_hint(&self) -> usize {
+        return match self {
+            RemoteMsg::FsDiff(FsDiff::Write(_, data)) => data.len(),
+            RemoteMsg::FsDiff(FsDiff::Delta(_, delta)) => delta.size(),
+            RemoteMsg::Chunk { data, .. } => data.0.len(),
+            RemoteMsg::Mesh(_, msg) => msg.size_hint(),
+            _ => 0,
+        };
+    }
+
+    /// Whether the message carries file contents, which are held back to
+    /// stay under the rate limits (see ratelimit.rs)
+    pub fn bulk(&self) -> bool {
+        return match self {
+            RemoteMsg::Batch(msgs) => msgs.iter().any(RemoteMsg::bulk),
+            msg => msg.size_hint() > 0,
+        };
+    }
+}
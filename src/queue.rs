@@ -0,0 +1,507 @@
+use crate::common::*;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    time,
+};
+
+// Everything sent to a peer or an attached editor goes through a queue that
+// a thread per connection takes messages out of to write them. A peer on a
+// slow link can't keep up with a busy session, so the queues are bounded,
+// and what happens when one fills up is up to the user:
+//
+// - block: wait for the peer to catch up. This holds up the whole daemon,
+//   which is what makes the peer's link the limit for everyone.
+// - collapse: drop writes that a newer write to the same file makes
+//   unnecessary, and only wait if that doesn't free up any room. A write
+//   is kept if anything queued after it touches the same file, e.g. moves
+//   it, since the peer needs it to get there.
+// - disconnect: drop everything queued and the connection along with it.
+//   The files that the peer missed are remembered, and sent again once the
+//   peer reconnects and resyncs (see resync.rs).
+//
+// Attached editors always get the disconnect policy, since responses are
+// sent to them with the list of attached clients locked, and one editor
+// that stops reading mustn't hold up the rest of the daemon.
+//
+// What the peer missed includes what was already written when the
+// connection dropped but never arrived. Peers that answer pings are pinged
+// after each batch that changes files, and files count as received once the
+// answer comes back.
+//
+// Small control messages (pings, disconnects) skip the limit, since they
+// must not wait behind everything else. Sending a peer everything at once
+// when it connects or resyncs always waits for room instead, since dropping
+// any of it would only start things over.
+//
+// While bulk messages are held back by a rate limit (see ratelimit.rs),
+// the rest can be taken out of the queue ahead of them, as long as nothing
+// they skip changes the same files.
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Overflow {
+    Block,
+    Collapse,
+    Disconnect,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Policy {
+    /// Most messages to hold for a peer before overflowing
+    pub limit: usize,
+    pub overflow: Overflow,
+}
+
+/// Messages that can be queued
+pub trait Queued {
+    /// Whether this message makes an earlier one unnecessary, so that the
+    /// earlier one can be dropped to make room
+    fn supersedes(&self, _earlier: &Self) -> bool {
+        return false;
+    }
+
+    /// Files that this message changes, which need to be sent again if the
+    /// message is dropped
+    fn paths(&self) -> Vec<RelativePathBuf> {
+        return Vec::new();
+    }
+
+    /// Whether the message is bulk traffic, which other messages can skip
+    /// ahead of
+    fn bulk(&self) -> bool {
+        return false;
+    }
+}
+
+impl Queued for RemoteMsg {
+    fn supersedes(&self, earlier: &Self) -> bool {
+        return match (self, earlier) {
+            (RemoteMsg::Mesh(_, msg), earlier) => msg.supersedes(earlier),
+            (msg, RemoteMsg::Mesh(_, earlier)) => msg.supersedes(earlier),
+            (
+                RemoteMsg::FsDiff(FsDiff::Write(path, _)),
+                RemoteMsg::FsDiff(FsDiff::Write(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Write(path, _)),
+                RemoteMsg::FsDiff(FsDiff::Delta(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Write(path, _)),
+                RemoteMsg::FsDiff(FsDiff::Large(earlier, _, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Large(path, _, _)),
+                RemoteMsg::FsDiff(FsDiff::Write(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Large(path, _, _)),
+                RemoteMsg::FsDiff(FsDiff::Delta(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Large(path, _, _)),
+                RemoteMsg::FsDiff(FsDiff::Large(earlier, _, _)),
+            ) => path == earlier,
+            (
+                RemoteMsg::FsDiff(FsDiff::Del(path)),
+                RemoteMsg::FsDiff(FsDiff::Write(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Del(path)),
+                RemoteMsg::FsDiff(FsDiff::Delta(earlier, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Del(path)),
+                RemoteMsg::FsDiff(FsDiff::Large(earlier, _, _)),
+            )
+            | (
+                RemoteMsg::FsDiff(FsDiff::Del(path)),
+                RemoteMsg::FsDiff(FsDiff::Chmod(earlier, _)),
+            ) => earlier.starts_with(path),
+            _ => false,
+        };
+    }
+
+    fn paths(&self) -> Vec<RelativePathBuf> {
+        return match self {
+            RemoteMsg::Mesh(_, msg) => msg.paths(),
+            RemoteMsg::FsDiff(FsDiff::Move(from, to)) => vec![from.clone(), to.clone()],
+            RemoteMsg::FsDiff(FsDiff::Write(path, _))
+            | RemoteMsg::FsDiff(FsDiff::Delta(path, _))
+            | RemoteMsg::FsDiff(FsDiff::Large(path, _, _))
+            | RemoteMsg::FsDiff(FsDiff::Del(path))
+            | RemoteMsg::FsDiff(FsDiff::NewDir(path))
+            | RemoteMsg::FsDiff(FsDiff::Chmod(path, _))
+            | RemoteMsg::BufferDiff(path, _, _) => vec![path.clone()],
+            RemoteMsg::Batch(msgs) => msgs.iter().flat_map(|msg| msg.paths()).collect(),
+            _ => Vec::new(),
+        };
+    }
+
+    fn bulk(&self) -> bool {
+        return RemoteMsg::bulk(self);
+    }
+}
+
+impl Queued for IpcClientResponse {}
+
+#[derive(Debug)]
+pub enum RecvError {
+    /// Nothing will be sent anymore
+    Disconnected,
+    /// The queue overflowed with the disconnect policy, so the connection
+    /// should be dropped
+    Overflowed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            RecvError::Disconnected => write!(f, "queue disconnected"),
+            RecvError::Overflowed => write!(f, "queue overflowed"),
+        };
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[derive(Debug)]
+struct Inner<T> {
+    msgs: VecDeque<T>,
+    senders: usize,
+    receiving: bool,
+    overflowed: bool,
+    /// Files changed by messages that were dropped
+    missed: BTreeSet<RelativePathBuf>,
+    /// Files changed by messages taken out of the queue that may still be
+    /// being written
+    in_flight: BTreeSet<RelativePathBuf>,
+    /// Files changed by each batch that has been written but not yet
+    /// confirmed as received
+    unconfirmed: VecDeque<BTreeSet<RelativePathBuf>>,
+}
+
+struct Shared<T> {
+    inner: Mutex<Inner<T>>,
+    changed: Condvar,
+    policy: Policy,
+    on_overflow: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+pub struct Sender<T: Queued> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T: Queued> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn bounded<T: Queued>(policy: Policy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            msgs: VecDeque::new(),
+            senders: 1,
+            receiving: true,
+            overflowed: false,
+            missed: BTreeSet::new(),
+            in_flight: BTreeSet::new(),
+            unconfirmed: VecDeque::new(),
+        }),
+        changed: Condvar::new(),
+        policy,
+        on_overflow: Mutex::new(None),
+    });
+    return (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    );
+}
+
+/// Drops the queued messages that a new one supersedes, unless a message
+/// queued after them touches the same files
+fn collapse<T: Queued>(msgs: &mut VecDeque<T>, msg: &T) {
+    let mut touched: Vec<RelativePathBuf> = Vec::new();
+    for i in (0..msgs.len()).rev() {
+        let paths = msgs[i].paths();
+        let depended_on = paths.iter().any(|path| {
+            touched
+                .iter()
+                .any(|touched| path.starts_with(touched) || touched.starts_with(path))
+        });
+        if msg.supersedes(&msgs[i]) && !depended_on {
+            msgs.remove(i);
+        } else {
+            touched.extend(paths);
+        }
+    }
+}
+
+impl<T: Queued> Sender<T> {
+    /// Queues a message, following the overflow policy if the queue is full.
+    /// The connection can drop at any time, so a message that can't be sent
+    /// anymore counts as missed rather than failing.
+    pub fn send(&self, msg: T) {
+        self.queue(msg, self.shared.policy.overflow);
+    }
+
+    /// Queues a message, waiting for room if the queue is full whatever the
+    /// policy
+    pub fn send_blocking(&self, msg: T) {
+        self.queue(msg, Overflow::Block);
+    }
+
+    fn queue(&self, msg: T, overflow: Overflow) {
+        let limit = self.shared.policy.limit;
+        let mut inner = self.shared.inner.lock().unwrap();
+        loop {
+            if !inner.receiving || inner.overflowed {
+                inner.missed.extend(msg.paths());
+                return;
+            }
+            if inner.msgs.len() < limit {
+                break;
+            }
+            match overflow {
+                Overflow::Block => (),
+                Overflow::Collapse => {
+                    collapse(&mut inner.msgs, &msg);
+                    if inner.msgs.len() < limit {
+                        break;
+                    }
+                }
+                Overflow::Disconnect => {
+                    let Inner { msgs, missed, .. } = &mut *inner;
+                    for dropped in msgs.drain(..) {
+                        missed.extend(dropped.paths());
+                    }
+                    missed.extend(msg.paths());
+                    inner.overflowed = true;
+                    self.shared.changed.notify_all();
+                    drop(inner);
+                    match self.shared.on_overflow.lock().unwrap().take() {
+                        Some(on_overflow) => on_overflow(),
+                        None => (),
+                    }
+                    return;
+                }
+            }
+            inner = self.shared.changed.wait(inner).unwrap();
+        }
+        inner.msgs.push_back(msg);
+        self.shared.changed.notify_all();
+    }
+
+    /// Queues a message regardless of the limit
+    pub fn push(&self, msg: T) -> Result<(), mpsc::SendError<T>> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if !inner.receiving {
+            return Err(mpsc::SendError(msg));
+        }
+        inner.msgs.push_back(msg);
+        self.shared.changed.notify_all();
+        return Ok(());
+    }
+
+    /// Sets something to do as soon as the queue overflows with the
+    /// disconnect policy, e.g. closing a connection that is stuck writing
+    pub fn on_overflow<F: FnOnce() + Send + 'static>(&self, on_overflow: F) {
+        *self.shared.on_overflow.lock().unwrap() = Some(Box::new(on_overflow));
+    }
+
+    /// Whether both senders send to the same queue, i.e. the same
+    /// connection
+    pub fn same_queue(&self, other: &Sender<T>) -> bool {
+        return Arc::ptr_eq(&self.shared, &other.shared);
+    }
+
+    /// How many messages are waiting to be sent
+    pub fn queued(&self) -> usize {
+        return self.shared.inner.lock().unwrap().msgs.len();
+    }
+
+    /// Confirms that the oldest batch marked as sent has been received
+    pub fn confirm(&self) {
+        self.shared.inner.lock().unwrap().unconfirmed.pop_front();
+    }
+
+    /// Files changed by messages that were dropped, are still waiting or
+    /// haven't been confirmed, i.e. that the other side may not have
+    /// received
+    pub fn missed(&self) -> BTreeSet<RelativePathBuf> {
+        let inner = self.shared.inner.lock().unwrap();
+        let mut missed = inner.missed.clone();
+        missed.extend(inner.in_flight.iter().cloned());
+        missed.extend(inner.unconfirmed.iter().flatten().cloned());
+        missed.extend(inner.msgs.iter().flat_map(|msg| msg.paths()));
+        return missed;
+    }
+}
+
+impl<T: Queued> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f
+            .debug_struct("Sender")
+            .field("queued", &self.queued())
+            .finish();
+    }
+}
+
+impl<T: Queued> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.inner.lock().unwrap().senders += 1;
+        return Sender {
+            shared: self.shared.clone(),
+        };
+    }
+}
+
+impl<T: Queued> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().senders -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl<T: Queued> Receiver<T> {
+    /// Waits for the next message. Coming back for more means that the
+    /// messages taken before have been written.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if !inner.overflowed {
+            inner.in_flight.clear();
+        }
+        loop {
+            if inner.overflowed {
+                return Err(RecvError::Overflowed);
+            }
+            match inner.msgs.pop_front() {
+                Some(msg) => {
+                    inner.in_flight.extend(msg.paths());
+                    self.shared.changed.notify_all();
+                    return Ok(msg);
+                }
+                None if inner.senders == 0 => return Err(RecvError::Disconnected),
+                None => (),
+            }
+            inner = self.shared.changed.wait(inner).unwrap();
+        }
+    }
+
+    /// Returns the next message if there is one waiting
+    pub fn try_recv(&self) -> Option<T> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.overflowed {
+            return None;
+        }
+        let msg = inner.msgs.pop_front();
+        match &msg {
+            Some(msg) => {
+                inner.in_flight.extend(msg.paths());
+                self.shared.changed.notify_all();
+            }
+            None => (),
+        }
+        return msg;
+    }
+
+    /// Waits for the next message that isn't bulk, taking it ahead of
+    /// messages that don't change the same files. Returns None if there is
+    /// none by the timeout.
+    pub fn recv_urgent(&self, timeout: time::Duration) -> Result<Option<T>, RecvError> {
+        let deadline = time::Instant::now() + timeout;
+        let mut inner = self.shared.inner.lock().unwrap();
+        if !inner.overflowed {
+            inner.in_flight.clear();
+        }
+        loop {
+            if inner.overflowed {
+                return Err(RecvError::Overflowed);
+            }
+            match Self::take_urgent(&mut inner) {
+                Some(msg) => {
+                    self.shared.changed.notify_all();
+                    return Ok(Some(msg));
+                }
+                None if inner.senders == 0 && inner.msgs.is_empty() => {
+                    return Err(RecvError::Disconnected)
+                }
+                None => (),
+            }
+            let now = time::Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            inner = self
+                .shared
+                .changed
+                .wait_timeout(inner, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Returns the next message that isn't bulk if there is one waiting,
+    /// like `recv_urgent`
+    pub fn try_recv_urgent(&self) -> Option<T> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.overflowed {
+            return None;
+        }
+        let msg = Self::take_urgent(&mut inner);
+        match &msg {
+            Some(_) => self.shared.changed.notify_all(),
+            None => (),
+        }
+        return msg;
+    }
+
+    fn take_urgent(inner: &mut Inner<T>) -> Option<T> {
+        let mut skipped: Vec<RelativePathBuf> = Vec::new();
+        let mut found = None;
+        for (i, msg) in inner.msgs.iter().enumerate() {
+            let paths = msg.paths();
+            let depends = paths.iter().any(|path| {
+                skipped
+                    .iter()
+                    .any(|skipped| path.starts_with(skipped) || skipped.starts_with(path))
+            });
+            if !msg.bulk() && !depends {
+                found = Some(i);
+                break;
+            }
+            skipped.extend(paths);
+        }
+        return match found.and_then(|i| inner.msgs.remove(i)) {
+            Some(msg) => {
+                inner.in_flight.extend(msg.paths());
+                Some(msg)
+            }
+            None => None,
+        };
+    }
+
+    /// Whether any of the messages taken out of the queue change files
+    pub fn in_flight(&self) -> bool {
+        return !self.shared.inner.lock().unwrap().in_flight.is_empty();
+    }
+
+    /// Marks the messages taken out of the queue as sent, to be confirmed
+    /// with one call to `Sender::confirm`
+    pub fn sent(&self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let batch = std::mem::take(&mut inner.in_flight);
+        inner.unconfirmed.push_back(batch);
+    }
+}
+
+impl<T: Queued> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.receiving = false;
+        inner.msgs.clear();
+        self.shared.changed.notify_all();
+    }
+}
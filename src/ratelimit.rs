@@ -0,0 +1,150 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time,
+};
+
+// Traffic to and from peers can be limited to a number of bytes per second
+// in each direction, e.g. so that the initial sync of a big tree doesn't
+// take up a shared uplink. The limits are for the daemon as a whole rather
+// than per peer, and can be changed while it runs.
+//
+// Every byte written to or read from a peer connection counts, but only
+// bulk traffic (file contents, see `RemoteMsg::bulk`) is held back to stay
+// under the limits. Edits, acknowledgements and pings go out straight away,
+// and skip ahead of queued files that they don't depend on (see queue.rs).
+// Messages are written whole at the speed of the link, and the next bulk
+// message waits until the average is back under the limit. Holding back
+// reading slows the peer down too once the socket buffers fill up.
+
+/// How much traffic can build up while idle, in seconds' worth of the limit
+const BURST: f64 = 1.0;
+/// Longest pause before checking again, so that changed limits are noticed
+const MAX_PAUSE: time::Duration = time::Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, or None if unlimited
+    rate: Option<u64>,
+    /// Bytes that can go before reaching the limit, negative when over it
+    available: f64,
+    updated: time::Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = time::Instant::now();
+        match self.rate {
+            Some(rate) => {
+                let elapsed = now.duration_since(self.updated).as_secs_f64();
+                self.available = (self.available + elapsed * rate as f64).min(BURST * rate as f64);
+            }
+            None => self.available = 0.0,
+        }
+        self.updated = now;
+    }
+}
+
+/// Limits traffic in one direction
+#[derive(Debug)]
+pub struct Limiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl Limiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        return Limiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                available: 0.0,
+                updated: time::Instant::now(),
+            }),
+        };
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        return self.bucket.lock().unwrap().rate;
+    }
+
+    /// Changes the limit; traffic already over the old limit has to be
+    /// made up for at the new one
+    pub fn set(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = rate;
+        bucket.refill();
+    }
+
+    /// Counts bytes that have gone through
+    pub fn take(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        if bucket.rate.is_some() {
+            bucket.available -= bytes as f64;
+        }
+    }
+
+    /// How long bulk traffic should hold off before checking again, zero
+    /// if it can go ahead
+    pub fn pause(&self) -> time::Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        return match bucket.rate {
+            Some(rate) if bucket.available < 0.0 => {
+                time::Duration::from_secs_f64(-bucket.available / rate.max(1) as f64).min(MAX_PAUSE)
+            }
+            _ => time::Duration::ZERO,
+        };
+    }
+}
+
+/// The limits on traffic to and from peers
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub upload: Arc<Limiter>,
+    pub download: Arc<Limiter>,
+}
+
+impl Limits {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        return Limits {
+            upload: Arc::new(Limiter::new(upload)),
+            download: Arc::new(Limiter::new(download)),
+        };
+    }
+}
+
+/// Counts what goes through a stream against a limiter
+pub struct Metered<T> {
+    inner: T,
+    limiter: Arc<Limiter>,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, limiter: &Arc<Limiter>) -> Self {
+        return Metered {
+            inner,
+            limiter: limiter.clone(),
+        };
+    }
+}
+
+impl<T: io::Read> io::Read for Metered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.limiter.take(len);
+        return Ok(len);
+    }
+}
+
+impl<T: io::Write> io::Write for Metered<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.limiter.take(len);
+        return Ok(len);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
@@ -0,0 +1,846 @@
+use crate::common::*;
+use crate::wire::Codec;
+use crate::{auth, keys, protocol, queue, ratelimit, tcp, tls};
+use std::{
+    collections::HashMap,
+    io, mem, net,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread, time,
+};
+use subtle::ConstantTimeEq;
+
+// Peers that can't connect to each other directly (e.g. behind different
+// NATs) can meet at a relay instead: a process run somewhere both can
+// reach with `collab relay`, which has no root directory of its own. Each
+// daemon connects out to the relay and joins a session by name. The relay
+// passes messages between the members of a session and tells them when
+// members join and leave, in place of the AddPeer messages that peers send
+// each other.
+//
+// A daemon treats every other member of its session as a peer, so nothing
+// else needs to know about the relay. Messages are encoded by the members
+// and passed along by the relay as they are, so the relay doesn't need to
+// understand them. Members prove to the relay that they know the session
+// secret with a token derived from it (see auth.rs), so the relay never
+// learns the secret itself. The relay does see everything passed through
+// it, though.
+//
+// The token only keeps strangers out of the relay's list of members: it is
+// the same every time, so the relay or anyone watching the connection to
+// it can join with it too. Members therefore don't take the relay's word
+// for who the others are. Each pair of members runs the same
+// authentication through the relay as peers do over a direct connection,
+// and only then treats the other as a peer, with the node id and key it
+// proved. Until then, anything else it sends is held back, up to a limit,
+// and a member that takes too long to authenticate is dropped. The relay
+// can still tamper with what it passes along afterwards, since there is no
+// TLS between members.
+
+/// How long another member has to authenticate with us
+const AUTH_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// How many bytes of messages we hold back from a member that hasn't
+/// authenticated yet before dropping it
+const MAX_HELD: usize = 16 << 20;
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Member {
+    /// Identifies the member in the session
+    pub node_id: NodeId,
+    pub addr: net::SocketAddr,
+    pub key: String,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+enum RelayMsg {
+    /// Sent by a daemon when it connects
+    Join {
+        session: String,
+        token: String,
+        member: Member,
+    },
+    /// Reply to a join, listing the members already in the session
+    Members(Vec<Member>),
+    /// Reply to a join that the relay turned down
+    Refused(String),
+    Joined(Member),
+    Left(NodeId),
+    /// A message for another member, encoded with bincode
+    Send {
+        to: NodeId,
+        msg: Vec<u8>,
+    },
+    /// A message from another member, encoded with bincode
+    Deliver {
+        from: NodeId,
+        msg: Vec<u8>,
+    },
+}
+
+/// What members send each other through the relay, encoded with bincode
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+enum MemberMsg {
+    /// Part of the authentication, as it would be sent over a connection
+    Auth(Vec<u8>),
+    Remote(RemoteMsg),
+}
+
+/// Capabilities that members can share through the relay. Batching and
+/// heartbeats are about the connection itself, which here is to the relay.
+fn member_capabilities(state: &SharedState) -> Vec<String> {
+    return protocol::peer_capabilities(state.compression)
+        .into_iter()
+        .filter(|cap| ![protocol::BINARY, protocol::COMPRESSION, protocol::HEARTBEAT].contains(cap))
+        .map(String::from)
+        .collect();
+}
+
+#[context("unable to receive relay message")]
+fn recv(codec: Codec, reader: &mut dyn io::BufRead) -> Result<Option<RelayMsg>> {
+    return match codec.read_frame(reader)? {
+        Some(data) => Ok(Some(codec.decode(&data[..])?)),
+        None => Ok(None),
+    };
+}
+
+#[context("unable to send relay message")]
+fn send(codec: Codec, writer: &mut dyn io::Write, msg: &RelayMsg) -> Result<()> {
+    return codec.write_frame(writer, &codec.encode(msg)?[..]);
+}
+
+/// Exchanges hellos with the other end of a relay connection
+fn relay_hello(stream: &mut tls::PeerStream) -> Result<Codec> {
+    let capabilities = protocol::hello(
+        &mut stream.reader,
+        &mut stream.writer,
+        protocol::PEER_VERSION,
+        protocol::RELAY_CAPABILITIES,
+    )?;
+    if !capabilities.contains(protocol::RELAY) {
+        return Err(CollabError::Error(
+            "The other side is a collab daemon, not a relay".to_string(),
+        )
+        .into());
+    }
+    return Ok(Codec::negotiate(&capabilities));
+}
+
+struct Connection {
+    /// Tells connections apart when a member rejoins before the relay
+    /// notices that its old connection dropped
+    id: u64,
+    member: Member,
+    sender: mpsc::Sender<RelayMsg>,
+}
+
+struct Session {
+    token: String,
+    members: HashMap<NodeId, Connection>,
+}
+
+#[derive(Clone)]
+struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    next_id: Arc<Mutex<u64>>,
+}
+
+/// Handles a daemon connected to the relay until it disconnects
+#[context("unable to serve relay connection")]
+fn serve(socket: net::TcpStream, sessions: &Sessions) -> Result<()> {
+    let addr = socket.peer_addr()?;
+    let mut stream = tls::PeerStream::plain(socket)?;
+    let codec = relay_hello(&mut stream)?;
+    let mut reader = stream.reader;
+    let (name, token, member) = match recv(codec, &mut reader)? {
+        Some(RelayMsg::Join {
+            session,
+            token,
+            member,
+        }) => (session, token, member),
+        _ => return Err(CollabError::Error("Expected to be asked to join".to_string()).into()),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let writer = stream.writer;
+        thread::spawn(move || -> Result<()> {
+            let mut writer = io::BufWriter::new(writer);
+            for msg in receiver {
+                send(codec, &mut writer, &msg)?;
+            }
+            return Ok(());
+        });
+    }
+
+    let id = {
+        let mut next_id = sessions.next_id.lock().unwrap();
+        *next_id += 1;
+        *next_id
+    };
+    {
+        let mut sessions = sessions.sessions.lock().unwrap();
+        let session = sessions.entry(name.clone()).or_insert_with(|| Session {
+            token: token.clone(),
+            members: HashMap::new(),
+        });
+        // the token stands in for the secret, so don't give away how much
+        // of it matched
+        if !bool::from(session.token.as_bytes().ct_eq(token.as_bytes())) {
+            sender.send(RelayMsg::Refused(
+                "The session secret doesn't match the other members'".to_string(),
+            ))?;
+            return Ok(());
+        }
+        match session.members.get(&member.node_id) {
+            Some(existing) if existing.member.key != member.key => {
+                sender.send(RelayMsg::Refused(format!(
+                    "Another member of the session is using the node id {}",
+                    member.node_id
+                )))?;
+                return Ok(());
+            }
+            _ => (),
+        }
+
+        // a member that is rejoining replaces its old connection
+        let others: Vec<&Connection> = session
+            .members
+            .values()
+            .filter(|other| other.member.node_id != member.node_id)
+            .collect();
+        for other in &others {
+            let _ = other.sender.send(RelayMsg::Joined(member.clone()));
+        }
+        sender.send(RelayMsg::Members(
+            others.iter().map(|other| other.member.clone()).collect(),
+        ))?;
+        session.members.insert(
+            member.node_id.clone(),
+            Connection {
+                id,
+                member: member.clone(),
+                sender,
+            },
+        );
+    }
+    println!("{} joined session {} from {}", member.addr, name, addr);
+
+    loop {
+        match recv(codec, &mut reader) {
+            Ok(Some(RelayMsg::Send { to, msg })) => {
+                let sessions = sessions.sessions.lock().unwrap();
+                match sessions
+                    .get(&name)
+                    .and_then(|session| session.members.get(&to))
+                {
+                    Some(target) => {
+                        let _ = target.sender.send(RelayMsg::Deliver {
+                            from: member.node_id.clone(),
+                            msg,
+                        });
+                    }
+                    None => (),
+                }
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("Member {} disconnected: {:#}", member.addr, err);
+                break;
+            }
+        }
+    }
+
+    println!("{} left session {}", member.addr, name);
+    let mut sessions = sessions.sessions.lock().unwrap();
+    let empty = match sessions.get_mut(&name) {
+        Some(session) => {
+            match session.members.get(&member.node_id) {
+                Some(connection) if connection.id == id => {
+                    session.members.remove(&member.node_id);
+                    for other in session.members.values() {
+                        let _ = other.sender.send(RelayMsg::Left(member.node_id.clone()));
+                    }
+                }
+                _ => (),
+            }
+            session.members.is_empty()
+        }
+        None => false,
+    };
+    if empty {
+        sessions.remove(&name);
+    }
+    return Ok(());
+}
+
+/// Runs a relay, which daemons can join sessions through
+#[context("unable to run relay on {}", listen)]
+pub fn relay(listen: net::SocketAddr) -> Result<()> {
+    let listener = net::TcpListener::bind(listen)?;
+    println!("Relaying on {}", listener.local_addr()?);
+
+    let sessions = Sessions {
+        sessions: Arc::new(Mutex::new(HashMap::new())),
+        next_id: Arc::new(Mutex::new(0)),
+    };
+    for socket in listener.incoming() {
+        match socket {
+            Ok(socket) => {
+                let sessions = sessions.clone();
+                thread::spawn(move || match serve(socket, &sessions) {
+                    Ok(()) => (),
+                    Err(err) => eprintln!("Failed connection: {:#}", err),
+                });
+            }
+            Err(err) => eprintln!("Failed connection: {}", err),
+        }
+    }
+    return Ok(());
+}
+
+/// How far authentication with another member has got
+enum MemberState {
+    Authenticating {
+        /// Tells attempts apart when a member joins again while we are
+        /// still authenticating with it
+        id: u64,
+        /// Passes on what the member sends for the authentication
+        auth: mpsc::Sender<Vec<u8>>,
+        /// Anything else the member sends meanwhile
+        held: Vec<RemoteMsg>,
+        /// The encoded size of what is held
+        held_size: usize,
+    },
+    Authenticated,
+}
+
+/// Our connection to the relay, shared by everything that deals with the
+/// other members through it
+#[derive(Clone)]
+struct Relayed {
+    writer: Arc<Mutex<dyn io::Write + Send>>,
+    codec: Codec,
+    members: Arc<Mutex<HashMap<NodeId, MemberState>>>,
+    next_id: Arc<Mutex<u64>>,
+    diff_sender: mpsc::Sender<Msg>,
+    state: SharedState,
+}
+
+/// What to send members once they have authenticated, when joining
+enum Greeting {
+    /// They greet us
+    Nothing,
+    /// Ask for everything, unless another member has been asked already
+    Startup(Arc<AtomicBool>),
+    Resync,
+}
+
+/// Adds another member of the session that has authenticated as a peer,
+/// returning false if we are already connected to it or it is banned.
+/// Messages queued for it are sent through the relay. If too much is
+/// queued for it, the member is resynced with rather than disconnected,
+/// since the connection to the relay is shared with everyone else.
+fn add_member(member: &Member, relayed: &Relayed) -> bool {
+    let state = &relayed.state;
+    if tcp::banned(state, Some(&member.node_id), &member.addr) {
+        return false;
+    }
+    let ours = member_capabilities(state);
+    let capabilities: protocol::Capabilities = member
+        .capabilities
+        .iter()
+        .filter(|cap| ours.contains(cap))
+        .cloned()
+        .collect();
+    let info = PeerInfo {
+        advertised_addr: member.addr,
+        host: None,
+        command: None,
+        node_id: Some(member.node_id.clone()),
+        fingerprint: None,
+        key: Some(member.key.clone()),
+    };
+
+    let (sender, receiver) = queue::bounded(state.queue);
+    {
+        let mut peers = state.peers.lock().unwrap();
+        // no need to go through the relay for peers we have a connection to
+        if peers.contains_key(&member.node_id) {
+            return false;
+        }
+        peers.insert(
+            member.node_id.clone(),
+            Peer {
+                sender: sender.clone(),
+                info: info.clone(),
+                advertised: true,
+                link: Link::Relayed,
+                capabilities: capabilities.clone(),
+                unreachable: false,
+            },
+        );
+    }
+    println!("New peer through relay: {}", member.addr);
+    state
+        .attached_clients
+        .lock()
+        .unwrap()
+        .broadcast(IpcClientResponse::PeerJoined(info));
+
+    let (member, relayed) = (member.clone(), relayed.clone());
+    thread::spawn(move || -> Result<()> {
+        let (state, codec) = (&relayed.state, relayed.codec);
+        let to = member.node_id.clone();
+        loop {
+            // while over the upload limit, only what can't wait goes out
+            let pause = state.limits.upload.pause();
+            let next = if pause.is_zero() {
+                receiver.recv().map(Some)
+            } else {
+                receiver.recv_urgent(pause)
+            };
+            let msg = match next {
+                Ok(Some(msg)) => msg,
+                Ok(None) => continue,
+                Err(queue::RecvError::Overflowed) => {
+                    eprintln!("Too much queued for peer {}, resyncing", member.addr);
+                    tcp::disconnect_peer(state, &to, Some(&sender))?;
+                    if !add_member(&member, &relayed) {
+                        return Ok(());
+                    }
+                    let register = state.register.lock().unwrap().clone();
+                    match state.peers.lock().unwrap().get(&to) {
+                        Some(peer) => peer
+                            .sender
+                            .send(RemoteMsg::Resync(state.advertised_addr, register)),
+                        None => (),
+                    }
+                    return Ok(());
+                }
+                Err(queue::RecvError::Disconnected) => return Ok(()),
+            };
+            let msg = match msg {
+                RemoteMsg::LocalDisconnect => return Ok(()),
+                msg => match tcp::outgoing(msg, &capabilities) {
+                    Some(msg) => msg,
+                    None => continue,
+                },
+            };
+            let msg = RelayMsg::Send {
+                to: to.clone(),
+                msg: Codec::Binary.encode(&MemberMsg::Remote(msg))?,
+            };
+            send(codec, &mut *relayed.writer.lock().unwrap(), &msg)?;
+        }
+    });
+    return true;
+}
+
+/// Reads what another member sends us while authenticating, until the
+/// deadline
+struct AuthReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    deadline: time::Instant,
+}
+
+impl io::Read for AuthReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            let timeout = self
+                .deadline
+                .saturating_duration_since(time::Instant::now());
+            self.pending = match self.receiver.recv_timeout(timeout) {
+                Ok(data) => data,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out authenticating",
+                    ))
+                }
+                // the member left, or we lost the relay
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        return Ok(len);
+    }
+}
+
+/// Sends what we write while authenticating to another member, a message
+/// per flush
+struct AuthWriter {
+    to: NodeId,
+    relayed: Relayed,
+    pending: Vec<u8>,
+}
+
+impl io::Write for AuthWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let msg = MemberMsg::Auth(mem::take(&mut self.pending));
+        let relayed = &self.relayed;
+        return Codec::Binary
+            .encode(&msg)
+            .and_then(|msg| {
+                let msg = RelayMsg::Send {
+                    to: self.to.clone(),
+                    msg,
+                };
+                send(relayed.codec, &mut *relayed.writer.lock().unwrap(), &msg)
+            })
+            .map_err(|err| io::Error::other(format!("{:#}", err)));
+    }
+}
+
+/// Authenticates with another member of the session through the relay,
+/// then adds it as a peer and greets it. We initiate if we are the one
+/// joining.
+fn authenticate_member(member: Member, initiator: bool, greeting: Greeting, relayed: &Relayed) {
+    let (auth, receiver) = mpsc::channel();
+    let id = {
+        let mut next_id = relayed.next_id.lock().unwrap();
+        *next_id += 1;
+        *next_id
+    };
+    relayed.members.lock().unwrap().insert(
+        member.node_id.clone(),
+        MemberState::Authenticating {
+            id,
+            auth,
+            held: Vec::new(),
+            held_size: 0,
+        },
+    );
+
+    let relayed = relayed.clone();
+    thread::spawn(move || {
+        let state = &relayed.state;
+        let mut stream = tls::PeerStream {
+            reader: Box::new(io::BufReader::new(AuthReader {
+                receiver,
+                pending: Vec::new(),
+                deadline: time::Instant::now() + AUTH_TIMEOUT,
+            })),
+            writer: Box::new(AuthWriter {
+                to: member.node_id.clone(),
+                relayed: relayed.clone(),
+                pending: Vec::new(),
+            }),
+            close: Arc::new(|| ()),
+            fingerprint: None,
+            binding: None,
+        };
+        let identity = match auth::authenticate(&mut stream, state, initiator, None) {
+            // the relay delivers its messages by the node id it joined with
+            Ok(identity) if identity.node_id != member.node_id => Err(CollabError::Error(format!(
+                "Member {} authenticated as node {}",
+                member.node_id, identity.node_id
+            ))
+            .into()),
+            identity => identity,
+        };
+
+        let added = {
+            let mut members = relayed.members.lock().unwrap();
+            let held = match members.get_mut(&member.node_id) {
+                Some(MemberState::Authenticating {
+                    id: current, held, ..
+                }) if *current == id => mem::take(held),
+                // it left, or joined again, while we were authenticating
+                _ => return,
+            };
+            let identity = match identity {
+                Ok(identity) => identity,
+                Err(err) => {
+                    members.remove(&member.node_id);
+                    eprintln!("Rejected member {}: {:#}", member.node_id, err);
+                    return;
+                }
+            };
+            members.insert(member.node_id.clone(), MemberState::Authenticated);
+            let member = Member {
+                node_id: identity.node_id,
+                addr: identity.addr,
+                key: identity.key,
+                capabilities: member.capabilities,
+            };
+            let added = add_member(&member, &relayed);
+            // still holding the lock, so that these go before anything
+            // else it sends
+            for msg in held {
+                let msg = Msg {
+                    body: MsgBody::Remote(msg),
+                    source: MsgSource::Peer(member.node_id.clone()),
+                };
+                match relayed.diff_sender.send(msg) {
+                    Ok(()) => (),
+                    Err(_) => return,
+                }
+            }
+            added
+        };
+        // members we have a direct connection to are already in sync
+        if !added {
+            return;
+        }
+
+        let msg = match greeting {
+            Greeting::Startup(asked) if !asked.swap(true, Ordering::SeqCst) => {
+                RemoteMsg::Startup(state.advertised_addr)
+            }
+            Greeting::Resync => {
+                let register = state.register.lock().unwrap().clone();
+                RemoteMsg::Resync(state.advertised_addr, register)
+            }
+            _ => return,
+        };
+        match state.peers.lock().unwrap().get(&member.node_id) {
+            Some(peer) => peer.sender.send(msg),
+            None => (),
+        }
+    });
+}
+
+/// Disconnects a member of the session that we reach through the relay,
+/// leaving it be if we have a direct connection to it
+fn disconnect_member(state: &SharedState, node_id: &NodeId) -> Result<bool> {
+    let connection = match state.peers.lock().unwrap().get(node_id) {
+        Some(peer) if peer.link == Link::Relayed => peer.sender.clone(),
+        _ => return Ok(false),
+    };
+    return tcp::disconnect_peer(state, node_id, Some(&connection));
+}
+
+/// Joins a session through a relay. Someone joining for the first time is
+/// sent everything by one of the members, like when connecting to a peer
+/// directly. Someone rejoining after losing the relay resyncs with everyone
+/// instead.
+#[context("unable to join session {} through relay {}", session, relay_addr)]
+pub fn join(
+    relay_addr: net::SocketAddr,
+    session: &str,
+    state: &SharedState,
+    diff_sender: &mpsc::Sender<Msg>,
+    rejoin: bool,
+) -> Result<()> {
+    let socket = net::TcpStream::connect(relay_addr)?;
+    let mut stream = tls::PeerStream::plain(socket)?;
+    let codec = relay_hello(&mut stream)?;
+    let join = RelayMsg::Join {
+        session: session.to_string(),
+        token: auth::session_token(&state.secret, session),
+        member: Member {
+            node_id: state.node_id.clone(),
+            addr: state.advertised_addr,
+            key: keys::format_key(&state.keypair.public()),
+            capabilities: member_capabilities(state),
+        },
+    };
+    send(codec, &mut stream.writer, &join)?;
+
+    let mut reader = io::BufReader::new(ratelimit::Metered::new(
+        stream.reader,
+        &state.limits.download,
+    ));
+    let members = match recv(codec, &mut reader)? {
+        Some(RelayMsg::Members(members)) => members,
+        Some(RelayMsg::Refused(reason)) => {
+            return Err(
+                CollabError::Error(format!("Relay refused to let us join: {}", reason)).into(),
+            )
+        }
+        _ => {
+            return Err(
+                CollabError::Error("Expected the members of the session".to_string()).into(),
+            )
+        }
+    };
+    println!(
+        "Joined session {} through relay {} ({} other members)",
+        session,
+        relay_addr,
+        members.len()
+    );
+
+    let writer = Arc::new(Mutex::new(io::BufWriter::new(ratelimit::Metered::new(
+        stream.writer,
+        &state.limits.upload,
+    ))));
+    let relayed = Relayed {
+        writer,
+        codec,
+        members: Arc::new(Mutex::new(HashMap::new())),
+        next_id: Arc::new(Mutex::new(0)),
+        diff_sender: diff_sender.clone(),
+        state: state.clone(),
+    };
+    let asked = Arc::new(AtomicBool::new(false));
+    for member in &members {
+        let greeting = if rejoin {
+            Greeting::Resync
+        } else {
+            Greeting::Startup(asked.clone())
+        };
+        authenticate_member(member.clone(), true, greeting, &relayed);
+    }
+
+    let (relay_addr, session, state, diff_sender) = (
+        relay_addr,
+        session.to_string(),
+        state.clone(),
+        diff_sender.clone(),
+    );
+    thread::spawn(move || {
+        let mut members: HashMap<NodeId, net::SocketAddr> = members
+            .iter()
+            .map(|member| (member.node_id.clone(), member.addr))
+            .collect();
+        loop {
+            match recv(codec, &mut reader) {
+                Ok(Some(RelayMsg::Deliver { from, msg })) => {
+                    match members.get(&from) {
+                        Some(addr) if tcp::banned(&state, Some(&from), addr) => continue,
+                        _ => (),
+                    }
+                    let size = msg.len();
+                    let msg = match Codec::Binary.decode(&msg[..]) {
+                        Ok(MemberMsg::Remote(msg)) => msg,
+                        Ok(MemberMsg::Auth(data)) => {
+                            match relayed.members.lock().unwrap().get(&from) {
+                                Some(MemberState::Authenticating { auth, .. }) => {
+                                    let _ = auth.send(data);
+                                }
+                                _ => (),
+                            }
+                            continue;
+                        }
+                        Err(err) => {
+                            eprintln!(
+                                "Ignoring message from {} that could not be decoded: {:#}",
+                                from, err
+                            );
+                            continue;
+                        }
+                    };
+                    // only listen to members that have authenticated
+                    let mut members = relayed.members.lock().unwrap();
+                    match members.get_mut(&from) {
+                        Some(MemberState::Authenticated) => (),
+                        Some(MemberState::Authenticating {
+                            held, held_size, ..
+                        }) => {
+                            *held_size += size;
+                            if *held_size > MAX_HELD {
+                                // ends the authentication too
+                                members.remove(&from);
+                                eprintln!(
+                                    "Dropped member {}: sent too much before authenticating",
+                                    from
+                                );
+                            } else {
+                                held.push(msg);
+                            }
+                            continue;
+                        }
+                        None => continue,
+                    }
+                    drop(members);
+                    let bulk = RemoteMsg::bulk(&msg);
+                    let msg = Msg {
+                        body: MsgBody::Remote(msg),
+                        source: MsgSource::Peer(from),
+                    };
+                    match diff_sender.send(msg) {
+                        Ok(()) => (),
+                        Err(_) => return,
+                    }
+                    // hold off reading more while over the download limit
+                    let mut pause = if bulk {
+                        state.limits.download.pause()
+                    } else {
+                        time::Duration::ZERO
+                    };
+                    while !pause.is_zero() {
+                        thread::sleep(pause);
+                        pause = state.limits.download.pause();
+                    }
+                }
+                Ok(Some(RelayMsg::Joined(member))) => {
+                    members.insert(member.node_id.clone(), member.addr);
+                    authenticate_member(member, false, Greeting::Nothing, &relayed);
+                }
+                Ok(Some(RelayMsg::Left(node_id))) => {
+                    if members.remove(&node_id).is_some() {
+                        relayed.members.lock().unwrap().remove(&node_id);
+                        println!("Peer left the relay: {}", node_id);
+                        let _ = disconnect_member(&state, &node_id);
+                    }
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    println!("Lost connection to relay {}", relay_addr);
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("Lost connection to relay {}: {:#}", relay_addr, err);
+                    break;
+                }
+            }
+        }
+
+        // ends any authentication still going on
+        relayed.members.lock().unwrap().clear();
+        for node_id in members.keys() {
+            let _ = disconnect_member(&state, node_id);
+        }
+        rejoin_relay(relay_addr, &session, &state, &diff_sender);
+    });
+
+    return Ok(());
+}
+
+/// Keeps trying to rejoin a session after losing the relay, backing off
+/// between attempts like when reconnecting to a peer
+fn rejoin_relay(
+    relay_addr: net::SocketAddr,
+    session: &str,
+    state: &SharedState,
+    diff_sender: &mpsc::Sender<Msg>,
+) {
+    let mut delay = tcp::RECONNECT_DELAY;
+    for attempt in 1..=tcp::MAX_RECONNECT_ATTEMPTS {
+        thread::sleep(delay);
+        delay = (delay * 2).min(tcp::MAX_RECONNECT_DELAY);
+        println!(
+            "Rejoining session {} through relay {} (attempt {} of {})...",
+            session,
+            relay_addr,
+            attempt,
+            tcp::MAX_RECONNECT_ATTEMPTS
+        );
+        match join(relay_addr, session, state, diff_sender, true) {
+            Ok(()) => return,
+            Err(err) => eprintln!("Failed connection: {:#}", err),
+        }
+    }
+    eprintln!(
+        "Giving up on relay {} after {} attempts",
+        relay_addr,
+        tcp::MAX_RECONNECT_ATTEMPTS
+    );
+}
@@ -0,0 +1,124 @@
+use crate::common::*;
+use crate::transfer;
+use std::{collections::BTreeSet, path::Path};
+
+// When a connection to a peer drops, the side that made the connection
+// keeps trying to reconnect (see tcp.rs). Rather than sending everything
+// again once it does, both sides exchange registers and only send the
+// files that changed while they were apart.
+//
+// Comparing registers only says which files differ, not which side has the
+// newer version. So each side also keeps its register as it was when the
+// connection dropped (a snapshot), which is what both sides last agreed on.
+// A side sends a file if it differs from the snapshot, i.e. it changed
+// locally. If both sides changed the same file, the side that reconnected
+// wins, so that the two don't end up swapping versions.
+//
+// Changes that were still queued for the peer when the connection dropped
+// (or that were dropped to make room, see queue.rs) never reached it, so
+// the snapshot also lists the files they were for. Those are compared
+// against the peer's register instead, as if the peer hadn't changed them.
+
+/// Where things stood with a peer when the connection to it dropped
+#[derive(Debug)]
+pub struct Snapshot {
+    pub register: Reg,
+    /// Files that changed here but may not have reached the peer
+    pub missed: BTreeSet<RelativePathBuf>,
+}
+
+/// Works out what to send a peer we have resynced with, given the snapshot
+/// taken when the connection dropped and both current registers
+pub fn diffs(
+    root: &Path,
+    base: &Snapshot,
+    ours: &Reg,
+    theirs: &Reg,
+    wins_conflicts: bool,
+) -> Vec<FsDiff> {
+    let paths: BTreeSet<&RelativePathBuf> = base
+        .register
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let mut dels = Vec::new();
+    let mut writes = Vec::new();
+    for path in paths {
+        let (ours, theirs) = (ours.get(path), theirs.get(path));
+        let base = if base.missed.contains(path) {
+            theirs
+        } else {
+            base.register.get(path)
+        };
+        if ours == theirs || ours == base || (theirs != base && !wins_conflicts) {
+            continue;
+        }
+        match ours {
+            Some(FsReg::File(_, perm)) => {
+                match transfer::read(&path_join(root, path), path.clone()) {
+                    Ok(diff) => {
+                        writes.push(diff);
+                        match perm {
+                            Some(perm) => writes.push(FsDiff::Chmod(path.clone(), perm.clone())),
+                            None => (),
+                        }
+                    }
+                    Err(_) => (), // file may have been deleted or moved
+                }
+            }
+            Some(FsReg::Dir) => writes.push(FsDiff::NewDir(path.clone())),
+            None => dels.push(FsDiff::Del(path.clone())),
+        }
+    }
+
+    // delete the contents of directories before the directories themselves
+    dels.reverse();
+    dels.extend(writes);
+    return dels;
+}
+
+/// Sends a peer we have reconnected with whatever changed on our side while
+/// the connection was down
+#[context("unable to resync with peer: {}", node_id)]
+pub fn resync(
+    root: &Path,
+    state: &SharedState,
+    node_id: &NodeId,
+    theirs: &Reg,
+    wins_conflicts: bool,
+) -> Result<()> {
+    let (sender, addr, capabilities) = match state.peers.lock().unwrap().get(node_id) {
+        Some(peer) => (
+            peer.sender.clone(),
+            peer.info.advertised_addr,
+            peer.capabilities.clone(),
+        ),
+        None => return Ok(()),
+    };
+    let ours = state.register.lock().unwrap().clone();
+    let snapshot = state.snapshots.lock().unwrap().remove(node_id);
+    // without a snapshot there is no telling what changed here, so leave it
+    // to the peer to send what it has
+    let base = match snapshot {
+        Some(snapshot) => snapshot,
+        None => Snapshot {
+            register: ours.clone(),
+            missed: BTreeSet::new(),
+        },
+    };
+
+    let diffs = diffs(root, &base, &ours, theirs, wins_conflicts);
+    println!("Resyncing with {}: sending {} changes", addr, diffs.len());
+    for diff in diffs {
+        sender.send_blocking(RemoteMsg::FsDiff(transfer::whole(
+            diff,
+            root,
+            &capabilities,
+        )));
+    }
+    // files the peer was sending us aren't in either register yet
+    transfer::resume(node_id, state)?;
+    return Ok(());
+}
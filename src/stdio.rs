@@ -0,0 +1,100 @@
+use crate::common::*;
+use crate::{ipc, tls};
+use std::{
+    io::{self, Read, Write},
+    net,
+    path::Path,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+// Peers usually connect over TCP, but the peer protocol works over any pair
+// of byte streams. With `collab start --connect-cmd COMMAND`, the daemon
+// runs COMMAND and talks to the peer over its stdin and stdout, which lets
+// it reach a daemon that isn't listening anywhere we can connect to, e.g.
+// `ssh devbox collab -r ~/project bridge`. `collab bridge` connects its own
+// stdin and stdout to the daemon running in its root, so that the daemon
+// sees an ordinary connection from its own machine.
+//
+// Nothing else changes: TLS and authentication run over the command's
+// streams as they would over a socket, and the command is run again each
+// time we reconnect.
+
+/// The command's process, which goes away along with the connection
+struct Child(Mutex<process::Child>);
+
+impl Child {
+    fn close(&self) {
+        let mut child = self.0.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Runs a command to reach a peer through, using its stdin and stdout as
+/// the connection. Its stderr is passed through, e.g. for ssh to report
+/// errors.
+#[context("unable to run {}", command)]
+pub fn spawn(command: &str) -> Result<tls::PeerStream> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    let (reader, writer) = match (child.stdout.take(), child.stdin.take()) {
+        (Some(reader), Some(writer)) => (reader, writer),
+        _ => return Err(CollabError::Error("Command has no stdio".to_string()).into()),
+    };
+    let child = Child(Mutex::new(child));
+    return Ok(tls::PeerStream {
+        reader: Box::new(io::BufReader::new(reader)),
+        writer: Box::new(writer),
+        close: Arc::new(move || child.close()),
+        fingerprint: None,
+        binding: None,
+    });
+}
+
+/// Connects stdin and stdout to the daemon running in the given root until
+/// either side closes the connection
+#[context("unable to bridge to daemon: {}", root.display())]
+pub fn bridge(root: &Path) -> Result<()> {
+    // the advertised address may be a forwarded port or another host
+    // entirely, so go straight to where the daemon listens
+    let listen_addr = ipc::client_get_info(root)?.listen_addr;
+    let ip: net::IpAddr = match listen_addr.ip() {
+        ip if !ip.is_unspecified() => ip,
+        net::IpAddr::V4(_) => net::Ipv4Addr::LOCALHOST.into(),
+        net::IpAddr::V6(_) => net::Ipv6Addr::LOCALHOST.into(),
+    };
+    let mut socket = net::TcpStream::connect((ip, listen_addr.port()))?;
+
+    {
+        let mut socket = socket.try_clone()?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut io::stdin().lock(), &mut socket);
+            let _ = socket.shutdown(net::Shutdown::Both);
+        });
+    }
+
+    // stdout is line buffered, which would hold back anything that doesn't
+    // end in a newline
+    let mut stdout = io::stdout().lock();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = socket.read(&mut buf[..])?;
+        if len == 0 {
+            return Ok(());
+        }
+        stdout.write_all(&buf[..len])?;
+        stdout.flush()?;
+    }
+}
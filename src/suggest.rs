@@ -0,0 +1,165 @@
+use crate::common::*;
+use std::{collections::HashMap, convert::TryFrom};
+
+// Editors attached with the suggesting role don't edit the shared buffer
+// directly. Their diffs are held here as pending suggestions, which are
+// shown to everyone attached to the file and kept anchored to the text as
+// other edits come in. An editor with editing rights can accept a
+// suggestion, at which point it becomes an ordinary buffer diff, or
+// reject it. Suggestions whose text is edited out from under them are
+// dropped as outdated.
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Suggestion {
+    pub id: String,
+    pub path: RelativePathBuf,
+    pub author: String,
+    pub diff: BufferDiff,
+}
+
+#[derive(Debug)]
+pub struct Suggestions {
+    next_id: u64,
+    by_id: HashMap<String, Suggestion>,
+}
+
+fn char_len(str: &str) -> u32 {
+    return str.chars().count() as u32;
+}
+
+impl Suggestions {
+    pub fn new() -> Self {
+        return Suggestions {
+            next_id: 0,
+            by_id: HashMap::new(),
+        };
+    }
+
+    /// Creates a new suggestion. Ids are prefixed with the node id of the
+    /// daemon that created them so that they are unique across peers.
+    pub fn create(
+        &mut self,
+        origin: &NodeId,
+        path: RelativePathBuf,
+        author: String,
+        diff: BufferDiff,
+    ) -> Suggestion {
+        let suggestion = Suggestion {
+            id: format!("{}/{}", origin, self.next_id),
+            path,
+            author,
+            diff,
+        };
+        self.next_id += 1;
+        self.add(suggestion.clone());
+        return suggestion;
+    }
+
+    pub fn add(&mut self, suggestion: Suggestion) {
+        self.by_id.insert(suggestion.id.clone(), suggestion);
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Suggestion> {
+        return self.by_id.remove(id);
+    }
+
+    pub fn get_path(&self, path: &RelativePath) -> Vec<Suggestion> {
+        return self
+            .by_id
+            .values()
+            .filter(|suggestion| suggestion.path == path)
+            .cloned()
+            .collect();
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Suggestion> {
+        return self.by_id.values();
+    }
+
+    /// Keeps suggestions anchored to the text after a diff is applied to
+    /// the buffer at the given path. Suggestions that overlap the edited
+    /// region no longer apply, so they are removed and returned.
+    pub fn anchor(&mut self, path: &RelativePath, diff: &BufferDiff) -> Vec<Suggestion> {
+        let (start, end) = match diff.end() {
+            Some(end) => (diff.pos, end),
+            // callers reject these, and no suggestion can be kept in place
+            // across an edit that doesn't fit in a buffer
+            None => (0, u32::MAX),
+        };
+        let delta = char_len(&diff.new_str) as i64 - diff.old_len as i64;
+
+        let mut outdated = Vec::new();
+        for suggestion in self.by_id.values_mut() {
+            if suggestion.path != path {
+                continue;
+            }
+            let (s_start, s_end) = match suggestion.diff.end() {
+                Some(s_end) => (suggestion.diff.pos, s_end),
+                None => {
+                    outdated.push(suggestion.id.clone());
+                    continue;
+                }
+            };
+            if end <= s_start {
+                // edit is entirely before the suggestion
+                match u32::try_from(s_start as i64 + delta) {
+                    Ok(pos) => suggestion.diff.pos = pos,
+                    Err(_) => outdated.push(suggestion.id.clone()),
+                }
+            } else if start >= s_end {
+                // edit is entirely after the suggestion
+            } else {
+                outdated.push(suggestion.id.clone());
+            }
+        }
+
+        return outdated
+            .iter()
+            .filter_map(|id| self.by_id.remove(id))
+            .collect();
+    }
+
+    /// Removes suggestions for a path that no longer exists
+    pub fn remove_under(&mut self, path: &RelativePath) -> Vec<Suggestion> {
+        let ids: Vec<String> = self
+            .by_id
+            .values()
+            .filter(|suggestion| suggestion.path.starts_with(path))
+            .map(|suggestion| suggestion.id.clone())
+            .collect();
+        return ids.iter().filter_map(|id| self.by_id.remove(id)).collect();
+    }
+
+    pub fn move_path(&mut self, from: &RelativePath, to: &RelativePath) {
+        for suggestion in self.by_id.values_mut() {
+            if suggestion.path.starts_with(from) {
+                let suffix = suggestion.path.strip_prefix(from).unwrap();
+                suggestion.path = if suffix.as_str().is_empty() {
+                    to.to_relative_path_buf()
+                } else {
+                    to.join(suffix)
+                };
+            }
+        }
+    }
+}
+
+/// Tells local editors that a suggestion is no longer pending
+pub fn notify_resolved(state: &SharedState, suggestion: &Suggestion, accepted: bool) {
+    let clients = state.attached_clients.lock().unwrap();
+    for client in clients.get_path(&suggestion.path) {
+        client.sender.send(IpcClientResponse::SuggestionResolved {
+            id: suggestion.id.clone(),
+            accepted,
+        });
+    }
+}
+
+/// Anchors pending suggestions after a diff has been applied to a buffer,
+/// notifying local editors of any that are now outdated
+pub fn anchor(state: &SharedState, path: &RelativePath, diff: &BufferDiff) {
+    let outdated = state.suggestions.lock().unwrap().anchor(path, diff);
+    for suggestion in outdated {
+        notify_resolved(state, &suggestion, false);
+    }
+}
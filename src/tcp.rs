@@ -1,134 +1,786 @@
 use crate::common::*;
-use std::{io, net, sync::mpsc, thread};
+use crate::wire::Codec;
+use crate::{auth, protocol, queue, ratelimit, resync, stdio, tls};
+use std::{
+    io, net,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread, time,
+};
 
-const TCP_DELIM: u8 = b'\0';
+/// Most messages to put in a single batch
+const MAX_BATCH: usize = 256;
+/// Stop adding to a batch once it holds about this many bytes of files
+const MAX_BATCH_SIZE: usize = 1 << 20;
 
-#[context("unable to disconnect peer: {}", addr)]
-fn disconnect_peer(state: &SharedState, addr: &net::SocketAddr) -> Result<()> {
-    let peer_opt = state.peers.lock().unwrap().remove(&addr);
+/// How long to wait before the first attempt to reconnect to a peer; each
+/// attempt after that waits twice as long as the last, up to the maximum
+pub const RECONNECT_DELAY: time::Duration = time::Duration::from_millis(500);
+pub const MAX_RECONNECT_DELAY: time::Duration = time::Duration::from_secs(30);
+/// Give up on a peer after this many failed attempts (a few minutes)
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 12;
+
+/// Adapts a message to what a peer supports, returning None if it can't be
+/// sent to the peer at all
+pub fn outgoing(msg: RemoteMsg, capabilities: &protocol::Capabilities) -> Option<RemoteMsg> {
+    let (id, msg) = match msg {
+        RemoteMsg::Mesh(id, msg) => (Some(id), *msg),
+        msg => (None, msg),
+    };
+    match msg.capability() {
+        Some(cap) if !capabilities.contains(cap) => return None,
+        _ => (),
+    }
+    let msg = match msg {
+        RemoteMsg::BufferDiff(path, diff, Some(_)) if !capabilities.contains(protocol::ACKS) => {
+            RemoteMsg::BufferDiff(path, diff, None)
+        }
+        msg => msg,
+    };
+    // peers that don't pass messages along have no use for ids
+    return Some(match id {
+        Some(id) if capabilities.contains(protocol::MESH) => RemoteMsg::Mesh(id, Box::new(msg)),
+        _ => msg,
+    });
+}
+
+#[context("unable to send messages")]
+fn send_batch(codec: Codec, writer: &mut dyn io::Write, mut batch: Vec<RemoteMsg>) -> Result<()> {
+    let msg = match batch.len() {
+        0 => return Ok(()),
+        1 => batch.remove(0),
+        _ => RemoteMsg::Batch(batch),
+    };
+    return codec.write_frame(writer, &codec.encode(&msg)?[..]);
+}
+
+/// Whether a peer with the given node id (if known) and address is banned
+pub fn banned(state: &SharedState, node_id: Option<&NodeId>, addr: &net::SocketAddr) -> bool {
+    return state
+        .bans
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|ban| ban.matches(node_id, addr));
+}
+
+/// Disconnects a peer, returning whether it was connected. If the
+/// connection is given, the peer is only disconnected if it is still
+/// connected that way, since it may have connected again another way in the
+/// meantime.
+#[context("unable to disconnect peer: {}", node_id)]
+pub fn disconnect_peer(
+    state: &SharedState,
+    node_id: &NodeId,
+    connection: Option<&queue::Sender<RemoteMsg>>,
+) -> Result<bool> {
+    // a change is registered and queued for peers under the register lock,
+    // so holding it here means that each change is either in the snapshot
+    // or missed by the peer
+    let register = state.register.lock().unwrap();
+    let peer_opt = {
+        let mut peers = state.peers.lock().unwrap();
+        match (peers.get(node_id), connection) {
+            (Some(peer), Some(connection)) if !peer.sender.same_queue(connection) => None,
+            _ => peers.remove(node_id),
+        }
+    };
     match peer_opt {
-        Some(peer) => peer.sender.send(RemoteMsg::LocalDisconnect)?,
+        Some(peer) => {
+            // remember where things stood, in case the peer comes back
+            let snapshot = resync::Snapshot {
+                register: register.clone(),
+                missed: peer.sender.missed(),
+            };
+            state
+                .snapshots
+                .lock()
+                .unwrap()
+                .insert(node_id.clone(), snapshot);
+            drop(register);
+            // fetching from the peer picks up again once it resyncs
+            state.transfers.lock().unwrap().stall(node_id);
+            let clients = state.attached_clients.lock().unwrap();
+            clients.broadcast(IpcClientResponse::PeerLeft(peer.info));
+            // the peer can no longer acknowledge anything
+            for pending in state.pending_acks.lock().unwrap().remove_peer(node_id) {
+                match clients.get_addr(&pending.client) {
+                    Some(client) => {
+                        client.sender.send(IpcClientResponse::Synced(pending.seq));
+                    }
+                    None => (),
+                }
+            }
+            // the writer has already stopped if the connection failed first
+            let _ = peer.sender.push(RemoteMsg::LocalDisconnect);
+            return Ok(true);
+        }
+        None => return Ok(false),
+    }
+}
+
+/// Keeps trying to connect to a peer that we lost the connection to, backing
+/// off between attempts. Stops once the peer has connected again, including
+/// if it was the one to reconnect, even if it has dropped again since.
+fn reconnect(info: PeerInfo, state: SharedState, diff_sender: mpsc::Sender<Msg>) {
+    let stop = Arc::new(AtomicBool::new(false));
+    match &info.node_id {
+        Some(node_id) => {
+            let mut reconnecting = state.reconnecting.lock().unwrap();
+            match reconnecting.insert(node_id.clone(), stop.clone()) {
+                Some(other) => other.store(true, Ordering::SeqCst),
+                None => (),
+            }
+        }
         None => (),
     }
-    return Ok(());
+
+    let mut delay = RECONNECT_DELAY;
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+
+        if stop.load(Ordering::SeqCst)
+            || banned(&state, info.node_id.as_ref(), &info.advertised_addr)
+        {
+            return;
+        }
+
+        println!(
+            "Reconnecting to {} (attempt {} of {})...",
+            info.advertised_addr, attempt, MAX_RECONNECT_ATTEMPTS
+        );
+        let register = state.register.lock().unwrap().clone();
+        let resync = RemoteMsg::Resync(state.advertised_addr, register);
+        match add_peer(&info, &state, &diff_sender, Some(resync)) {
+            Ok(()) => return,
+            Err(err) => eprintln!("Failed connection: {:#}", err),
+        }
+    }
+    eprintln!(
+        "Giving up on reconnecting to {} after {} attempts",
+        info.advertised_addr, MAX_RECONNECT_ATTEMPTS
+    );
 }
 
+/// Pings a peer regularly, marking it unreachable when it hasn't been heard
+/// from for the heartbeat timeout and reachable again once it has. Stops
+/// when the connection closes, at which point `last_seen` is None.
+fn heartbeat(
+    node_id: NodeId,
+    peer_sender: queue::Sender<RemoteMsg>,
+    last_seen: Arc<Mutex<Option<time::Instant>>>,
+    state: SharedState,
+) {
+    let timeout = state.heartbeat_timeout;
+    loop {
+        thread::sleep(timeout / 3);
+        let elapsed = match *last_seen.lock().unwrap() {
+            Some(last_seen) => last_seen.elapsed(),
+            None => return,
+        };
+        let unreachable = elapsed > timeout;
+        match state.peers.lock().unwrap().get_mut(&node_id) {
+            Some(peer)
+                if peer.sender.same_queue(&peer_sender) && peer.unreachable != unreachable =>
+            {
+                peer.unreachable = unreachable;
+                let addr = peer.info.advertised_addr;
+                if unreachable {
+                    println!(
+                        "Peer unreachable: {}, no answer for {}s",
+                        addr,
+                        elapsed.as_secs()
+                    );
+                } else {
+                    println!("Peer reachable again: {}", addr);
+                }
+            }
+            _ => (),
+        }
+        match peer_sender.push(RemoteMsg::Ping) {
+            Ok(()) => (),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Handles a connection to a peer until it drops, sending what is put in
+/// the peer's queue. If we were the one to connect, we are also the one to
+/// reconnect, so `reconnect` is given.
 #[context("unable to start tcp handler")]
 fn tcp_handler(
-    stream: net::TcpStream,
+    peer: (net::SocketAddr, NodeId),
+    stream: tls::PeerStream,
     sender: mpsc::Sender<Msg>,
-    receiver: mpsc::Receiver<RemoteMsg>,
+    queue: (queue::Sender<RemoteMsg>, queue::Receiver<RemoteMsg>),
+    capabilities: protocol::Capabilities,
+    reconnect: Option<PeerInfo>,
     state: &SharedState,
 ) -> Result<()> {
-    use io::{BufRead, Write};
+    let (addr, node_id) = peer;
+    println!("New peer connection: {} (node {})", addr, node_id);
 
-    let addr = stream.peer_addr()?;
-    println!("New peer connection: {}", addr);
+    let (peer_sender, receiver) = queue;
+
+    let codec = Codec::negotiate(&capabilities);
+
+    // anything from the peer shows that it is still there
+    let last_seen = Arc::new(Mutex::new(Some(time::Instant::now())));
+    if capabilities.contains(protocol::HEARTBEAT) {
+        let (node_id, peer_sender, last_seen, state) = (
+            node_id.clone(),
+            peer_sender.clone(),
+            last_seen.clone(),
+            state.clone(),
+        );
+        thread::spawn(move || heartbeat(node_id, peer_sender, last_seen, state));
+    }
 
     {
-        let stream = stream.try_clone()?;
+        // don't wait for a write that may be stuck to finish; the reader
+        // notices and disconnects the peer
+        let close = stream.close.clone();
+        peer_sender.on_overflow(move || {
+            eprintln!(
+                "Too much queued for peer {}, disconnecting to resync later",
+                addr
+            );
+            close();
+        });
+    }
+
+    {
+        let (stream, close) = (stream.writer, stream.close.clone());
+        let limits = state.limits.clone();
         thread::spawn(move || -> Result<()> {
-            let mut writer = io::BufWriter::new(stream);
+            let mut writer = io::BufWriter::new(ratelimit::Metered::new(stream, &limits.upload));
+            let batching = capabilities.contains(protocol::COMPRESSION);
+            // peers that answer pings can confirm that files arrived
+            let confirming = capabilities.contains(protocol::HEARTBEAT);
             loop {
-                let msg = receiver.recv()?;
-                match msg {
-                    RemoteMsg::LocalDisconnect => return Ok(()),
-                    _ => (),
+                // while over the upload limit, only what can't wait goes out
+                let pause = limits.upload.pause();
+                let held = !pause.is_zero();
+                // keep batches to about a second's worth of the limit
+                let max_batch_size = match limits.upload.rate() {
+                    Some(rate) => MAX_BATCH_SIZE.min(rate as usize),
+                    None => MAX_BATCH_SIZE,
+                };
+                // send along anything else that is already waiting
+                let mut batch = Vec::new();
+                let mut batch_size = 0;
+                let mut ping = false;
+                let next = if held {
+                    receiver.recv_urgent(pause)
+                } else {
+                    receiver.recv().map(Some)
+                };
+                let mut msg = match next {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => continue,
+                    Err(queue::RecvError::Overflowed) => return Ok(()),
+                    Err(err) => return Err(err.into()),
                 };
-                let data = serde_json::to_vec(&msg)?;
-                writer.write(&data[..])?;
-                writer.write(&[TCP_DELIM])?;
-                writer.flush()?;
+                loop {
+                    match msg {
+                        RemoteMsg::LocalDisconnect => {
+                            send_batch(codec, &mut writer, batch)?;
+                            // the peer may have connected again another
+                            // way, in which case this connection is still
+                            // open
+                            close();
+                            return Ok(());
+                        }
+                        // sent after the batch, see below
+                        RemoteMsg::Ping => ping = true,
+                        msg => match outgoing(msg, &capabilities) {
+                            Some(msg) => {
+                                batch_size += msg.size_hint();
+                                batch.push(msg);
+                            }
+                            None => (),
+                        },
+                    }
+                    if !batching || batch.len() >= MAX_BATCH || batch_size >= max_batch_size {
+                        break;
+                    }
+                    let next = if held {
+                        receiver.try_recv_urgent()
+                    } else {
+                        receiver.try_recv()
+                    };
+                    msg = match next {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+                }
+                send_batch(codec, &mut writer, batch)?;
+                // the answer confirms that everything before it arrived
+                if confirming && (ping || receiver.in_flight()) {
+                    send_batch(codec, &mut writer, vec![RemoteMsg::Ping])?;
+                    receiver.sent();
+                }
             }
         });
     }
 
-    let mut reader = io::BufReader::new(stream);
-    loop {
-        let mut data = Vec::new();
-        match reader.read_until(TCP_DELIM, &mut data) {
-            Ok(0) => {
+    let mut reader = io::BufReader::new(ratelimit::Metered::new(
+        stream.reader,
+        &state.limits.download,
+    ));
+    let disconnected = loop {
+        match codec.read_frame(&mut reader) {
+            Ok(None) => {
                 println!("Peer disconnected: {}", addr);
-                return disconnect_peer(state, &addr);
+                break disconnect_peer(state, &node_id, Some(&peer_sender));
             }
-            Ok(size) => {
-                let body = serde_json::from_slice(&data[..size - 1])?;
-                sender
-                    .send(Msg {
-                        body: MsgBody::Remote(body),
-                        source: MsgSource::Peer(addr),
-                    })
-                    .map_err(|err| {
-                        CollabError::Error(format!("Error sending received tcp message: {}", err))
-                    })?;
+            Ok(Some(data)) => {
+                *last_seen.lock().unwrap() = Some(time::Instant::now());
+                // a newer peer may send messages that we don't know about;
+                // skip them rather than dropping the connection
+                let body = match codec.decode(&data[..]) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        eprintln!(
+                            "Ignoring message from {} that could not be decoded: {:#}",
+                            addr, err
+                        );
+                        continue;
+                    }
+                };
+                let bulk = RemoteMsg::bulk(&body);
+                let msgs = match body {
+                    RemoteMsg::Batch(msgs) => msgs,
+                    body => vec![body],
+                };
+                for body in msgs {
+                    match body {
+                        RemoteMsg::Ping => {
+                            let _ = peer_sender.push(RemoteMsg::Pong);
+                            continue;
+                        }
+                        RemoteMsg::Pong => {
+                            peer_sender.confirm();
+                            continue;
+                        }
+                        _ => (),
+                    }
+                    sender
+                        .send(Msg {
+                            body: MsgBody::Remote(body),
+                            source: MsgSource::Peer(node_id.clone()),
+                        })
+                        .map_err(|err| {
+                            CollabError::Error(format!(
+                                "Error sending received tcp message: {}",
+                                err
+                            ))
+                        })?;
+                }
+                // hold off reading more while over the download limit; the
+                // peer hasn't gone quiet, we aren't listening
+                let mut pause = if bulk {
+                    state.limits.download.pause()
+                } else {
+                    time::Duration::ZERO
+                };
+                while !pause.is_zero() {
+                    thread::sleep(pause);
+                    *last_seen.lock().unwrap() = Some(time::Instant::now());
+                    pause = state.limits.download.pause();
+                }
             }
             Err(err) => {
-                eprintln!("Peer disconnected: {}, error: {}", addr, err);
-                return disconnect_peer(state, &addr);
+                eprintln!("Peer disconnected: {}, error: {:#}", addr, err);
+                break disconnect_peer(state, &node_id, Some(&peer_sender));
             }
         }
+    };
+    *last_seen.lock().unwrap() = None;
+
+    // if the peer was already disconnected, it was on purpose or it has
+    // connected again another way
+    match (reconnect, &disconnected) {
+        (Some(info), Ok(true)) => {
+            let (state, sender) = (state.clone(), sender.clone());
+            thread::spawn(move || self::reconnect(info, state, sender));
+        }
+        _ => (),
     }
+    return disconnected.map(|_| ());
 }
 
-#[context("unable to add tcp handler")]
+/// Whether a new connection to a peer we are already connected to should
+/// replace the existing one. A direct connection replaces one through a
+/// relay, and a peer connecting again the same way replaces its old
+/// connection, which may not have noticed yet that it dropped. If we both
+/// connected to each other at once, both sides keep the connection made by
+/// the lower node id.
+fn replaces(existing: &Peer, link: Link, node_id: &NodeId, state: &SharedState) -> bool {
+    return match (existing.link, link) {
+        (Link::Relayed, _) => true,
+        (existing, link) if existing == link => true,
+        (_, Link::Dialed) => &state.node_id < node_id,
+        _ => node_id < &state.node_id,
+    };
+}
+
+/// Starts handling a connection to an authenticated peer, returning false
+/// if we already have a better connection to it. `dialed` is what we
+/// connected to the peer with, or None if it connected to us.
+#[context("unable to add tcp handler: {}", addr)]
 fn add_tcp_handler(
     state: &SharedState,
-    stream: net::TcpStream,
+    addr: net::SocketAddr,
+    stream: tls::PeerStream,
     diff_sender: mpsc::Sender<Msg>,
-) -> Result<()> {
-    let (sender, receiver) = mpsc::channel();
-    let addr = stream.peer_addr()?;
+    identity: auth::PeerIdentity,
+    dialed: Option<&PeerInfo>,
+    capabilities: protocol::Capabilities,
+) -> Result<bool> {
+    let node_id = identity.node_id;
+    let link = if dialed.is_some() {
+        Link::Dialed
+    } else {
+        Link::Accepted
+    };
+    let existing = match state.peers.lock().unwrap().get(&node_id) {
+        Some(peer) if replaces(peer, link, &node_id, state) => Some(peer.sender.clone()),
+        Some(_) => {
+            println!("Already connected to {}, closing new connection", node_id);
+            return Ok(false);
+        }
+        None => None,
+    };
+    match existing {
+        Some(connection) => {
+            disconnect_peer(state, &node_id, Some(&connection))?;
+        }
+        None => (),
+    }
+
+    match state.reconnecting.lock().unwrap().remove(&node_id) {
+        Some(stop) => stop.store(true, Ordering::SeqCst),
+        None => (),
+    }
+
+    let (sender, receiver) = queue::bounded(state.queue);
+    let info = PeerInfo {
+        advertised_addr: addr,
+        host: dialed.and_then(|dialed| dialed.host.clone()),
+        command: dialed.and_then(|dialed| dialed.command.clone()),
+        node_id: Some(node_id.clone()),
+        fingerprint: stream.fingerprint.clone(),
+        key: Some(identity.key),
+    };
+    // we only know where to reconnect to if we made the connection
+    let reconnect = dialed.map(|_| info.clone());
     state.peers.lock().unwrap().insert(
-        addr,
+        node_id.clone(),
         Peer {
             sender: sender.clone(),
-            info: PeerInfo {
-                advertised_addr: addr,
-            },
+            info,
+            advertised: dialed.is_some(),
+            link,
+            capabilities: capabilities.clone(),
+            unreachable: false,
         },
     );
     let state = state.clone();
-    thread::spawn(move || tcp_handler(stream, diff_sender, receiver, &state));
+    thread::spawn(move || {
+        tcp_handler(
+            (addr, node_id),
+            stream,
+            diff_sender,
+            (sender, receiver),
+            capabilities,
+            reconnect,
+            &state,
+        )
+    });
+    return Ok(true);
+}
+
+/// Exchanges hellos with a peer, returning the capabilities we share
+fn peer_hello(stream: &mut tls::PeerStream, state: &SharedState) -> Result<protocol::Capabilities> {
+    return protocol::hello(
+        &mut stream.reader,
+        &mut stream.writer,
+        protocol::PEER_VERSION,
+        &protocol::peer_capabilities(state.compression)[..],
+    );
+}
+
+/// Sets up a newly accepted connection, performing the TLS handshake first
+/// if TLS is enabled. The peer has to speak our protocol version, prove it
+/// knows the session secret and have a trusted key before anything is sent
+/// to it or accepted from it.
+#[context("unable to accept peer")]
+fn accept_peer(
+    state: &SharedState,
+    socket: net::TcpStream,
+    diff_sender: mpsc::Sender<Msg>,
+) -> Result<()> {
+    let addr = socket.peer_addr()?;
+    if banned(state, None, &addr) {
+        eprintln!("Rejected peer {}: banned", addr);
+        return Ok(());
+    }
+    let mut stream = match &state.tls {
+        Some(identity) => identity.accept(tls::PeerStream::plain(socket)?)?,
+        None => tls::PeerStream::plain(socket)?,
+    };
+    let capabilities = match peer_hello(&mut stream, state) {
+        Ok(capabilities) => capabilities,
+        Err(err) => {
+            eprintln!("Rejected peer {}: {:#}", addr, err);
+            return Ok(());
+        }
+    };
+    let identity = match auth::authenticate(&mut stream, state, false, None) {
+        Ok(identity) => {
+            println!(
+                "Peer {} authenticated as {} (node {})",
+                addr, identity.addr, identity.node_id
+            );
+            identity
+        }
+        Err(err) => {
+            eprintln!("Rejected peer {}: {:#}", addr, err);
+            return Ok(());
+        }
+    };
+    add_tcp_handler(
+        state,
+        addr,
+        stream,
+        diff_sender,
+        identity,
+        None,
+        capabilities,
+    )?;
     return Ok(());
 }
 
-#[context("unable to add peer: {}", addr)]
+/// Resolves a host name or address with a port, e.g. `myhost.local:4000`
+/// or `[::1]:4000`, to all of its IPv4 and IPv6 addresses
+#[context("unable to resolve {}", host)]
+pub fn resolve(host: &str) -> Result<Vec<net::SocketAddr>> {
+    let addrs: Vec<net::SocketAddr> = net::ToSocketAddrs::to_socket_addrs(host)?.collect();
+    if addrs.is_empty() {
+        return Err(CollabError::Error(format!("No addresses found for {}", host)).into());
+    }
+    return Ok(addrs);
+}
+
+/// Describes a peer to connect to by host name or address. Addresses are
+/// used as they are; host names are kept to be resolved again on every
+/// connection, in case they point somewhere else by then.
+pub fn dial_info(host: &str, fingerprint: Option<String>) -> Result<PeerInfo> {
+    let (advertised_addr, host) = match host.parse() {
+        Ok(addr) => (addr, None),
+        Err(_) => (resolve(host)?[0], Some(host.to_string())),
+    };
+    return Ok(PeerInfo {
+        advertised_addr,
+        host,
+        command: None,
+        node_id: None,
+        fingerprint,
+        key: None,
+    });
+}
+
+/// Describes a peer to connect to through a command (see stdio.rs). Its
+/// address isn't known until it says what it is.
+pub fn command_info(command: &str, fingerprint: Option<String>) -> PeerInfo {
+    return PeerInfo {
+        advertised_addr: net::SocketAddr::from(([0, 0, 0, 0], 0)),
+        host: None,
+        command: Some(command.to_string()),
+        node_id: None,
+        fingerprint,
+        key: None,
+    };
+}
+
+/// Connects to the first of a peer's addresses that accepts the connection,
+/// resolving its host name again if it has one
+fn dial(info: &PeerInfo, state: &SharedState) -> Result<(net::TcpStream, net::SocketAddr)> {
+    let addrs = match &info.host {
+        Some(host) => resolve(host)?,
+        None => vec![info.advertised_addr],
+    };
+    let mut error = None;
+    for addr in addrs {
+        if banned(state, info.node_id.as_ref(), &addr) {
+            error = Some(CollabError::Error(format!("Peer at {} is banned", addr)).into());
+            continue;
+        }
+        match net::TcpStream::connect(addr) {
+            Ok(socket) => return Ok((socket, addr)),
+            Err(err) => error = Some(CollabError::Error(format!("{}: {}", addr, err)).into()),
+        }
+    }
+    return Err(error.unwrap());
+}
+
+/// Connects to a peer, sending it `first` before anything else. If TLS is
+/// enabled, the peer must present the certificate with the given
+/// fingerprint. If its key is given, the peer must have that key.
+#[context("unable to add peer: {}", info.target())]
 pub fn add_peer(
-    addr: &net::SocketAddr,
+    info: &PeerInfo,
     state: &SharedState,
     diff_send: &mpsc::Sender<Msg>,
-    startup: Option<net::SocketAddr>,
+    first: Option<RemoteMsg>,
 ) -> Result<()> {
-    let stream = net::TcpStream::connect(addr)?;
-    match startup {
-        Some(addr) => {
-            use io::Write;
-            let mut writer = io::BufWriter::new(stream.try_clone()?);
-            let data = serde_json::to_vec(&RemoteMsg::Startup(addr))?;
-            writer.write(&data[..])?;
-            writer.write(&[TCP_DELIM])?;
-            writer.flush()?;
+    // a peer reached through a command says which address it has
+    let (stream, dialed) = match &info.command {
+        Some(command) => (stdio::spawn(command)?, None),
+        None => {
+            let (socket, addr) = dial(info, state)?;
+            (tls::PeerStream::plain(socket)?, Some(addr))
+        }
+    };
+    let mut stream = match (&state.tls, &info.fingerprint) {
+        (Some(identity), Some(fingerprint)) => identity.connect(stream, fingerprint)?,
+        (Some(_), None) => {
+            return Err(CollabError::Error(format!(
+                "No certificate fingerprint known for {}; TLS requires one to connect",
+                info.target()
+            ))
+            .into())
+        }
+        (None, _) => stream,
+    };
+    let capabilities = peer_hello(&mut stream, state)?;
+    let identity = auth::authenticate(&mut stream, state, true, dialed.as_ref())?;
+    let addr = identity.addr;
+    match &info.key {
+        Some(key) if key != &identity.key => {
+            return Err(CollabError::Error(format!(
+                "Peer at {} has key {}, but was introduced with key {}",
+                addr, identity.key, key
+            ))
+            .into())
+        }
+        _ => (),
+    }
+    match first {
+        Some(msg) => {
+            let codec = Codec::negotiate(&capabilities);
+            let data = codec.encode(&msg)?;
+            codec.write_frame(&mut stream.writer, &data[..])?;
         }
         None => (),
     }
-    add_tcp_handler(state, stream, diff_send.clone())?;
+    let info = PeerInfo {
+        advertised_addr: addr,
+        host: info.host.clone(),
+        command: info.command.clone(),
+        node_id: Some(identity.node_id.clone()),
+        fingerprint: stream.fingerprint.clone(),
+        key: Some(identity.key.clone()),
+    };
+    let added = add_tcp_handler(
+        state,
+        addr,
+        stream,
+        diff_send.clone(),
+        identity,
+        Some(&info),
+        capabilities,
+    )?;
+    if added {
+        state
+            .attached_clients
+            .lock()
+            .unwrap()
+            .broadcast(IpcClientResponse::PeerJoined(info));
+    }
     return Ok(());
 }
 
-#[context("unable to start tcp listener")]
+/// Picks the address to advertise to peers. Listening on an unspecified
+/// address (0.0.0.0 or ::) isn't something peers can connect to, so in that
+/// case loopback is used unless an address is given explicitly.
+fn advertised_addr(
+    local_addr: net::SocketAddr,
+    advertise: Option<net::SocketAddr>,
+) -> net::SocketAddr {
+    return match advertise {
+        Some(addr) => addr,
+        None if local_addr.ip().is_unspecified() => {
+            let ip = match local_addr {
+                net::SocketAddr::V4(_) => net::IpAddr::V4(net::Ipv4Addr::LOCALHOST),
+                net::SocketAddr::V6(_) => net::IpAddr::V6(net::Ipv6Addr::LOCALHOST),
+            };
+            eprintln!(
+                "Listening on {} without --advertise; remote peers won't be told how to reach this instance",
+                local_addr
+            );
+            net::SocketAddr::new(ip, local_addr.port())
+        }
+        None => local_addr,
+    };
+}
+
+/// Binds the listener for peers, returning it along with the address to
+/// advertise
+#[context("unable to bind tcp listener: {:?}", config)]
+pub fn bind(config: &StartConfig) -> Result<(net::TcpListener, net::SocketAddr)> {
+    // port 0 picks an open port
+    let socket = net::TcpListener::bind(config.listen)?;
+    let local_addr = socket.local_addr()?;
+    let advertised_addr = advertised_addr(local_addr, config.advertise);
+
+    println!("Listening for connections on {}", local_addr);
+    println!("Advertising address {}", advertised_addr);
+
+    return Ok((socket, advertised_addr));
+}
+
+/// Starts accepting peers, connecting to the configured peer first
+#[context("unable to start tcp listener: {:?}", config)]
 pub fn tcp_listener(
     state: &SharedState,
     diff_sender: &mpsc::Sender<Msg>,
-    connect: Option<net::SocketAddr>,
-) -> Result<net::SocketAddr> {
-    // this picks an open port
-    let socket = net::TcpListener::bind("127.0.0.1:0")?;
-    let local_addr = socket.local_addr()?;
+    socket: net::TcpListener,
+    config: &StartConfig,
+) -> Result<()> {
+    match &state.tls {
+        Some(identity) => println!("TLS certificate fingerprint: {}", identity.fingerprint),
+        None => (),
+    }
 
-    match connect {
-        Some(addr) => {
-            println!("Attempting to connect to {}...", addr);
-            add_peer(&addr, state, &diff_sender, Some(local_addr))?
+    match &config.connect {
+        Some(host) => {
+            println!("Attempting to connect to {}...", host);
+            let info = dial_info(host, config.fingerprint.clone())?;
+            add_peer(
+                &info,
+                state,
+                &diff_sender,
+                Some(RemoteMsg::Startup(state.advertised_addr)),
+            )?
+        }
+        None => (),
+    }
+
+    match &config.connect_cmd {
+        Some(command) => {
+            println!("Attempting to connect through `{}`...", command);
+            let info = command_info(command, config.fingerprint.clone());
+            add_peer(
+                &info,
+                state,
+                &diff_sender,
+                Some(RemoteMsg::Startup(state.advertised_addr)),
+            )?
         }
         None => (),
     }
@@ -138,12 +790,19 @@ pub fn tcp_listener(
         loop {
             for stream in socket.incoming() {
                 match stream {
-                    Ok(stream) => add_tcp_handler(&state, stream, diff_sender.clone())?,
+                    Ok(stream) => {
+                        // the handshake may take a while, so don't hold up other peers
+                        let (state, diff_sender) = (state.clone(), diff_sender.clone());
+                        thread::spawn(move || match accept_peer(&state, stream, diff_sender) {
+                            Ok(()) => (),
+                            Err(err) => eprintln!("Failed connection: {:#}", err),
+                        });
+                    }
                     Err(err) => eprintln!("Failed connection: {}", err),
                 }
             }
         }
     });
 
-    return Ok(local_addr);
+    return Ok(());
 }
@@ -0,0 +1,427 @@
+use crate::common::*;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig,
+    ServerConnection, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    convert::TryFrom,
+    fs,
+    io::{self, BufRead, Read, Write},
+    net,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+// Peers don't have certificates signed by any authority. Instead each
+// session generates a self-signed certificate, and peers are identified by
+// the SHA-256 fingerprint of their certificate. The fingerprint of the
+// first instance is passed on the command line when connecting to it;
+// after that, fingerprints of the other instances in the session are
+// learned from peers that have already been verified.
+//
+// The same certificate is used both for accepting connections and as the
+// client certificate when connecting out, so that the accepting side also
+// learns the fingerprint of everyone that connects to it.
+
+/// Name presented in the certificate. Certificates are matched by
+/// fingerprint only, so this is never checked.
+const CERT_NAME: &str = "collab";
+
+/// Label for the value exported from the session for authentication
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-collab-auth";
+
+/// Formats a certificate fingerprint as colon-separated hex, e.g. "AB:CD:..."
+pub fn fingerprint(cert: &[u8]) -> String {
+    return Sha256::digest(cert)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":");
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    return fingerprint.replace(":", "").to_uppercase();
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    return Arc::new(crypto::ring::default_provider());
+}
+
+/// The certificate and key used by this instance for the session.
+#[derive(Debug)]
+pub struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    pub fingerprint: String,
+}
+
+impl Identity {
+    /// Generates a new self-signed certificate, storing it and its key in
+    /// the session directory.
+    #[context("unable to generate tls identity: {}", dir.display())]
+    pub fn generate(dir: &Path) -> Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![CERT_NAME.to_string()])?;
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+        // the session dir is under the shared temp dir, so only we may look
+        // inside it, and only we may read the key
+        fs::create_dir_all(dir)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if cfg!(target_family = "unix") {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+            options.mode(0o600);
+        }
+        fs::write(dir.join("cert.der"), &cert)?;
+        let mut file = options.open(dir.join("key.der"))?;
+        if cfg!(target_family = "unix") {
+            use std::os::unix::fs::PermissionsExt;
+            // in case it was left behind by an earlier session
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(key.secret_pkcs8_der())?;
+
+        return Ok(Self {
+            fingerprint: fingerprint(&cert),
+            cert,
+            key,
+        });
+    }
+
+    fn key(&self) -> PrivateKeyDer<'static> {
+        return PrivateKeyDer::Pkcs8(self.key.clone_key());
+    }
+
+    /// Performs the server side of the handshake on an accepted connection.
+    #[context("unable to accept tls connection")]
+    pub fn accept(&self, stream: PeerStream) -> Result<PeerStream> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(AnyCert(provider())))
+            .with_single_cert(vec![self.cert.clone()], self.key())?;
+        let conn = ServerConnection::new(Arc::new(config))?;
+        return handshake(stream, conn.into());
+    }
+
+    /// Performs the client side of the handshake, checking that the peer
+    /// presents the certificate with the given fingerprint.
+    #[context("unable to open tls connection")]
+    pub fn connect(&self, stream: PeerStream, expected: &str) -> Result<PeerStream> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCert {
+                provider: provider(),
+                fingerprint: normalize_fingerprint(expected),
+            }))
+            .with_client_auth_cert(vec![self.cert.clone()], self.key())?;
+        let conn = ClientConnection::new(Arc::new(config), ServerName::try_from(CERT_NAME)?)?;
+        return handshake(stream, conn.into());
+    }
+}
+
+/// Accepts the certificate with a particular fingerprint, regardless of
+/// who signed it or what names it is for.
+#[derive(Debug)]
+struct PinnedCert {
+    provider: Arc<CryptoProvider>,
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint(end_entity);
+        return if normalize_fingerprint(&actual) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Peer certificate fingerprint {} does not match the expected fingerprint",
+                actual
+            )))
+        };
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        return crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        );
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        return crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        );
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        return self
+            .provider
+            .signature_verification_algorithms
+            .supported_schemes();
+    }
+}
+
+/// Requires a client certificate, but accepts any. The fingerprint is
+/// recorded so that it can be passed on to other peers.
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ClientCertVerifier for AnyCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        return &[];
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        return Ok(ClientCertVerified::assertion());
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        return crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        );
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        return crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        );
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        return self.0.signature_verification_algorithms.supported_schemes();
+    }
+}
+
+/// A connection to a peer, split into halves that can be used from
+/// separate threads. Usually a TCP socket, but it can be any pair of byte
+/// streams (see stdio.rs).
+pub struct PeerStream {
+    pub reader: Box<dyn BufRead + Send>,
+    pub writer: Box<dyn Write + Send>,
+    /// Closes the underlying connection, so that it can be closed from
+    /// either half
+    pub close: Arc<dyn Fn() + Send + Sync>,
+    /// Fingerprint of the peer's certificate, if the connection uses TLS
+    pub fingerprint: Option<String>,
+    /// Value exported from the TLS session, which both ends only agree on
+    /// if nobody is in the middle (see auth.rs)
+    pub binding: Option<Vec<u8>>,
+}
+
+impl PeerStream {
+    #[context("unable to split tcp stream")]
+    pub fn plain(socket: net::TcpStream) -> Result<Self> {
+        let closing = socket.try_clone()?;
+        return Ok(Self {
+            reader: Box::new(io::BufReader::new(socket.try_clone()?)),
+            writer: Box::new(socket),
+            close: Arc::new(move || {
+                let _ = closing.shutdown(net::Shutdown::Both);
+            }),
+            fingerprint: None,
+            binding: None,
+        });
+    }
+}
+
+/// Both halves of a stream as one, for rustls to do the handshake over
+struct Halves<'a> {
+    reader: &'a mut dyn Read,
+    writer: &'a mut dyn Write,
+}
+
+impl Read for Halves<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        return self.reader.read(buf);
+    }
+}
+
+impl Write for Halves<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.writer.write(buf);
+    }
+
+    // rustls hands over a whole flight of records at once, which should go
+    // out together rather than one small write at a time
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        return self.writer.write_vectored(bufs);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.writer.flush();
+    }
+}
+
+/// State shared between the two halves of a TLS stream. Outgoing records
+/// are produced while holding the connection lock, and the send lock is
+/// taken before the connection lock is released so that records go out in
+/// the order they were produced. Blocking on a slow socket then only holds
+/// up other writes, not reads.
+struct TlsShared {
+    conn: Mutex<rustls::Connection>,
+    send: Mutex<Box<dyn Write + Send>>,
+}
+
+impl TlsShared {
+    fn send_pending(&self, mut conn: std::sync::MutexGuard<rustls::Connection>) -> io::Result<()> {
+        let mut data = Vec::new();
+        while conn.wants_write() {
+            conn.write_tls(&mut data)?;
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut send = self.send.lock().unwrap();
+        drop(conn);
+        send.write_all(&data[..])?;
+        return send.flush();
+    }
+}
+
+struct TlsReader {
+    shared: Arc<TlsShared>,
+    reader: Box<dyn BufRead + Send>,
+    /// Bytes read from the socket that rustls has not taken yet
+    pending: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = self.shared.conn.lock().unwrap();
+                match conn.reader().read(buf) {
+                    Ok(size) => return Ok(size),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(err) => return Err(err),
+                }
+                if !self.pending.is_empty() {
+                    let size = conn.read_tls(&mut &self.pending[..])?;
+                    self.pending.drain(..size);
+                    conn.process_new_packets()
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    self.shared.send_pending(conn)?;
+                    continue;
+                }
+            }
+            let mut chunk = [0; 16384];
+            let size = self.reader.read(&mut chunk)?;
+            if size == 0 {
+                // let rustls know about the end of the stream
+                let mut conn = self.shared.conn.lock().unwrap();
+                conn.read_tls(&mut io::empty())?;
+                conn.process_new_packets()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                return conn.reader().read(buf);
+            }
+            self.pending.extend_from_slice(&chunk[..size]);
+        }
+    }
+}
+
+struct TlsWriter {
+    shared: Arc<TlsShared>,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let size = conn.writer().write(buf)?;
+        self.shared.send_pending(conn)?;
+        return Ok(size);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let conn = self.shared.conn.lock().unwrap();
+        return self.shared.send_pending(conn);
+    }
+}
+
+#[context("unable to complete tls handshake")]
+fn handshake(stream: PeerStream, mut conn: rustls::Connection) -> Result<PeerStream> {
+    let PeerStream {
+        mut reader,
+        mut writer,
+        close,
+        ..
+    } = stream;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut Halves {
+            reader: &mut reader,
+            writer: &mut writer,
+        })?;
+    }
+    let fingerprint = match conn.peer_certificates() {
+        Some([cert, ..]) => fingerprint(cert),
+        _ => {
+            return Err(CollabError::Error("Peer did not present a certificate".to_string()).into())
+        }
+    };
+    let binding = conn.export_keying_material(vec![0; 32], EXPORTER_LABEL, None)?;
+    let shared = Arc::new(TlsShared {
+        conn: Mutex::new(conn),
+        send: Mutex::new(writer),
+    });
+    return Ok(PeerStream {
+        reader: Box::new(io::BufReader::new(TlsReader {
+            shared: shared.clone(),
+            reader,
+            pending: Vec::new(),
+        })),
+        writer: Box::new(TlsWriter { shared }),
+        close,
+        fingerprint: Some(fingerprint),
+        binding: Some(binding),
+    });
+}
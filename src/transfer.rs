@@ -0,0 +1,483 @@
+use crate::common::*;
+use crate::{delta, ipc, protocol, queue};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+};
+
+// Files too large to keep as bases for deltas (see delta.rs) aren't read
+// into memory and sent in one message, which would hold up everything
+// else on the connection for as long as they take. Instead, peers are only
+// told a large file's size and hash, and fetch it from the peer that told
+// them in chunks. Chunks are requested a few at a time, so that other
+// messages go in between, and each one is read from disk as it is asked
+// for.
+//
+// Chunks are written to a partial file in the session directory, which is
+// moved into place once the whole file has arrived and its hash checks
+// out. Only then is the file registered and passed along to other peers,
+// just as if it had arrived whole, so they fetch it in turn from a peer
+// that has all of it. Partial files are only readable by us, since the
+// session directory is under the shared temp dir. If the connection drops,
+// the partial file is kept: once the peer resyncs, fetching picks up where
+// it left off, hashing what already arrived and asking only for the rest.
+
+/// Files larger than this are fetched in chunks
+pub const LARGE_FILE: u64 = delta::MAX_BASE_SIZE as u64;
+
+const CHUNK_SIZE: u64 = 256 << 10;
+
+/// How many chunks to have asked for at once
+const WINDOW: u64 = 8;
+
+#[derive(Debug)]
+struct Transfer {
+    hash: u64,
+    size: u64,
+    /// The peer the file is being fetched from
+    from: NodeId,
+    partial: PathBuf,
+    file: fs::File,
+    /// Hash of what has arrived so far
+    hasher: DefaultHasher,
+    received: u64,
+    requested: u64,
+    /// Permissions that arrived for the file while it was being fetched
+    perm: Option<FilePerm>,
+}
+
+/// A transfer that stopped when the connection to its peer dropped, kept
+/// until the peer resyncs
+#[derive(Debug)]
+struct Stalled {
+    hash: u64,
+    size: u64,
+    from: NodeId,
+    perm: Option<FilePerm>,
+}
+
+impl Transfer {
+    /// Asks for chunks until there are enough on the way
+    fn request(&mut self, path: &RelativePath, sender: &queue::Sender<RemoteMsg>) {
+        while self.requested < self.size && self.requested < self.received + WINDOW * CHUNK_SIZE {
+            sender.send(RemoteMsg::FetchChunk {
+                path: path.to_relative_path_buf(),
+                hash: self.hash,
+                offset: self.requested,
+            });
+            self.requested += CHUNK_SIZE;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Transfers {
+    root: PathBuf,
+    /// Where partial files are kept
+    dir: PathBuf,
+    fetching: HashMap<RelativePathBuf, Transfer>,
+    stalled: HashMap<RelativePathBuf, Stalled>,
+    /// Hashes of files that have been fetched but not registered yet
+    fetched: HashMap<RelativePathBuf, u64>,
+}
+
+impl Transfers {
+    #[context("unable to set up transfers, root: {}", root.display())]
+    pub fn new(root: &Path) -> Result<Self> {
+        return Ok(Transfers {
+            root: root.to_path_buf(),
+            dir: ipc::session_dir(root)?.join("transfers"),
+            fetching: HashMap::new(),
+            stalled: HashMap::new(),
+            fetched: HashMap::new(),
+        });
+    }
+
+    /// Where the part of a file that has arrived so far is kept, which is
+    /// the same for every attempt at fetching the same version
+    fn partial(&self, path: &RelativePath, hash: u64) -> PathBuf {
+        let mut name = DefaultHasher::new();
+        (path, hash).hash(&mut name);
+        return self.dir.join(format!("{:016x}", name.finish()));
+    }
+
+    /// Holds on to permissions for a file being fetched, to set once it is
+    /// there, returning false if the file isn't being fetched
+    pub fn defer_chmod(&mut self, path: &RelativePath, perm: &FilePerm) -> bool {
+        return match (self.fetching.get_mut(path), self.stalled.get_mut(path)) {
+            (Some(transfer), _) => {
+                transfer.perm = Some(perm.clone());
+                true
+            }
+            (None, Some(stalled)) => {
+                stalled.perm = Some(perm.clone());
+                true
+            }
+            (None, None) => false,
+        };
+    }
+
+    /// Gives up on fetching files that a diff replaces
+    pub fn cancel(&mut self, diff: &FsDiff) {
+        let path = match diff {
+            FsDiff::Write(path, _)
+            | FsDiff::Delta(path, _)
+            | FsDiff::Large(path, _, _)
+            | FsDiff::Del(path)
+            | FsDiff::Move(path, _) => path,
+            _ => return,
+        };
+        let cancelled: Vec<RelativePathBuf> = self
+            .fetching
+            .keys()
+            .filter(|fetching| fetching.starts_with(path))
+            .cloned()
+            .collect();
+        for path in cancelled {
+            let transfer = self.fetching.remove(&path).unwrap();
+            let _ = fs::remove_file(&transfer.partial);
+        }
+        let cancelled: Vec<RelativePathBuf> = self
+            .stalled
+            .keys()
+            .filter(|stalled| stalled.starts_with(path))
+            .cloned()
+            .collect();
+        for path in cancelled {
+            let stalled = self.stalled.remove(&path).unwrap();
+            let _ = fs::remove_file(self.partial(&path, stalled.hash));
+        }
+    }
+
+    /// Sets aside the files being fetched from a peer that has
+    /// disconnected, keeping what has arrived of them
+    pub fn stall(&mut self, peer: &NodeId) {
+        let stalled: Vec<RelativePathBuf> = self
+            .fetching
+            .iter()
+            .filter(|(_, transfer)| &transfer.from == peer)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in stalled {
+            let transfer = self.fetching.remove(&path).unwrap();
+            self.stalled.insert(
+                path,
+                Stalled {
+                    hash: transfer.hash,
+                    size: transfer.size,
+                    from: transfer.from,
+                    perm: transfer.perm,
+                },
+            );
+        }
+    }
+}
+
+/// Hashes a file the same way as `hash_file`, without reading all of it
+/// into memory. Fails if the file isn't the given size, e.g. because it is
+/// still being written.
+fn hash_reader(reader: &mut dyn Read, size: u64) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    (size as usize).hash(&mut hasher);
+    let read = hash_into(&mut hasher, reader)?;
+    if read != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, read {}", size, read),
+        ));
+    }
+    return Ok(hasher.finish());
+}
+
+/// Feeds everything a reader has to a hasher, returning how many bytes
+/// that was
+fn hash_into(hasher: &mut DefaultHasher, reader: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut read = 0;
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(read),
+            len => {
+                hasher.write(&buf[..len]);
+                read += len as u64;
+            }
+        }
+    }
+}
+
+/// Reads a file into the diff that sends it: whole if it is small enough,
+/// or its size and hash if it is large
+#[context("unable to read file: {}", path.display())]
+pub fn read(path: &Path, relative: RelativePathBuf) -> Result<FsDiff> {
+    let mut file = fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    if size <= LARGE_FILE {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        return Ok(FsDiff::Write(relative, Arc::new(data)));
+    }
+    let hash = hash_reader(&mut file, size)?;
+    return Ok(FsDiff::Large(relative, size, hash));
+}
+
+/// Turns a large file back into a whole one for peers that can't fetch it
+/// in chunks
+pub fn whole(diff: FsDiff, root: &Path, capabilities: &protocol::Capabilities) -> FsDiff {
+    return match diff {
+        FsDiff::Large(path, size, hash) if !capabilities.contains(protocol::CHUNKS) => {
+            match fs::read(path_join(root, &path)) {
+                Ok(data) => FsDiff::Write(path, Arc::new(data)),
+                // file may have been deleted or moved
+                Err(_) => FsDiff::Large(path, size, hash),
+            }
+        }
+        diff => diff,
+    };
+}
+
+/// Starts fetching a large file that a peer told us about, unless we
+/// already have it. Returns whether handling the diff has to wait until
+/// the file has been fetched.
+#[context("unable to fetch {} from {}", path, source)]
+pub fn fetch(
+    path: &RelativePath,
+    size: u64,
+    hash: u64,
+    source: &NodeId,
+    state: &SharedState,
+) -> Result<bool> {
+    match state.register.lock().unwrap().get(path) {
+        Some(FsReg::File(ours, _)) if *ours == hash => return Ok(false),
+        _ => (),
+    }
+    let mut transfers = state.transfers.lock().unwrap();
+    if transfers.fetched.get(path) == Some(&hash) {
+        transfers.fetched.remove(path);
+        return Ok(false);
+    }
+
+    let (fetching, sender) = {
+        let peers = state.peers.lock().unwrap();
+        let fetching = match transfers.fetching.get(path) {
+            Some(transfer) => transfer.hash == hash && peers.contains_key(&transfer.from),
+            None => false,
+        };
+        (fetching, peers.get(source).map(|peer| peer.sender.clone()))
+    };
+    if fetching {
+        return Ok(true);
+    }
+    match transfers.fetching.remove(path) {
+        Some(transfer) if transfer.hash != hash => {
+            let _ = fs::remove_file(&transfer.partial);
+        }
+        _ => (),
+    }
+    // the peer will tell us again when it resyncs
+    let sender = match sender {
+        Some(sender) => sender,
+        None => return Ok(true),
+    };
+    let perm = match transfers.stalled.remove(path) {
+        Some(stalled) if stalled.hash != hash => {
+            let _ = fs::remove_file(transfers.partial(path, stalled.hash));
+            None
+        }
+        Some(stalled) => stalled.perm,
+        None => None,
+    };
+
+    fs::create_dir_all(&transfers.dir)?;
+    let partial = transfers.partial(path, hash);
+    let mut options = fs::OpenOptions::new();
+    options.read(true).append(true).create(true);
+    if cfg!(target_family = "unix") {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&partial)?;
+
+    // pick up where an earlier attempt left off
+    let mut received = file.metadata()?.len();
+    if received > size {
+        file.set_len(0)?;
+        received = 0;
+    }
+    let mut hasher = DefaultHasher::new();
+    (size as usize).hash(&mut hasher);
+    file.seek(io::SeekFrom::Start(0))?;
+    hash_into(&mut hasher, &mut file)?;
+    if received > 0 {
+        println!(
+            "Resuming {} from {} at {} of {} bytes",
+            path, source, received, size
+        );
+    } else {
+        println!("Fetching {} from {}: {} bytes", path, source, size);
+    }
+
+    let mut transfer = Transfer {
+        hash,
+        size,
+        from: source.clone(),
+        partial,
+        file,
+        hasher,
+        received,
+        requested: received,
+        perm,
+    };
+    transfer.request(path, &sender);
+    transfers
+        .fetching
+        .insert(path.to_relative_path_buf(), transfer);
+    return Ok(true);
+}
+
+/// Fetches the rest of the files that a peer was sending when the
+/// connection to it dropped, now that it has resynced
+#[context("unable to resume fetching from {}", source)]
+pub fn resume(source: &NodeId, state: &SharedState) -> Result<()> {
+    let stalled: Vec<(RelativePathBuf, u64, u64)> = state
+        .transfers
+        .lock()
+        .unwrap()
+        .stalled
+        .iter()
+        .filter(|(_, stalled)| &stalled.from == source)
+        .map(|(path, stalled)| (path.clone(), stalled.size, stalled.hash))
+        .collect();
+    for (path, size, hash) in stalled {
+        fetch(&path, size, hash, source, state)?;
+    }
+    return Ok(());
+}
+
+/// Writes a chunk of a file being fetched, asking for more or moving the
+/// file into place once all of it is there
+#[context("unable to receive chunk of {} from {}", path, source)]
+pub fn receive(
+    path: &RelativePath,
+    hash: u64,
+    offset: u64,
+    data: Bytes,
+    source: &NodeId,
+    state: &SharedState,
+    msg_sender: &mpsc::Sender<Msg>,
+) -> Result<()> {
+    let mut transfers = state.transfers.lock().unwrap();
+    let transfer = match transfers.fetching.get_mut(path) {
+        Some(transfer)
+            if transfer.hash == hash && &transfer.from == source && transfer.received == offset =>
+        {
+            transfer
+        }
+        // left over from an attempt that was given up on
+        _ => return Ok(()),
+    };
+    let data = data.0;
+    // chunks are never longer than asked for, and never run past the size
+    // the file was advertised with
+    let fits = match offset.checked_add(data.len() as u64) {
+        Some(end) => end <= transfer.size,
+        None => false,
+    };
+    if data.is_empty() || data.len() as u64 > CHUNK_SIZE || !fits {
+        // the peer will tell us about the new version
+        println!("{} changed while being fetched from {}", path, source);
+        let transfer = transfers.fetching.remove(path).unwrap();
+        fs::remove_file(&transfer.partial)?;
+        return Ok(());
+    }
+    transfer.file.write_all(&data[..])?;
+    transfer.hasher.write(&data[..]);
+    transfer.received += data.len() as u64;
+    if transfer.received < transfer.size {
+        match state.peers.lock().unwrap().get(source) {
+            Some(peer) => transfer.request(path, &peer.sender),
+            None => (),
+        }
+        return Ok(());
+    }
+
+    let transfer = transfers.fetching.remove(path).unwrap();
+    drop(transfer.file);
+    if transfer.hasher.finish() != hash {
+        eprintln!("Fetched {} from {}, but it doesn't match", path, source);
+        fs::remove_file(&transfer.partial)?;
+        return Ok(());
+    }
+    let full_path = path_join(&transfers.root, path);
+    match fs::rename(&transfer.partial, &full_path) {
+        Ok(()) => (),
+        // the session directory may be on another file system
+        Err(_) => {
+            fs::copy(&transfer.partial, &full_path)?;
+            fs::remove_file(&transfer.partial)?;
+        }
+    }
+    println!("Fetched {} from {}", path, source);
+    transfers.fetched.insert(path.to_relative_path_buf(), hash);
+
+    // now that the file is here, handle it like any other change
+    let mut diffs = vec![FsDiff::Large(
+        path.to_relative_path_buf(),
+        transfer.size,
+        hash,
+    )];
+    match transfer.perm {
+        Some(perm) => diffs.push(FsDiff::Chmod(path.to_relative_path_buf(), perm)),
+        None => (),
+    }
+    for diff in diffs {
+        msg_sender
+            .send(Msg {
+                body: MsgBody::Remote(RemoteMsg::FsDiff(diff)),
+                source: MsgSource::Peer(source.clone()),
+            })
+            .map_err(|err| CollabError::Error(format!("Error sending fetched file: {}", err)))?;
+    }
+    return Ok(());
+}
+
+/// Sends a peer the chunk of a large file that it asked for, or an empty
+/// one if the file has changed since it was told about it
+#[context("unable to send chunk of {} to {}", path, source)]
+pub fn send_chunk(
+    root: &Path,
+    path: &RelativePath,
+    hash: u64,
+    offset: u64,
+    source: &NodeId,
+    state: &SharedState,
+) -> Result<()> {
+    let current = match state.register.lock().unwrap().get(path) {
+        Some(FsReg::File(ours, _)) => *ours == hash,
+        _ => false,
+    };
+    let mut data = Vec::new();
+    if current {
+        // file may have been deleted or moved
+        match fs::File::open(path_join(root, path)) {
+            Ok(mut file) => {
+                file.seek(io::SeekFrom::Start(offset))?;
+                file.take(CHUNK_SIZE).read_to_end(&mut data)?;
+            }
+            Err(_) => (),
+        }
+    }
+    match state.peers.lock().unwrap().get(source) {
+        Some(peer) => peer.sender.send(RemoteMsg::Chunk {
+            path: path.to_relative_path_buf(),
+            hash,
+            offset,
+            data: Bytes(data),
+        }),
+        None => (),
+    }
+    return Ok(());
+}
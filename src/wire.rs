@@ -0,0 +1,197 @@
+use crate::common::*;
+use crate::protocol;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::{
+    convert::TryFrom,
+    io::{self, BufRead, Read, Write},
+};
+
+// Messages are sent in frames. The hello and authentication that open a
+// connection are always JSON terminated by a null byte, which every version
+// understands. After that, both sides switch to the binary format if they
+// both support it: each frame is a 4-byte big-endian length followed by the
+// message encoded with bincode. File contents then go over as raw bytes
+// rather than as JSON arrays of numbers, and since the length of a frame is
+// known up front, nothing inside it can be mistaken for its end.
+//
+// If both sides also support compression, binary frames carry a flags byte
+// after the length, saying whether the rest of the frame is compressed.
+// Only frames above a threshold are compressed, and only if that actually
+// makes them smaller. Peers also batch up messages that are waiting to be
+// sent (e.g. the files sent on startup), so that lots of small files
+// compress together.
+
+const DELIM: u8 = b'\0';
+
+/// Largest frame we accept, well above the largest batch or file chunk
+/// that a peer sends
+pub const MAX_FRAME: usize = 64 << 20;
+
+/// Frames smaller than this aren't worth compressing
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Flag set on compressed frames
+const FLAG_DEFLATE: u8 = 1;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Codec {
+    Json,
+    Binary,
+    /// Binary frames that may be compressed
+    Compressed,
+}
+
+impl Codec {
+    /// The codec to use after the hello, given the capabilities both sides
+    /// share
+    pub fn negotiate(capabilities: &protocol::Capabilities) -> Self {
+        return match (
+            capabilities.contains(protocol::BINARY),
+            capabilities.contains(protocol::COMPRESSION),
+        ) {
+            (true, true) => Codec::Compressed,
+            (true, false) => Codec::Binary,
+            (false, _) => Codec::Json,
+        };
+    }
+
+    #[context("unable to encode message")]
+    pub fn encode<T: serde::Serialize>(&self, msg: &T) -> Result<Vec<u8>> {
+        return Ok(match self {
+            Codec::Json => serde_json::to_vec(msg)?,
+            Codec::Binary | Codec::Compressed => bincode::serialize(msg)?,
+        });
+    }
+
+    #[context("unable to decode message")]
+    pub fn decode<T: serde::de::DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        return Ok(match self {
+            Codec::Json => serde_json::from_slice(data)?,
+            Codec::Binary | Codec::Compressed => bincode::deserialize(data)?,
+        });
+    }
+
+    /// Writes a single frame. The frame goes out in one write so that
+    /// handshakes don't stall on delayed acknowledgements.
+    #[context("unable to write frame")]
+    pub fn write_frame(&self, writer: &mut dyn io::Write, data: &[u8]) -> Result<()> {
+        let frame = match self {
+            Codec::Json => {
+                let mut frame = Vec::with_capacity(data.len() + 1);
+                frame.extend_from_slice(data);
+                frame.push(DELIM);
+                frame
+            }
+            Codec::Binary => length_prefixed(&[], data)?,
+            Codec::Compressed => match compress(data)? {
+                Some(compressed) => length_prefixed(&[FLAG_DEFLATE], &compressed[..])?,
+                None => length_prefixed(&[0], data)?,
+            },
+        };
+        writer.write_all(&frame[..])?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    /// Reads a single frame, returning None if the connection was closed.
+    /// Fails on frames larger than MAX_FRAME, so that the other side can't
+    /// make us buffer without limit.
+    #[context("unable to read frame")]
+    pub fn read_frame(&self, reader: &mut dyn io::BufRead) -> Result<Option<Vec<u8>>> {
+        match self {
+            Codec::Json => {
+                let mut data = Vec::new();
+                reader
+                    .take(MAX_FRAME as u64 + 1)
+                    .read_until(DELIM, &mut data)?;
+                return match data.last() {
+                    Some(&DELIM) => {
+                        data.pop();
+                        Ok(Some(data))
+                    }
+                    _ if data.len() > MAX_FRAME => Err(too_large()),
+                    // closed, possibly partway through the frame
+                    _ => Ok(None),
+                };
+            }
+            Codec::Binary => return read_length_prefixed(reader),
+            Codec::Compressed => {
+                let frame = match read_length_prefixed(reader)? {
+                    Some(frame) => frame,
+                    None => return Ok(None),
+                };
+                return match frame.split_first() {
+                    Some((&FLAG_DEFLATE, data)) => {
+                        let mut decompressed = Vec::new();
+                        DeflateDecoder::new(data)
+                            .take(MAX_FRAME as u64 + 1)
+                            .read_to_end(&mut decompressed)?;
+                        if decompressed.len() > MAX_FRAME {
+                            return Err(too_large());
+                        }
+                        Ok(Some(decompressed))
+                    }
+                    Some((0, data)) => Ok(Some(data.to_vec())),
+                    Some((flags, _)) => {
+                        Err(CollabError::Error(format!("Unknown frame flags: {}", flags)).into())
+                    }
+                    None => Err(CollabError::Error("Empty frame".to_string()).into()),
+                };
+            }
+        }
+    }
+}
+
+/// Compresses a frame if it is large enough to be worth it, returning None
+/// if it is better sent as is
+fn compress(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    if data.len() < COMPRESSION_THRESHOLD {
+        return Ok(None);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+    return Ok(if compressed.len() < data.len() {
+        Some(compressed)
+    } else {
+        None
+    });
+}
+
+fn too_large() -> Error {
+    return CollabError::Error(format!("Frame larger than {} bytes", MAX_FRAME)).into();
+}
+
+fn length_prefixed(header: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let len = match u32::try_from(header.len() + data.len()) {
+        Ok(len) if len as usize <= MAX_FRAME => len,
+        _ => {
+            return Err(CollabError::Error(format!(
+                "Message too large to send: {} bytes",
+                data.len()
+            ))
+            .into())
+        }
+    };
+    let mut frame = Vec::with_capacity(4 + header.len() + data.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(header);
+    frame.extend_from_slice(data);
+    return Ok(frame);
+}
+
+fn read_length_prefixed(reader: &mut dyn io::Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(too_large());
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data[..])?;
+    return Ok(Some(data));
+}
@@ -3,10 +3,12 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use relative_path::{RelativePath, RelativePathBuf};
 use std::ffi::OsStr;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use tempdir::TempDir;
@@ -14,22 +16,63 @@ use tempdir::TempDir;
 use crate::common;
 use crate::util;
 
+/// Per-user config directory (keypair and known peers) used for commands
+/// run in the given root, so that each test instance has its own identity
+pub fn config_dir<P: AsRef<Path>>(root: P) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push("collab_test_config");
+    dir.push(root.as_ref().file_name().unwrap());
+    return dir;
+}
+
+/// Directory where the daemon for the given root keeps the rest of its
+/// session, e.g. its TLS key
+pub fn session_dir<P: AsRef<Path>>(root: P) -> PathBuf {
+    let mut dir = std::env::temp_dir();
+    dir.push("collab");
+    dir.push(format!("{}.d", root.as_ref().display().to_string().replace("/", "!")));
+    return dir;
+}
+
+/// Port that the daemon in the given root listens for attached clients on
+pub fn ipc_port<P: AsRef<Path>>(root: P) -> common::Result<u16> {
+    let mut key = std::env::temp_dir();
+    key.push("collab");
+    key.push(root.as_ref().display().to_string().replace("/", "!"));
+    let data: serde_json::Value = serde_json::from_slice(&std::fs::read(key)?)?;
+    return Ok(data["port"].as_u64().unwrap() as u16);
+}
+
 pub fn spawn<I: IntoIterator<Item = S>, S: AsRef<OsStr>, P: AsRef<Path>>(
     args: I,
     root: P,
 ) -> process::Command {
     let cargo_bin = util::cargo_bin("collab");
     let mut cmd = process::Command::new(cargo_bin);
+    cmd.env("COLLAB_CONFIG_DIR", config_dir(&root));
     cmd.current_dir(root);
     cmd.args(args);
     return cmd;
 }
 
+/// Shell command line that runs collab in the given root, e.g. for a
+/// daemon to connect through with --connect-cmd
+pub fn command_line<P: AsRef<Path>>(args: &[&str], root: P) -> String {
+    return format!(
+        "{} -r {} {}",
+        util::cargo_bin("collab").display(),
+        root.as_ref().display(),
+        args.join(" ")
+    );
+}
+
 pub struct Daemon {
     pub id: String,
     daemon: process::Child,
     pub root: PathBuf,
-    address: String,
+    pub address: String,
+    /// Secret of the session this daemon belongs to
+    pub secret: String,
 }
 
 impl Drop for Daemon {
@@ -39,6 +82,8 @@ impl Drop for Daemon {
         let wait_daemon = self.daemon.wait();
         let wait_stop = stop.wait();
 
+        let _ = std::fs::remove_dir_all(config_dir(&self.root));
+
         // if we have already failed, just give up
         if !thread::panicking() {
             // wait on the daemon first so that we see the correct error
@@ -52,6 +97,7 @@ fn spawn_daemon<P: AsRef<Path>>(
     id: &str,
     root: &P,
     connect: Option<&Daemon>,
+    extra_args: &[&str],
 ) -> common::Result<Daemon> {
     let mut args = Vec::new();
     args.push("start");
@@ -59,9 +105,20 @@ fn spawn_daemon<P: AsRef<Path>>(
         Some(peer) => {
             args.push("-c");
             args.push(&peer.address);
+            if !extra_args.contains(&"--secret") {
+                args.push("--secret");
+                args.push(&peer.secret);
+            }
         }
         None => {}
     }
+    args.extend_from_slice(extra_args);
+
+    // the daemon only prints the secret if it generates one
+    let mut secret = match args.iter().position(|arg| *arg == "--secret") {
+        Some(index) => Some(args[index + 1].to_string()),
+        None => None,
+    };
 
     let mut daemon = spawn(args, &root)
         .stdout(process::Stdio::piped())
@@ -90,6 +147,10 @@ fn spawn_daemon<P: AsRef<Path>>(
         // wait until the address gets printed
         let line = stdout.next().unwrap().unwrap();
         echo_line(&line, &id, false);
+        match line.strip_prefix("Session secret: ") {
+            Some(generated) => secret = Some(generated.to_string()),
+            None => (),
+        }
         match RE.captures(&line).map(|m| m.get(0)) {
             Some(Some(add)) => break add.as_str().to_string(),
             _ => continue,
@@ -119,15 +180,194 @@ fn spawn_daemon<P: AsRef<Path>>(
         daemon,
         root: root.as_ref().to_path_buf(),
         address,
+        secret: secret.expect("daemon did not print a session secret"),
     });
 }
 
 pub fn daemon<P: AsRef<Path>>(id: &str, root: &P) -> common::Result<Daemon> {
-    return Ok(spawn_daemon(id, root, None)?);
+    return Ok(spawn_daemon(id, root, None, &[])?);
+}
+
+pub fn daemon_with_args<P: AsRef<Path>>(
+    id: &str,
+    root: &P,
+    args: &[&str],
+) -> common::Result<Daemon> {
+    return Ok(spawn_daemon(id, root, None, args)?);
 }
 
 pub fn connect<P: AsRef<Path>>(id: &str, root: &P, peer: &Daemon) -> common::Result<Daemon> {
-    return Ok(spawn_daemon(id, root, Some(peer))?);
+    return Ok(spawn_daemon(id, root, Some(peer), &[])?);
+}
+
+pub fn connect_with_args<P: AsRef<Path>>(
+    id: &str,
+    root: &P,
+    peer: &Daemon,
+    args: &[&str],
+) -> common::Result<Daemon> {
+    return Ok(spawn_daemon(id, root, Some(peer), args)?);
+}
+
+/// Runs `collab info` for the session in the given directory
+pub fn info<P: AsRef<Path>>(root: &P) -> common::Result<String> {
+    let output = spawn(&["info"], &root).output()?;
+    return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+}
+
+/// Finds a port that is free right now, for tests that need a fixed port
+pub fn free_port() -> common::Result<u16> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    return Ok(listener.local_addr()?.port());
+}
+
+/// Forwards connections to a daemon, so that tests can cut them off as if
+/// the network had gone down
+pub struct Proxy {
+    pub address: String,
+    down: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Proxy {
+    pub fn new(target: &Daemon) -> common::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let proxy = Proxy {
+            address: listener.local_addr()?.to_string(),
+            down: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            streams: Arc::new(Mutex::new(Vec::new())),
+            bytes: Arc::new(AtomicU64::new(0)),
+        };
+
+        let target = target.address.clone();
+        let (down, paused, streams, bytes) = (
+            proxy.down.clone(),
+            proxy.paused.clone(),
+            proxy.streams.clone(),
+            proxy.bytes.clone(),
+        );
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                if down.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let server = TcpStream::connect(&target).unwrap();
+                let mut streams = streams.lock().unwrap();
+                for (mut from, mut to) in vec![
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server.try_clone().unwrap(), client.try_clone().unwrap()),
+                ] {
+                    let (paused, bytes) = (paused.clone(), bytes.clone());
+                    thread::spawn(move || {
+                        let mut buf = [0; 4096];
+                        loop {
+                            let len = match from.read(&mut buf) {
+                                Ok(0) | Err(_) => break,
+                                Ok(len) => len,
+                            };
+                            while paused.load(Ordering::SeqCst) {
+                                thread::sleep(Duration::from_millis(10));
+                            }
+                            if to.write_all(&buf[..len]).is_err() {
+                                break;
+                            }
+                            bytes.fetch_add(len as u64, Ordering::SeqCst);
+                        }
+                        let _ = to.shutdown(Shutdown::Both);
+                    });
+                }
+                streams.push(client);
+                streams.push(server);
+            }
+        });
+
+        return Ok(proxy);
+    }
+
+    /// Cuts off all connections and refuses new ones until `up` is called
+    pub fn down(&self) {
+        self.down.store(true, Ordering::SeqCst);
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn up(&self) {
+        self.down.store(false, Ordering::SeqCst);
+    }
+
+    /// Holds on to everything sent through the proxy, without closing any
+    /// connections, until `resume` is called
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// How many bytes have gone through the proxy, either way
+    pub fn bytes(&self) -> u64 {
+        return self.bytes.load(Ordering::SeqCst);
+    }
+}
+
+/// A `collab relay` process, which daemons can join sessions through
+pub struct Relay {
+    relay: process::Child,
+    pub address: String,
+    _dir: TempDir,
+}
+
+impl Relay {
+    pub fn new() -> common::Result<Self> {
+        let dir = tempdir()?;
+        let mut relay = spawn(&["relay", "--listen", "127.0.0.1:0"], &dir)
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()?;
+
+        let mut stdout = BufReader::new(relay.stdout.take().unwrap()).lines();
+        let stderr = BufReader::new(relay.stderr.take().unwrap()).lines();
+
+        let address = loop {
+            let line = stdout.next().unwrap().unwrap();
+            println!("RELAY stdout: {}", line);
+            match line.strip_prefix("Relaying on ") {
+                Some(address) => break address.to_string(),
+                None => continue,
+            }
+        };
+
+        thread::spawn(move || {
+            for line in stdout {
+                println!("RELAY stdout: {}", line.unwrap());
+            }
+        });
+        thread::spawn(move || {
+            for line in stderr {
+                eprintln!("RELAY stderr: {}", line.unwrap().red());
+            }
+        });
+
+        return Ok(Relay {
+            relay,
+            address,
+            _dir: dir,
+        });
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        // the relay runs until it is killed
+        let _ = self.relay.kill();
+        let _ = self.relay.wait();
+    }
 }
 
 pub struct Attach<'a> {
@@ -136,6 +376,7 @@ pub struct Attach<'a> {
     process: process::Child,
     stdout: mpsc::Receiver<String>,
     stderr: mpsc::Receiver<String>,
+    events: mpsc::Receiver<serde_json::Value>,
 }
 
 impl<'a> Drop for Attach<'a> {
@@ -197,17 +438,30 @@ impl<'a> Attach<'a> {
             None => None,
         });
     }
+
+    /// Events are split out from the rest of stdout so that they don't get
+    /// in the way of diffs.
+    pub fn pop_event(&mut self) -> Option<serde_json::Value> {
+        return self.events.try_recv().ok();
+    }
 }
 
 pub fn attach<'a, P: AsRef<RelativePath>>(
     daemon: &'a Daemon,
     path: P,
+) -> common::Result<Attach<'a>> {
+    return attach_with_args(daemon, path, &[]);
+}
+
+pub fn attach_with_args<'a, P: AsRef<RelativePath>>(
+    daemon: &'a Daemon,
+    path: P,
+    extra_args: &[&str],
 ) -> common::Result<Attach<'a>> {
     let path_ref = path.as_ref();
-    let mut process = spawn(
-        &["attach", "--description", "", "--file", path_ref.as_str()],
-        &daemon.root,
-    )
+    let mut args = vec!["attach", "--description", "", "--file", path_ref.as_str()];
+    args.extend_from_slice(extra_args);
+    let mut process = spawn(&args, &daemon.root)
     .stdout(process::Stdio::piped())
     .stderr(process::Stdio::piped())
     .stdin(process::Stdio::piped())
@@ -218,6 +472,7 @@ pub fn attach<'a, P: AsRef<RelativePath>>(
 
     let (stdout_send, stdout_recv) = mpsc::channel();
     let (stderr_send, stderr_recv) = mpsc::channel();
+    let (events_send, events_recv) = mpsc::channel();
 
     fn echo_line(line: &str, id: &str, path: &RelativePath, err: bool) {
         if err {
@@ -233,7 +488,10 @@ pub fn attach<'a, P: AsRef<RelativePath>>(
             for line in stdout {
                 let line = line.unwrap();
                 echo_line(&line, &id, &path, false);
-                stdout_send.send(line).unwrap();
+                match serde_json::from_str::<serde_json::Value>(&line) {
+                    Ok(value) if value.get("event").is_some() => events_send.send(value).unwrap(),
+                    _ => stdout_send.send(line).unwrap(),
+                }
             }
         });
     }
@@ -255,6 +513,7 @@ pub fn attach<'a, P: AsRef<RelativePath>>(
         process: process,
         stdout: stdout_recv,
         stderr: stderr_recv,
+        events: events_recv,
     });
 }
 
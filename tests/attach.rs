@@ -1,4 +1,9 @@
-use test_common::{common::Result, dir, file, files, rig};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+};
+
+use test_common::{common::Result, dir, file, files, path, rig};
 
 #[test]
 fn connect() -> Result<()> {
@@ -19,9 +24,44 @@ fn connect() -> Result<()> {
     return Ok(());
 }
 
+#[test]
+fn detach_one() -> Result<()> {
+    let root = rig::tempdir()?;
+    let daemon = rig::daemon("r1", &root)?;
+
+    let files = dir! {
+        "file" => file!("")
+    };
+    files.apply(&root)?;
+
+    rig::wait();
+
+    let mut attach1 = rig::attach(&daemon, "file")?;
+    let mut attach2 = rig::attach(&daemon, "file")?;
+    let attach3 = rig::attach(&daemon, "file")?;
+
+    rig::wait();
+
+    // the others stay attached to the file
+    drop(attach3);
+
+    rig::wait();
+
+    let diff = rig::BufferDiff::new(0, 0, "x");
+    attach1.send_diff(&diff)?;
+
+    rig::wait();
+
+    assert_eq!(attach2.pop_diff()?, Some(diff));
+
+    return Ok(());
+}
+
 macro_rules! basic_pair({$name1:ident, $name2:ident} => {
-    let root1 = rig::tempdir()?;
-    let daemon1 = rig::daemon("r1", &root1)?;
+    basic_pair!(root1, $name1, $name2);
+}; {$root1:ident, $name1:ident, $name2:ident} => {
+    let $root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &$root1)?;
 
     let root2 = rig::tempdir()?;
     let daemon2 = rig::connect("r2", &root2, &daemon1)?;
@@ -29,7 +69,7 @@ macro_rules! basic_pair({$name1:ident, $name2:ident} => {
     let files = dir! {
         "file" => file!("")
     };
-    files.apply(&root1)?;
+    files.apply(&$root1)?;
 
     rig::wait();
 
@@ -85,3 +125,305 @@ fn send_back() -> Result<()> {
 
     return Ok(());
 }
+
+#[test]
+fn file_moved() -> Result<()> {
+    basic_pair!(root1, attach1, attach2);
+
+    fs::rename(path!(&root1, "file"), path!(&root1, "moved"))?;
+
+    rig::wait();
+
+    let event = attach2.pop_event().unwrap();
+    assert_eq!(event["event"], "FileMoved");
+    assert_eq!(event["from"], "file");
+    assert_eq!(event["to"], "moved");
+
+    // the client should now be attached to the new path
+    let diff = rig::BufferDiff::new(0, 0, "x");
+
+    attach1.send_diff(&diff)?;
+
+    rig::wait();
+
+    assert_eq!(attach2.pop_diff()?, Some(diff));
+
+    return Ok(());
+}
+
+#[test]
+fn file_deleted() -> Result<()> {
+    basic_pair!(root1, attach1, attach2);
+
+    fs::remove_file(path!(&root1, "file"))?;
+
+    rig::wait();
+
+    let event = attach2.pop_event().unwrap();
+    assert_eq!(event["event"], "FileDeleted");
+    assert_eq!(event["path"], "file");
+
+    let event = attach1.pop_event().unwrap();
+    assert_eq!(event["event"], "FileDeleted");
+
+    return Ok(());
+}
+
+#[test]
+fn peer_events() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    let files = dir! {
+        "file" => file!("")
+    };
+    files.apply(&root1)?;
+
+    rig::wait();
+
+    let mut attach = rig::attach(&daemon1, "file")?;
+
+    rig::wait();
+
+    {
+        let root2 = rig::tempdir()?;
+        let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+        rig::wait();
+
+        assert_eq!(attach.pop_event().unwrap()["event"], "PeerJoined");
+    }
+
+    rig::wait();
+
+    assert_eq!(attach.pop_event().unwrap()["event"], "PeerLeft");
+
+    return Ok(());
+}
+
+#[test]
+fn encoding() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    // "café" in latin-1, which is not valid UTF-8
+    fs::write(path!(&root1, "file"), b"caf\xe9")?;
+
+    rig::wait();
+
+    // the encoding of the first client is detected from the file
+    let mut attach1 = rig::attach(&daemon1, "file")?;
+    let mut attach2 = rig::attach_with_args(&daemon1, "file", &["--encoding", "utf-8"])?;
+
+    rig::wait();
+
+    attach1.send(b"{\"pos\":4,\"old_len\":0,\"new_str\":\"\xe9\"}")?;
+
+    rig::wait();
+
+    assert_eq!(attach2.pop_diff()?, Some(rig::BufferDiff::new(4, 0, "é")));
+
+    // the file on disk is left alone
+    assert_eq!(fs::read(path!(&root1, "file"))?, b"caf\xe9");
+
+    return Ok(());
+}
+
+#[test]
+fn acks() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    let root2 = rig::tempdir()?;
+    let daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    let files = dir! {
+        "file" => file!("")
+    };
+    files.apply(&root1)?;
+
+    rig::wait();
+
+    let mut attach1 = rig::attach_with_args(&daemon1, "file", &["--ack", "peers"])?;
+    let mut attach2 = rig::attach_with_args(&daemon2, "file", &["--ack", "daemon"])?;
+
+    rig::wait();
+
+    attach1.send(r#"{"pos":0,"old_len":0,"new_str":"x","seq":5}"#)?;
+
+    rig::wait();
+
+    assert_eq!(
+        attach1.pop_event().unwrap(),
+        serde_json::json!({"event": "Ack", "seq": 5})
+    );
+    assert_eq!(
+        attach1.pop_event().unwrap(),
+        serde_json::json!({"event": "Synced", "seq": 5})
+    );
+    assert_eq!(attach2.pop_diff()?, Some(rig::BufferDiff::new(0, 0, "x")));
+
+    // without a sequence number, diffs are numbered from the last one
+    attach2.send_diff(&rig::BufferDiff::new(1, 0, "y"))?;
+
+    rig::wait();
+
+    assert_eq!(
+        attach2.pop_event().unwrap(),
+        serde_json::json!({"event": "Ack", "seq": 1})
+    );
+    assert_eq!(attach2.pop_event(), None);
+
+    return Ok(());
+}
+
+#[test]
+fn summon() -> Result<()> {
+    basic_pair!(root1, attach1, attach2);
+
+    let status = rig::spawn(["summon", "all", "file:3"], &root1).status()?;
+    assert!(status.success());
+
+    rig::wait();
+
+    let event = attach2.pop_event().unwrap();
+    assert_eq!(event["event"], "Summon");
+    assert_eq!(event["path"], "file");
+    assert_eq!(event["line"], 3);
+
+    // only peers are summoned
+    assert_eq!(attach1.pop_event(), None);
+
+    return Ok(());
+}
+
+#[test]
+fn suggestions() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    let root2 = rig::tempdir()?;
+    let daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    let files = dir! {
+        "file" => file!("")
+    };
+    files.apply(&root1)?;
+
+    rig::wait();
+
+    let mut editor = rig::attach(&daemon1, "file")?;
+    let mut suggester = rig::attach_with_args(&daemon2, "file", &["--role", "suggest"])?;
+
+    rig::wait();
+
+    suggester.send_diff(&rig::BufferDiff::new(0, 0, "x"))?;
+
+    rig::wait();
+
+    // the suggestion is shown, but not applied
+    let event = editor.pop_event().unwrap();
+    assert_eq!(event["event"], "Suggestion");
+    assert_eq!(event["new_str"], "x");
+    assert_eq!(editor.pop_diff()?, None);
+    assert_eq!(suggester.pop_event().unwrap()["event"], "Suggestion");
+
+    // edits before the suggestion move it along
+    let edit = rig::BufferDiff::new(0, 0, "ab");
+    editor.send_diff(&edit)?;
+
+    rig::wait();
+
+    assert_eq!(suggester.pop_diff()?, Some(edit));
+
+    let id = event["id"].as_str().unwrap();
+    editor.send(serde_json::json!({ "accept": id }).to_string())?;
+
+    rig::wait();
+
+    let accepted = rig::BufferDiff::new(2, 0, "x");
+    assert_eq!(editor.pop_diff()?, Some(accepted.clone()));
+    assert_eq!(suggester.pop_diff()?, Some(accepted));
+
+    let resolved = serde_json::json!({"event": "SuggestionResolved", "id": id, "accepted": true});
+    assert_eq!(editor.pop_event().unwrap(), resolved);
+    assert_eq!(suggester.pop_event().unwrap(), resolved);
+
+    // suggesting clients can't accept suggestions
+    suggester.send(serde_json::json!({ "accept": id }).to_string())?;
+
+    rig::wait();
+
+    assert_eq!(suggester.pop_event().unwrap()["event"], "Error");
+
+    // diffs whose range overflows are refused, whatever the role
+    let overflowing = rig::BufferDiff::new(1, u32::MAX, "");
+    editor.send_diff(&overflowing)?;
+    suggester.send_diff(&overflowing)?;
+
+    rig::wait();
+
+    assert_eq!(editor.pop_event().unwrap()["event"], "Error");
+    assert_eq!(suggester.pop_event().unwrap()["event"], "Error");
+    assert_eq!(suggester.pop_diff()?, None);
+
+    // and the daemon carries on
+    let edit = rig::BufferDiff::new(0, 1, "");
+    editor.send_diff(&edit)?;
+
+    rig::wait();
+
+    assert_eq!(suggester.pop_diff()?, Some(edit));
+
+    return Ok(());
+}
+
+#[test]
+fn stalled_client() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    let root2 = rig::tempdir()?;
+    let daemon2 = rig::connect_with_args("r2", &root2, &daemon1, &["--queue-limit", "16"])?;
+
+    let files = dir! {
+        "file" => file!("")
+    };
+    files.apply(&root1)?;
+
+    rig::wait();
+
+    let mut attach1 = rig::attach(&daemon1, "file")?;
+    let mut attach2 = rig::attach(&daemon2, "file")?;
+
+    // an editor that attaches and then never reads what it is sent
+    let mut stalled = std::net::TcpStream::connect(("127.0.0.1", rig::ipc_port(&root2)?))?;
+    stalled.write_all(b"{\"version\":7,\"capabilities\":[]}\0")?;
+    let mut hello = Vec::new();
+    BufReader::new(&stalled).read_until(b'\0', &mut hello)?;
+    stalled.write_all(
+        br#"{"AttachRequest":{"path":"file","desc":"","encoding":"UTF-8","acks":"None","role":"Edit"}}"#,
+    )?;
+    stalled.write_all(b"\0")?;
+
+    rig::wait();
+
+    // far more than fits in the socket on the way to it
+    let text = "x".repeat(64 * 1024);
+    for _ in 0..256 {
+        attach1.send_diff(&rig::BufferDiff::new(0, 0, text.clone()))?;
+    }
+
+    for _ in 0..20 {
+        rig::wait();
+    }
+
+    // r2 drops the stalled editor rather than waiting for it
+    for _ in 0..256 {
+        let diff = attach2.pop_diff()?.map(|diff| diff.new_str.len());
+        assert_eq!(diff, Some(text.len()));
+    }
+    assert_eq!(attach2.pop_diff()?, None);
+
+    return Ok(());
+}
@@ -1,4 +1,4 @@
-use std::fs;
+use std::{fs, path::PathBuf, thread, time::Duration};
 
 use test_common::{common::Result, dir, file, files, path, rig};
 
@@ -212,3 +212,1083 @@ fn three() -> Result<()> {
 
     return Ok(());
 }
+
+#[test]
+fn fixed_listen_address() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let port = rig::free_port()?;
+    let listen = format!("127.0.0.1:{}", port);
+
+    let files = dir! {
+        "foobar" => file!("x")
+    };
+
+    files.apply(&root1)?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--listen", &listen])?;
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    let info = rig::info(&root1)?;
+    assert!(info.contains(&format!("Address: {}", listen)));
+
+    return Ok(());
+}
+
+#[test]
+fn advertised_address() -> Result<()> {
+    let root1 = rig::tempdir()?;
+
+    let port = rig::free_port()?;
+    let advertise = format!("127.0.0.1:{}", port);
+
+    let _daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &["--listen", "0.0.0.0:0", "--advertise", &advertise],
+    )?;
+
+    rig::wait();
+
+    let info = rig::info(&root1)?;
+    assert!(info.contains(&format!("Address: {}", advertise)));
+
+    return Ok(());
+}
+
+fn fingerprint(info: &str) -> String {
+    return info
+        .lines()
+        .find_map(|line| line.strip_prefix("Fingerprint: "))
+        .expect("no fingerprint in info")
+        .to_string();
+}
+
+#[test]
+fn tls() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+    let root3 = rig::tempdir()?;
+
+    let files = dir! {
+        "foobar" => file!("x"),
+        "dir" => dir! {
+            "file" => file!("more text")
+        }
+    };
+
+    files.apply(&root1)?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--tls"])?;
+    rig::wait();
+    let fingerprint1 = fingerprint(&rig::info(&root1)?);
+
+    let args = ["--tls", "--fingerprint", &fingerprint1];
+    let _daemon2 = rig::connect_with_args("r2", &root2, &daemon1, &args)?;
+    rig::wait();
+    let _daemon3 = rig::connect_with_args("r3", &root3, &daemon1, &args)?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+    assert_eq!(files, files::load_dir(&root3)?);
+
+    // the third instance connected to the second using the fingerprint
+    // learned from the first
+    let info3 = rig::info(&root3)?;
+    assert!(info3.contains("Peers (2 total)"));
+    assert!(info3.contains(&fingerprint(&rig::info(&root2)?)));
+
+    fs::write(path!(&root3, "foobar"), "y")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root1, "foobar"))?, "y");
+    assert_eq!(fs::read_to_string(path!(&root2, "foobar"))?, "y");
+
+    // the key is kept from other users
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = rig::session_dir(&root1);
+        assert_eq!(fs::metadata(&dir)?.permissions().mode() & 0o777, 0o700);
+        let key = fs::metadata(dir.join("key.der"))?;
+        assert_eq!(key.permissions().mode() & 0o777, 0o600);
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn tls_wrong_fingerprint() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    dir! { "foobar" => file!("x") }.apply(&root1)?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--tls"])?;
+    rig::wait();
+    let fingerprint1 = fingerprint(&rig::info(&root1)?);
+
+    let first = if fingerprint1.starts_with("00") {
+        "FF"
+    } else {
+        "00"
+    };
+    let wrong = format!("{}{}", first, &fingerprint1[2..]);
+    let args = [
+        "start",
+        "-c",
+        &daemon1.address,
+        "--secret",
+        &daemon1.secret,
+        "--tls",
+        "--fingerprint",
+        &wrong,
+    ];
+    let output = rig::spawn(args, &root2).output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("does not match"));
+
+    rig::wait();
+
+    assert!(!path!(&root2, "foobar").exists());
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+
+    return Ok(());
+}
+
+#[test]
+fn explicit_secret() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let files = dir! { "foobar" => file!("x") };
+    files.apply(&root1)?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--secret", "hunter2"])?;
+    let _daemon2 = rig::connect_with_args("r2", &root2, &daemon1, &["--secret", "hunter2"])?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    return Ok(());
+}
+
+#[test]
+fn wrong_secret() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    dir! { "foobar" => file!("x") }.apply(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    let args = ["start", "-c", &daemon1.address, "--secret", "wrong"];
+    let output = rig::spawn(args, &root2).output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("session secret"));
+
+    rig::wait();
+
+    assert!(!path!(&root2, "foobar").exists());
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+
+    return Ok(());
+}
+
+#[test]
+fn known_peers() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let files = dir! { "foobar" => file!("x") };
+    files.apply(&root1)?;
+
+    let key = |info: String| {
+        info.lines()
+            .find_map(|line| line.strip_prefix("Key: "))
+            .unwrap()
+            .to_string()
+    };
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    rig::wait();
+    let key1 = key(rig::info(&root1)?);
+
+    // a key that has been untrusted is refused
+    let untrust = rig::spawn(["untrust", &key1], &root2).output()?;
+    assert!(untrust.status.success());
+
+    let args = ["start", "-c", &daemon1.address, "--secret", &daemon1.secret];
+    let output = rig::spawn(args, &root2).output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("untrusted key"));
+    assert!(!path!(&root2, "foobar").exists());
+
+    let trust = rig::spawn(["trust", &key1], &root2).output()?;
+    assert!(trust.status.success());
+
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    let known_peers = fs::read_to_string(path!(rig::config_dir(&root2), "known_peers"))?;
+    assert!(known_peers.contains(&format!("{} trusted", key1)));
+    // the side that was connected to checks the key too
+    let key2 = key(rig::info(&root2)?);
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let identity = fs::metadata(path!(rig::config_dir(&root2), "identity"))?;
+        assert_eq!(identity.permissions().mode() & 0o777, 0o600);
+    }
+    let known_peers = fs::read_to_string(path!(rig::config_dir(&root1), "known_peers"))?;
+    assert!(known_peers.contains(&format!("{} trusted", key2)));
+
+    return Ok(());
+}
+
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let files = dir! { "foobar" => file!("x") };
+    files.apply(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    // pretend to be a peer from the future
+    let mut stream = std::net::TcpStream::connect(&daemon1.address)?;
+    stream.write_all(b"{\"version\":999,\"capabilities\":[\"teleport\"]}\0")?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    let hello: serde_json::Value = serde_json::from_slice(&reply[..reply.len() - 1])?;
+    assert_eq!(hello["version"], 3);
+
+    // the daemon refuses the connection, but keeps going
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    return Ok(());
+}
+
+#[test]
+fn oversized_frame() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let root1 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+
+    // a hello that never ends; the daemon should give up on it rather than
+    // buffer it all
+    let mut stream = std::net::TcpStream::connect(&daemon1.address)?;
+    let chunk = vec![b' '; 1 << 20];
+    for _ in 0..65 {
+        match stream.write_all(&chunk[..]) {
+            Ok(()) => (),
+            Err(_) => break,
+        }
+    }
+    let mut reply = Vec::new();
+    let _ = stream.read_to_end(&mut reply);
+
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+
+    return Ok(());
+}
+
+#[test]
+fn binary_file() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    // every byte value, including ones that used to delimit frames
+    let data: Vec<u8> = (0..1 << 16).map(|i| i as u8).collect();
+    fs::write(path!(&root1, "data.bin"), &data)?;
+
+    rig::wait();
+
+    assert_eq!(fs::read(path!(&root2, "data.bin"))?, data);
+
+    return Ok(());
+}
+
+#[test]
+fn compressed_startup() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    // enough small files to be batched, and some large enough to compress
+    fs::create_dir(path!(&root1, "src"))?;
+    for i in 0..50 {
+        let text = format!("line {}\n", i).repeat(i * 4);
+        fs::write(path!(&root1, "src", format!("file{}.txt", i)), text)?;
+    }
+    let files = files::load_dir(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    return Ok(());
+}
+
+#[test]
+fn no_compression() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let files = dir! { "foobar" => file!("x".repeat(10000)) };
+    files.apply(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let _daemon2 = rig::connect_with_args("r2", &root2, &daemon1, &["--no-compression"])?;
+
+    rig::wait();
+
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    return Ok(());
+}
+
+#[test]
+fn delta() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let mut data: Vec<u8> = (0..20000).map(|i| (i * 7 % 256) as u8).collect();
+    fs::write(path!(&root1, "data.bin"), &data)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert_eq!(fs::read(path!(&root2, "data.bin"))?, data);
+
+    // small changes in the middle and at the end
+    data[10000] = 0xff;
+    data.splice(5000..5000, b"inserted".iter().cloned());
+    data.extend_from_slice(b"appended");
+    fs::write(path!(&root1, "data.bin"), &data)?;
+
+    rig::wait();
+
+    assert_eq!(fs::read(path!(&root2, "data.bin"))?, data);
+
+    return Ok(());
+}
+
+#[test]
+fn reconnect() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    dir! {
+        "unchanged" => file!("unchanged"),
+        "changed1" => file!("original"),
+        "changed2" => file!("original"),
+        "both" => file!("original"),
+        "deleted" => file!("original")
+    }
+    .apply(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let proxy = rig::Proxy::new(&daemon1)?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &["-c", &proxy.address, "--secret", &daemon1.secret],
+    )?;
+
+    rig::wait();
+
+    proxy.down();
+    rig::wait();
+
+    // change things on both sides while the connection is down
+    fs::write(path!(&root1, "changed1"), "changed by r1")?;
+    fs::write(path!(&root1, "added"), "added by r1")?;
+    fs::write(path!(&root2, "changed2"), "changed by r2")?;
+    fs::remove_file(path!(&root2, "deleted"))?;
+    fs::write(path!(&root1, "both"), "changed by r1")?;
+    fs::write(path!(&root2, "both"), "changed by r2")?;
+
+    // r2 keeps retrying until the connection is back
+    thread::sleep(Duration::from_secs(1));
+    proxy.up();
+    thread::sleep(Duration::from_secs(3));
+
+    // the side that reconnected wins when both changed a file
+    let files = dir! {
+        "unchanged" => file!("unchanged"),
+        "changed1" => file!("changed by r1"),
+        "changed2" => file!("changed by r2"),
+        "both" => file!("changed by r2"),
+        "added" => file!("added by r1")
+    };
+    assert_eq!(files, files::load_dir(&root1)?);
+    assert_eq!(files, files::load_dir(&root2)?);
+
+    return Ok(());
+}
+
+#[test]
+fn chain() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+    let root3 = rig::tempdir()?;
+
+    dir! { "file" => file!("") }.apply(&root1)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    // r2 introduces r3 to r1, but r3 refuses to connect to it, so
+    // everything between r1 and r3 has to go through r2
+    let key1 = rig::info(&root1)?
+        .lines()
+        .find_map(|line| line.strip_prefix("Key: "))
+        .unwrap()
+        .to_string();
+    let untrust = rig::spawn(["untrust", &key1], &root3).output()?;
+    assert!(untrust.status.success());
+    let daemon3 = rig::connect("r3", &root3, &daemon2)?;
+
+    rig::wait();
+
+    assert!(rig::info(&root3)?.contains("Peers (1 total)"));
+    assert_eq!(fs::read_to_string(path!(&root3, "file"))?, "");
+
+    fs::write(path!(&root1, "from1"), "r1")?;
+    fs::write(path!(&root3, "from3"), "r3")?;
+
+    rig::wait();
+
+    let files = dir! {
+        "file" => file!(""),
+        "from1" => file!("r1"),
+        "from3" => file!("r3")
+    };
+    assert_eq!(files, files::load_dir(&root1)?);
+    assert_eq!(files, files::load_dir(&root2)?);
+    assert_eq!(files, files::load_dir(&root3)?);
+
+    let mut attach1 = rig::attach(&daemon1, "file")?;
+    let mut attach3 = rig::attach(&daemon3, "file")?;
+
+    rig::wait();
+
+    let diff = rig::BufferDiff::new(0, 0, "x");
+    attach1.send_diff(&diff)?;
+
+    rig::wait();
+
+    // exactly once
+    assert_eq!(attach3.pop_diff()?, Some(diff));
+    assert_eq!(attach3.pop_diff()?, None);
+    assert_eq!(attach1.pop_diff()?, None);
+
+    return Ok(());
+}
+
+#[test]
+fn heartbeat() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--heartbeat-timeout", "1"])?;
+    let proxy = rig::Proxy::new(&daemon1)?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &[
+            "-c",
+            &proxy.address,
+            "--secret",
+            &daemon1.secret,
+            "--heartbeat-timeout",
+            "1",
+        ],
+    )?;
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    assert!(!rig::info(&root1)?.contains("unreachable"));
+
+    // the connection stays open, but nothing gets through
+    proxy.pause();
+    thread::sleep(Duration::from_secs(2));
+
+    assert!(rig::info(&root1)?.contains("unreachable"));
+    assert!(rig::info(&root2)?.contains("unreachable"));
+
+    proxy.resume();
+    thread::sleep(Duration::from_secs(1));
+
+    assert!(!rig::info(&root1)?.contains("unreachable"));
+    assert!(!rig::info(&root2)?.contains("unreachable"));
+
+    return Ok(());
+}
+
+#[test]
+fn relay() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    dir! { "file" => file!("r1") }.apply(&root1)?;
+
+    let relay = rig::Relay::new()?;
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &["--relay", &relay.address, "--session", "test"],
+    )?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &[
+            "--relay",
+            &relay.address,
+            "--session",
+            "test",
+            "--secret",
+            &daemon1.secret,
+        ],
+    )?;
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    assert!(rig::info(&root2)?.contains("Peers (1 total)"));
+    assert_eq!(fs::read_to_string(path!(&root2, "file"))?, "r1");
+
+    fs::write(path!(&root2, "from2"), "r2")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root1, "from2"))?, "r2");
+
+    return Ok(());
+}
+
+fn node_id(info: &str) -> String {
+    return info
+        .lines()
+        .find_map(|line| line.strip_prefix("Node id: "))
+        .expect("no node id in info")
+        .to_string();
+}
+
+#[test]
+fn node_ids() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    dir! { "file" => file!("r1") }.apply(&root1)?;
+
+    // r2 reaches r1 both directly and through the relay, but it is still
+    // the same peer
+    let relay = rig::Relay::new()?;
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &["--relay", &relay.address, "--session", "test"],
+    )?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &[
+            "-c",
+            &daemon1.address,
+            "--secret",
+            &daemon1.secret,
+            "--relay",
+            &relay.address,
+            "--session",
+            "test",
+        ],
+    )?;
+
+    rig::wait();
+
+    let (info1, info2) = (rig::info(&root1)?, rig::info(&root2)?);
+    assert!(info1.contains("Peers (1 total)"));
+    assert!(info2.contains("Peers (1 total)"));
+    assert!(info1.contains(&node_id(&info2)));
+    assert!(info2.contains(&node_id(&info1)));
+    assert_ne!(node_id(&info1), node_id(&info2));
+
+    fs::write(path!(&root2, "from2"), "r2")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root2, "file"))?, "r1");
+    assert_eq!(fs::read_to_string(path!(&root1, "from2"))?, "r2");
+
+    return Ok(());
+}
+
+#[test]
+fn overflow() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &[
+            "--overflow",
+            "disconnect",
+            "--queue-limit",
+            "2",
+            "--no-compression",
+        ],
+    )?;
+    let proxy = rig::Proxy::new(&daemon1)?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &["-c", &proxy.address, "--secret", &daemon1.secret],
+    )?;
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("0 queued"));
+
+    // more than fits in the connection while nothing gets through, so the
+    // queue overflows and r1 drops the connection
+    proxy.pause();
+    let files: Vec<(String, Vec<u8>)> = (0..8)
+        .map(|i| {
+            let data = (0..1 << 20)
+                .map(|j| ((i * 31 + j * 7) % 251) as u8)
+                .collect();
+            (format!("file{}", i), data)
+        })
+        .collect();
+    for (name, data) in &files {
+        fs::write(path!(&root1, name), data)?;
+    }
+
+    thread::sleep(Duration::from_secs(2));
+
+    // once r2 reconnects, it is sent whatever it missed
+    proxy.resume();
+    thread::sleep(Duration::from_secs(5));
+
+    for (name, data) in &files {
+        assert_eq!(&fs::read(path!(&root2, name))?, data);
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn collapse_keeps_moves() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &[
+            "--overflow",
+            "collapse",
+            "--queue-limit",
+            "4",
+            "--no-compression",
+        ],
+    )?;
+    let proxy = rig::Proxy::new(&daemon1)?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &["-c", &proxy.address, "--secret", &daemon1.secret],
+    )?;
+
+    rig::wait();
+
+    // more than fits in the connection while nothing gets through, which
+    // holds up everything queued after it
+    proxy.pause();
+    let filler: Vec<u8> = (0..12u32 << 20)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    fs::write(path!(&root1, "filler"), &filler)?;
+    rig::wait();
+
+    // the second write to a fills the queue, but mustn't replace the first,
+    // which the move needs
+    fs::write(path!(&root1, "a"), "first")?;
+    rig::wait();
+    fs::rename(path!(&root1, "a"), path!(&root1, "b"))?;
+    rig::wait();
+    fs::write(path!(&root1, "a"), "second")?;
+    rig::wait();
+
+    proxy.resume();
+    for _ in 0..60 {
+        match fs::read(path!(&root2, "filler")) {
+            Ok(read) if read == filler => break,
+            _ => thread::sleep(Duration::from_millis(500)),
+        }
+    }
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root2, "b"))?, "first");
+    assert_eq!(fs::read_to_string(path!(&root2, "a"))?, "second");
+    assert!(fs::read(path!(&root2, "filler"))? == filler);
+
+    return Ok(());
+}
+
+#[test]
+fn peer_management() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    let node2 = node_id(&rig::info(&root2)?);
+
+    // r2 made the connection, so it would normally reconnect
+    let ban = rig::spawn(["ban", &node2], &root1).output()?;
+    assert!(ban.status.success());
+    thread::sleep(Duration::from_secs(2));
+
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+    assert!(rig::info(&root1)?.contains(&format!("Banned: {}", node2)));
+
+    let unban = rig::spawn(["unban", &node2], &root1).output()?;
+    assert!(unban.status.success());
+    let connect = rig::spawn(["connect", &daemon2.address], &root1).output()?;
+    assert!(connect.status.success());
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    assert!(rig::info(&root2)?.contains("Peers (1 total)"));
+
+    fs::write(path!(&root2, "from2"), "r2")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root1, "from2"))?, "r2");
+
+    // neither side reconnects after a deliberate disconnect
+    let disconnect = rig::spawn(["disconnect", &daemon2.address], &root1).output()?;
+    assert!(disconnect.status.success());
+    thread::sleep(Duration::from_secs(2));
+
+    assert!(rig::info(&root1)?.contains("Peers (0 total)"));
+    assert!(rig::info(&root2)?.contains("Peers (0 total)"));
+
+    let disconnect = rig::spawn(["disconnect", &daemon2.address], &root1).output()?;
+    assert!(!disconnect.status.success());
+
+    return Ok(());
+}
+
+#[test]
+fn connect_by_host_name() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+    let root3 = rig::tempdir()?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let port = daemon1.address.rsplit(':').next().unwrap();
+    let host = format!("localhost:{}", port);
+    let _daemon2 =
+        rig::daemon_with_args("r2", &root2, &["-c", &host, "--secret", &daemon1.secret])?;
+    let _daemon3 = rig::daemon_with_args("r3", &root3, &["--secret", &daemon1.secret])?;
+
+    rig::wait();
+
+    let connect = rig::spawn(["connect", &host], &root3).output()?;
+    assert!(connect.status.success());
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (2 total)"));
+
+    fs::write(path!(&root2, "from2"), "r2")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root3, "from2"))?, "r2");
+
+    let connect = rig::spawn(["connect", "nowhere.invalid:4000"], &root3).output()?;
+    assert!(!connect.status.success());
+
+    return Ok(());
+}
+
+#[test]
+fn lan_discovery() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+    let root3 = rig::tempdir()?;
+
+    // other tests may be announcing sessions of their own
+    let name = format!("discovery-{}", std::process::id());
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--announce", &name])?;
+
+    let discover = rig::spawn(["discover"], &root3).output()?;
+    assert!(discover.status.success());
+    let stdout = String::from_utf8_lossy(&discover.stdout);
+    assert!(stdout.contains(&format!("{} on ", name)));
+    assert!(stdout.contains(&format!("at {} (0 peers)", daemon1.address)));
+
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &["--join", &name, "--secret", &daemon1.secret],
+    )?;
+
+    thread::sleep(Duration::from_secs(3));
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+
+    fs::write(path!(&root1, "from1"), "r1")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root2, "from1"))?, "r1");
+
+    return Ok(());
+}
+
+#[test]
+fn large_files() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    // larger than the largest file sent whole
+    let large: Vec<u8> = (0..17u32 << 20).map(|i| (i % 251) as u8).collect();
+    fs::write(path!(&root1, "startup.bin"), &large)?;
+
+    let daemon1 = rig::daemon("r1", &root1)?;
+    let _daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    // chunks take a while to go through in debug builds
+    let arrived = |path: PathBuf, data: &Vec<u8>| {
+        for _ in 0..60 {
+            match fs::read(&path) {
+                Ok(read) if read == *data => return true,
+                _ => thread::sleep(Duration::from_millis(500)),
+            }
+        }
+        false
+    };
+
+    assert!(arrived(path!(&root2, "startup.bin"), &large));
+
+    let changed: Vec<u8> = large.iter().rev().cloned().collect();
+    fs::write(path!(&root2, "watched.bin"), &changed)?;
+
+    assert!(arrived(path!(&root1, "watched.bin"), &changed));
+    assert!(fs::read(path!(&root2, "startup.bin"))? == large);
+
+    return Ok(());
+}
+
+#[test]
+fn interrupted_transfer() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    let large = vec![b'x'; 17 << 20];
+    fs::write(path!(&root1, "startup.bin"), &large)?;
+
+    let daemon1 = rig::daemon_with_args("r1", &root1, &["--no-compression"])?;
+    let proxy = rig::Proxy::new(&daemon1)?;
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &["-c", &proxy.address, "--secret", &daemon1.secret],
+    )?;
+
+    // hold the file up once some of it has arrived
+    let transfers = rig::session_dir(&root2).join("transfers");
+    let mut partials: Vec<PathBuf> = Vec::new();
+    for _ in 0..1000 {
+        partials = match fs::read_dir(&transfers) {
+            Ok(entries) => entries.map(|entry| entry.unwrap().path()).collect(),
+            Err(_) => Vec::new(),
+        };
+        let started = match partials.first() {
+            Some(partial) => fs::metadata(partial)?.len() > 0,
+            None => false,
+        };
+        if started {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    proxy.pause();
+
+    // the partial file is kept from other users
+    assert_eq!(partials.len(), 1);
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let partial = fs::metadata(&partials[0])?;
+        assert_eq!(partial.permissions().mode() & 0o777, 0o600);
+        assert!(partial.len() > 0);
+    }
+
+    // the connection drops partway through
+    proxy.down();
+    proxy.resume();
+    rig::wait();
+    let before = proxy.bytes();
+    proxy.up();
+
+    for _ in 0..60 {
+        match fs::read(path!(&root2, "startup.bin")) {
+            Ok(read) if read == large => break,
+            _ => thread::sleep(Duration::from_millis(500)),
+        }
+    }
+    assert!(fs::read(path!(&root2, "startup.bin"))? == large);
+
+    // only what hadn't arrived was sent again
+    assert!(proxy.bytes() - before < large.len() as u64);
+    assert_eq!(fs::read_dir(&transfers)?.count(), 0);
+
+    return Ok(());
+}
+
+#[test]
+fn rate_limits() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    fs::write(path!(&root1, "file"), "")?;
+    // about eight seconds' worth at the limit, one frame per file
+    let data = vec![b'x'; 256 << 10];
+    for i in 0..8 {
+        fs::write(path!(&root1, format!("bulk{}", i)), &data)?;
+    }
+
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &["--upload-limit", "256K", "--no-compression"],
+    )?;
+    let daemon2 = rig::connect("r2", &root2, &daemon1)?;
+
+    thread::sleep(Duration::from_secs(1));
+
+    assert!(rig::info(&root1)?.contains("Upload limit: 262144 bytes/s, download limit: none"));
+    let arrived = (0..8)
+        .filter(|i| path!(&root2, format!("bulk{}", i)).exists())
+        .count();
+    assert!(arrived < 8);
+
+    // edits don't wait for the files
+    let mut attach1 = rig::attach(&daemon1, "file")?;
+    let mut attach2 = rig::attach(&daemon2, "file")?;
+
+    rig::wait();
+
+    let diff = rig::BufferDiff::new(0, 0, "x");
+    attach1.send_diff(&diff)?;
+
+    rig::wait();
+
+    assert_eq!(attach2.pop_diff()?, Some(diff));
+
+    let limit = rig::spawn(["limit", "--upload", "0"], &root1).output()?;
+    assert!(limit.status.success());
+    assert!(String::from_utf8_lossy(&limit.stdout).contains("Upload limit: none"));
+
+    thread::sleep(Duration::from_secs(2));
+
+    for i in 0..8 {
+        assert_eq!(fs::read(path!(&root2, format!("bulk{}", i)))?, data);
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn connect_command() -> Result<()> {
+    let root1 = rig::tempdir()?;
+    let root2 = rig::tempdir()?;
+
+    fs::write(path!(&root1, "from1"), "r1")?;
+
+    // the bridge goes to where r1 listens, not to where it says it is
+    let daemon1 = rig::daemon_with_args(
+        "r1",
+        &root1,
+        &[
+            "--tls",
+            "--listen",
+            "0.0.0.0:0",
+            "--advertise",
+            "192.0.2.1:9",
+        ],
+    )?;
+    rig::wait();
+    let fingerprint1 = fingerprint(&rig::info(&root1)?);
+
+    // stands in for e.g. `ssh devbox collab bridge`
+    let bridge = rig::command_line(&["bridge"], &root1);
+    let _daemon2 = rig::daemon_with_args(
+        "r2",
+        &root2,
+        &[
+            "--connect-cmd",
+            &bridge,
+            "--secret",
+            &daemon1.secret,
+            "--tls",
+            "--fingerprint",
+            &fingerprint1,
+        ],
+    )?;
+
+    rig::wait();
+
+    assert!(rig::info(&root1)?.contains("Peers (1 total)"));
+    assert!(rig::info(&root2)?.contains("Peers (1 total)"));
+    assert_eq!(fs::read_to_string(path!(&root2, "from1"))?, "r1");
+
+    fs::write(path!(&root2, "from2"), "r2")?;
+
+    rig::wait();
+
+    assert_eq!(fs::read_to_string(path!(&root1, "from2"))?, "r2");
+
+    return Ok(());
+}